@@ -0,0 +1,198 @@
+use super::*;
+use std::io::{Read, Write};
+
+/// Magic bytes at the start of a backup archive.
+const ARCHIVE_MAGIC: [u8; 4] = *b"ODBA";
+
+/// Current version of the backup archive layout.
+const ARCHIVE_VERSION: u32 = 1;
+
+/// Maximum length of the data in a single archive block.
+pub const MAX_BLOCK_SIZE: usize = 1 << 20;
+
+/// Backup archive writer.
+///
+/// The archive is a sequence of blocks, each containing a file name, a
+/// chunk of the file data, and a CRC32 checksum of the chunk. Large files
+/// are written as consecutive blocks with the same name so that they can
+/// be streamed without loading them into memory.
+///
+/// The archive ends with an empty block name which allows the reader to
+/// detect archives that were cut off in the middle of a stream.
+#[derive(Debug)]
+pub struct ArchiveWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    /// Create an archive writer and write the archive header.
+    pub fn new(mut writer: W) -> Result<Self, Box<dyn Error>> {
+        writer.write_all(&ARCHIVE_MAGIC)?;
+        writer.write_all(&ARCHIVE_VERSION.to_le_bytes())?;
+        Ok(ArchiveWriter { writer })
+    }
+
+    /// Write a file split into blocks of the maximum block size.
+    pub fn write_file(
+        &mut self,
+        name: &str,
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        if data.is_empty() {
+            return self.write_block(name, data);
+        }
+
+        for chunk in data.chunks(MAX_BLOCK_SIZE) {
+            self.write_block(name, chunk)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a chunk of a file as a single block.
+    pub fn write_block(
+        &mut self,
+        name: &str,
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        if name.is_empty() || name.len() > u16::MAX as usize {
+            return Err("Invalid archive file name".into());
+        }
+
+        if data.len() > MAX_BLOCK_SIZE {
+            return Err("Archive block exceeds the maximum size".into());
+        }
+
+        self.writer.write_all(&(name.len() as u16).to_le_bytes())?;
+        self.writer.write_all(name.as_bytes())?;
+        self.writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.writer.write_all(data)?;
+        self.writer.write_all(&crc32fast::hash(data).to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Write the end marker and flush the archive.
+    pub fn finish(mut self) -> Result<W, Box<dyn Error>> {
+        self.writer.write_all(&0u16.to_le_bytes())?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Chunk of a file read from the backup archive.
+///
+/// Fields:
+/// - name: Name of the file the data belongs to.
+/// - data: Chunk of the file data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveBlock {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Backup archive reader.
+#[derive(Debug)]
+pub struct ArchiveReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    /// Create an archive reader and validate the archive header.
+    pub fn new(mut reader: R) -> Result<Self, Box<dyn Error>> {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        if header[0..4] != ARCHIVE_MAGIC {
+            return Err("The file is not a backup archive".into());
+        }
+
+        let version = u32::from_le_bytes(header[4..8].try_into()?);
+        if version > ARCHIVE_VERSION {
+            let message = format!("Unsupported archive version: {version}");
+            return Err(message.into());
+        }
+
+        Ok(ArchiveReader { reader })
+    }
+
+    /// Read the next block of the archive.
+    ///
+    /// Returns None after the end marker. An archive without the end
+    /// marker is incomplete and fails with an error.
+    pub fn next_block(
+        &mut self,
+    ) -> Result<Option<ArchiveBlock>, Box<dyn Error>> {
+        let incomplete = |_| "The backup archive is incomplete";
+
+        let mut length = [0; 2];
+        self.reader.read_exact(&mut length).map_err(incomplete)?;
+        let length = u16::from_le_bytes(length) as usize;
+        if length == 0 {
+            return Ok(None);
+        }
+
+        let mut name = vec![0; length];
+        self.reader.read_exact(&mut name).map_err(incomplete)?;
+        let name = String::from_utf8(name)?;
+
+        let mut length = [0; 4];
+        self.reader.read_exact(&mut length).map_err(incomplete)?;
+        let length = u32::from_le_bytes(length) as usize;
+        if length > MAX_BLOCK_SIZE {
+            return Err("Archive block exceeds the maximum size".into());
+        }
+
+        let mut data = vec![0; length];
+        self.reader.read_exact(&mut data).map_err(incomplete)?;
+
+        let mut checksum = [0; 4];
+        self.reader.read_exact(&mut checksum).map_err(incomplete)?;
+        if crc32fast::hash(&data) != u32::from_le_bytes(checksum) {
+            let message = format!("Checksum mismatch in archive file {name}");
+            return Err(message.into());
+        }
+
+        Ok(Some(ArchiveBlock { name, data }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_read() {
+        let large = vec![7; MAX_BLOCK_SIZE + 1];
+
+        let mut writer = ArchiveWriter::new(vec![]).unwrap();
+        writer.write_file("small", b"data").unwrap();
+        writer.write_file("large", &large).unwrap();
+        let bytes = writer.finish().unwrap();
+
+        let mut reader = ArchiveReader::new(bytes.as_slice()).unwrap();
+        let mut files: Vec<(String, Vec<u8>)> = vec![];
+        while let Some(block) = reader.next_block().unwrap() {
+            match files.last_mut() {
+                Some((name, content)) if *name == block.name => {
+                    content.extend(block.data);
+                }
+                _ => files.push((block.name, block.data)),
+            }
+        }
+
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0], ("small".to_string(), b"data".to_vec()));
+        assert_eq!(files[1].1, large);
+    }
+
+    #[test]
+    fn test_read_incomplete_archive() {
+        let mut writer = ArchiveWriter::new(vec![]).unwrap();
+        writer.write_file("file", b"data").unwrap();
+        let bytes = writer.finish().unwrap();
+
+        // Cut off the end marker and part of the checksum.
+        let bytes = &bytes[..bytes.len() - 4];
+        let mut reader = ArchiveReader::new(bytes).unwrap();
+        assert!(reader.next_block().is_err());
+    }
+}
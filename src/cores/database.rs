@@ -1,12 +1,40 @@
 use super::*;
 use protos::database_server::Database as DatabaseService;
-use std::io::{BufReader, BufWriter};
+use rand::seq::SliceRandom;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response};
 
 const TMP_DIR: &str = "tmp";
+const SNAPSHOTS_DIR: &str = "snapshots";
+const MANIFEST_FILE: &str = "odb_manifest";
 const PARAMS_FILE: &str = "odb_params";
 const STORAGE_FILE: &str = "odb_storage";
 const INDEX_FILE: &str = "odb_index";
+const STORAGE_DELTA_FILE: &str = "odb_storage_delta";
+const INDEX_DELTA_FILE: &str = "odb_index_delta";
+const WAL_FILE: &str = "odb_wal";
+const LOCK_FILE: &str = "odb_lock";
+const VECTORS_DIR: &str = "vectors";
+
+/// Number of delta generations after which a full snapshot is created.
+const COMPACTION_THRESHOLD: usize = 8;
+
+/// Number of backup chunks buffered before the stream applies backpressure.
+const BACKUP_BUFFER: usize = 4;
+
+/// Number of records read from the storage at a time when the export or
+/// the retraining iterates over all records.
+const READ_BATCH_SIZE: usize = 1024;
+
+/// Number of times the inspection loads the snapshot when the server keeps
+/// committing new ones while it's loaded.
+const INSPECT_ATTEMPTS: usize = 3;
 
 /// Database parameters.
 ///
@@ -14,11 +42,74 @@ const INDEX_FILE: &str = "odb_index";
 /// - dimension: Vector dimension.
 /// - metric: Metric to calculate distance.
 /// - density: Max number of records per IVF cluster.
+/// - storage: Location of the record vectors.
+/// - index: Type of the ANNS index.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Parameters {
     pub dimension: usize,
     pub metric: Metric,
     pub density: usize,
+    pub storage: StorageMode,
+    pub index: IndexType,
+}
+
+impl Parameters {
+    /// Check that the data files can be encrypted with the parameters.
+    ///
+    /// The vector segments of the mapped storage are modified in place, so
+    /// they're kept in plaintext unlike the snapshot files. Encryption is
+    /// only supported with the memory storage.
+    pub fn check_encryption(&self) -> Result<(), Box<dyn Error>> {
+        if self.storage == StorageMode::Mapped {
+            let message = "Encryption isn't supported with the mapped \
+                storage because the vector segments aren't encrypted. Use \
+                the memory storage for an encrypted database";
+            return Err(message.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Database parameters prior to format version 3.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct LegacyParameters {
+    dimension: usize,
+    metric: Metric,
+    density: usize,
+}
+
+impl From<LegacyParameters> for Parameters {
+    fn from(value: LegacyParameters) -> Self {
+        Parameters {
+            dimension: value.dimension,
+            metric: value.metric,
+            density: value.density,
+            storage: StorageMode::Memory,
+            index: IndexType::Ivf,
+        }
+    }
+}
+
+/// Database parameters from format version 3 prior to version 7.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct StorageParameters {
+    dimension: usize,
+    metric: Metric,
+    density: usize,
+    storage: StorageMode,
+}
+
+impl From<StorageParameters> for Parameters {
+    fn from(value: StorageParameters) -> Self {
+        Parameters {
+            dimension: value.dimension,
+            metric: value.metric,
+            density: value.density,
+            storage: value.storage,
+            index: IndexType::Ivf,
+        }
+    }
 }
 
 /// Dynamic query-time parameters.
@@ -26,31 +117,117 @@ pub struct Parameters {
 /// Fields:
 /// - probes: Suggested number of clusters to visit.
 /// - radius: Maximum distance to include in the result.
+/// - ef: Number of candidates explored in the HNSW graph.
+/// - exact: Compare with all records instead of searching the index.
+/// - rerank: Number of IVF-PQ candidates re-ranked with the full vectors.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct QueryParameters {
     pub probes: usize,
     pub radius: f32,
+    pub ef: usize,
+    pub exact: bool,
+    pub rerank: usize,
 }
 
 impl Default for QueryParameters {
     /// Default query parameters:
     /// - probes: 32
     /// - radius: ∞
+    /// - ef: 64
+    /// - exact: false
+    /// - rerank: 100
     fn default() -> Self {
-        QueryParameters { probes: 32, radius: f32::INFINITY }
+        QueryParameters {
+            probes: 32,
+            radius: f32::INFINITY,
+            ef: 64,
+            exact: false,
+            rerank: 100,
+        }
     }
 }
 
 impl TryFrom<protos::QueryParameters> for QueryParameters {
     type Error = Status;
     fn try_from(value: protos::QueryParameters) -> Result<Self, Self::Error> {
+        if value.ef < 0 {
+            let message = "Invalid ef value, ef must not be negative";
+            return Err(Status::invalid_argument(message));
+        }
+
+        if value.rerank < 0 {
+            let message = "Invalid rerank value, rerank must not be negative";
+            return Err(Status::invalid_argument(message));
+        }
+
+        // Unset values are 0 in the request so they fall back to the
+        // defaults.
+        let default = QueryParameters::default();
+        let ef = match value.ef {
+            0 => default.ef,
+            ef => ef as usize,
+        };
+
+        let rerank = match value.rerank {
+            0 => default.rerank,
+            rerank => rerank as usize,
+        };
+
         Ok(QueryParameters {
             probes: value.probes as usize,
             radius: value.radius,
+            ef,
+            exact: value.exact,
+            rerank,
         })
     }
 }
 
+/// Dynamic open-time parameters.
+///
+/// Fields:
+/// - retention: Number of the latest snapshot generations to keep.
+/// - restore: Older snapshot generation to restore the database to.
+/// - compression: Codec to compress the snapshot files.
+/// - key: Key to encrypt the snapshot files and the write-ahead log.
+/// - merge_threshold: Fraction of the density below which a cluster is
+///   merged into its neighbors on deletion.
+/// - maintenance: Open for an offline command which keeps all snapshot
+///   generations regardless of the retention.
+/// - encrypt_existing: Encrypt the existing data files with the key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenParameters {
+    pub retention: usize,
+    pub restore: Option<u64>,
+    pub compression: Compression,
+    pub key: Option<EncryptionKey>,
+    pub merge_threshold: f32,
+    pub maintenance: bool,
+    pub encrypt_existing: bool,
+}
+
+impl Default for OpenParameters {
+    /// Default open parameters:
+    /// - retention: 3
+    /// - restore: None
+    /// - compression: None
+    /// - key: None
+    /// - merge_threshold: 0.1
+    /// - maintenance: false
+    /// - encrypt_existing: false
+    fn default() -> Self {
+        OpenParameters {
+            retention: 3,
+            restore: None,
+            compression: Compression::None,
+            key: None,
+            merge_threshold: 0.1,
+            maintenance: false,
+            encrypt_existing: false,
+        }
+    }
+}
+
 /// Database snapshot statistics.
 ///
 /// The snapshot statistics include the information that might be useful
@@ -67,243 +244,1776 @@ impl From<SnapshotStats> for protos::SnapshotResponse {
     }
 }
 
+/// Result of the index retraining.
+///
+/// Fields:
+/// - records: Number of records assigned to the new clusters.
+/// - clusters: Number of clusters in the new index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetrainStats {
+    pub records: usize,
+    pub clusters: usize,
+}
+
+impl From<RetrainStats> for protos::RetrainResponse {
+    fn from(value: RetrainStats) -> Self {
+        protos::RetrainResponse {
+            records: value.records as i32,
+            clusters: value.clusters as i32,
+        }
+    }
+}
+
+/// Snapshot manifest.
+///
+/// The manifest points to the chain of generations of the latest complete
+/// snapshot: a base generation containing the full database state followed
+/// by delta generations containing the changes since the previous one.
+///
+/// Each snapshot is written into its own generation directory and becomes
+/// visible only when the manifest is atomically replaced. This way, the
+/// database always loads a consistent set of snapshot files.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Manifest {
+    pub base: u64,
+    pub deltas: Vec<u64>,
+}
+
+impl Manifest {
+    /// Return the latest generation in the chain.
+    pub fn generation(&self) -> u64 {
+        self.deltas.last().copied().unwrap_or(self.base)
+    }
+
+    /// Return the generations in the chain starting from the base.
+    pub fn chain(&self) -> Vec<u64> {
+        let mut chain = vec![self.base];
+        chain.extend(self.deltas.iter());
+        chain
+    }
+}
+
+/// Snapshot generation stored in the data directory.
+///
+/// Fields:
+/// - generation: Snapshot generation number.
+/// - full: Whether the generation contains the full database state.
+/// - size: Total size of the snapshot files in bytes.
+/// - created: Time when the snapshot was persisted.
+/// - current: Whether the database is loaded from this generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    pub generation: u64,
+    pub full: bool,
+    pub size: u64,
+    pub created: SystemTime,
+    pub current: bool,
+}
+
+/// Offline summary of the data directory.
+///
+/// Fields:
+/// - params: Database parameters.
+/// - generation: Latest snapshot generation.
+/// - count: Number of records in the snapshot.
+/// - cluster_sizes: Number of records in each index cluster.
+/// - metadata_keys: Number of records using each metadata key.
+/// - files: Files in the data directory with their sizes in bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseInfo {
+    pub params: Parameters,
+    pub generation: u64,
+    pub count: usize,
+    pub cluster_sizes: Vec<usize>,
+    pub metadata_keys: BTreeMap<String, usize>,
+    pub files: Vec<(PathBuf, u64)>,
+}
+
+/// Inconsistencies between the index and the storage.
+///
+/// Fields:
+/// - dangling: Indexed IDs without a record in the storage.
+/// - orphaned: Stored records that aren't in the index.
+/// - duplicates: IDs indexed more than once.
+/// - empty_clusters: Clusters without any record.
+/// - count: Record count tracked by the storage.
+/// - records: Actual number of records in the storage.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsistencyReport {
+    pub dangling: Vec<RecordID>,
+    pub orphaned: Vec<RecordID>,
+    pub duplicates: Vec<RecordID>,
+    pub empty_clusters: Vec<usize>,
+    pub count: usize,
+    pub records: usize,
+}
+
+impl ConsistencyReport {
+    /// Check if no inconsistency is found.
+    pub fn is_consistent(&self) -> bool {
+        self.dangling.is_empty()
+            && self.orphaned.is_empty()
+            && self.duplicates.is_empty()
+            && self.empty_clusters.is_empty()
+            && self.count == self.records
+    }
+}
+
+/// Database state loaded from a snapshot chain.
+type SnapshotState = (Parameters, Box<dyn Index>, Box<dyn Storage>);
+
+/// Frozen view of the database state captured for a snapshot.
+enum Snapshot {
+    Full(Box<dyn Encode>, Box<dyn Encode>),
+    Delta(Box<dyn Encode>, Box<dyn Encode>),
+}
+
 #[derive(Debug)]
 pub struct Database {
     dir: PathBuf,
     params: Parameters,
-    index: RwLock<Index>,
-    storage: RwLock<Storage>,
+    manifest: Mutex<Manifest>,
+    index: RwLock<Box<dyn Index>>,
+    storage: RwLock<Box<dyn Storage>>,
+    wal: Mutex<WriteAheadLog>,
+    writes: AtomicUsize,
+    retention: usize,
+    merge_threshold: f32,
+    files: FileOptions,
+    backups: AtomicUsize,
+    _lock: Option<File>,
 }
 
 impl Database {
-    pub fn configure(params: &Parameters) {
-        let index = Index::new()
-            .with_metric(params.metric)
-            .with_density(params.density);
-
-        let db = Database {
-            dir: Self::dir(),
-            params: *params,
-            index: RwLock::new(index),
-            storage: RwLock::new(Storage::new()),
-        };
-
-        if db.dir.join(PARAMS_FILE).exists() {
-            let stdin = std::io::stdin();
-            let overwrite = {
-                eprint!("Database is already configured. Overwrite? (y/n): ");
-                let mut input = String::new();
-                stdin.read_line(&mut input).unwrap();
-                matches!(input.to_lowercase().trim(), "y")
-            };
+    /// Configure a new database in the data directory.
+    ///
+    /// If the directory already contains a database, it's only reset when
+    /// overwrite is true. Otherwise, this fails without modifying it.
+    pub fn configure(
+        dir: impl AsRef<Path>,
+        params: &Parameters,
+        overwrite: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if let IndexType::IvfPq { subspaces } = params.index {
+            if !params.dimension.is_multiple_of(subspaces) {
+                let message = "The dimension should be divisible by the \
+                    number of subspaces";
+                return Err(message.into());
+            }
+        }
 
+        let dir = dir.as_ref();
+        if Self::is_configured(dir)? {
             if !overwrite {
-                return;
+                let message =
+                    format!("{} is already configured", dir.display());
+                return Err(message.into());
             }
 
-            fs::remove_dir_all(&db.dir).expect("Failed to reset the database");
-            println!("The database has been reset successfully");
+            // The database must not be reset while it's open.
+            let lock = Self::lock_dir(dir)?;
+            fs::remove_dir_all(dir)?;
+            drop(lock);
+            tracing::info!("Removed the existing database");
         }
 
-        db.setup_dir().expect("Failed to setup database directory");
+        Self::setup_dir(dir.to_path_buf(), params)
+    }
+
+    /// Check if the data directory contains a database.
+    pub fn is_configured(
+        dir: impl AsRef<Path>,
+    ) -> Result<bool, Box<dyn Error>> {
+        let dir = dir.as_ref();
+        let manifest = dir.join(MANIFEST_FILE).try_exists()?;
+        Ok(manifest || dir.join(PARAMS_FILE).try_exists()?)
     }
 
-    pub fn open() -> Result<Self, Box<dyn Error>> {
-        let dir = Self::dir();
-        let params = Self::load_binary(dir.join(PARAMS_FILE))?;
-        let index = Self::load_binary(dir.join(INDEX_FILE))?;
-        let storage: Storage = Self::load_binary(dir.join(STORAGE_FILE))?;
+    /// Open the database from the latest snapshot.
+    ///
+    /// If an older snapshot generation is specified to be restored, the
+    /// database is rolled back to it. The newer generations and the
+    /// operations in the write-ahead log are discarded permanently.
+    ///
+    /// If the data files are encrypted, the same key must be provided to
+    /// open the database. If a key is provided, all data files must be
+    /// encrypted with it unless the existing files are to be encrypted.
+    ///
+    /// The data directory is locked until the database is dropped, so it
+    /// can't be opened by another process at the same time. In maintenance
+    /// mode, the retention is ignored and no retained snapshot generation
+    /// is removed because the retention of the server isn't known.
+    pub fn open(
+        dir: impl AsRef<Path>,
+        options: &OpenParameters,
+    ) -> Result<Self, Box<dyn Error>> {
+        let dir = dir.as_ref().to_path_buf();
+        let key = options.key.clone();
+
+        if !(0.0..=1.0).contains(&options.merge_threshold) {
+            let message = "Merge threshold should be between 0 and 1";
+            return Err(message.into());
+        }
+
+        let lock = Self::lock_dir(&dir)?;
+        let retention = match options.maintenance {
+            true => usize::MAX,
+            false => options.retention,
+        };
+
+        // Data directories created before the manifest was introduced store
+        // the snapshot files in the root of the directory.
+        let legacy = !dir.join(MANIFEST_FILE).try_exists()?;
+        if legacy && dir.join(PARAMS_FILE).try_exists()? {
+            Self::upgrade_legacy_dir(&dir)?;
+        }
+
+        let mut manifest = Self::load_manifest(&dir)?;
+        if options.encrypt_existing {
+            let message = "The key to encrypt the data files is not provided";
+            let key = key.as_ref().ok_or(message)?;
+            Self::encrypt_existing(&dir, &manifest, key)?;
+        }
+
+        if let Some(generation) = options.restore {
+            if generation != manifest.generation() {
+                manifest = Self::restore_generation(&dir, generation)?;
+            }
+        }
+
+        Self::cleanup_dir(&dir, &manifest, retention)?;
+
+        let (params, mut index, mut storage) =
+            Self::load_snapshot(&dir, &manifest, key.as_ref())?;
+        index.set_merge_threshold(options.merge_threshold);
+
+        if key.is_some() {
+            params.check_encryption()?;
+        }
 
         let count = storage.count();
         tracing::info!("Restored {count} record(s) from the disk");
 
+        let mut wal = WriteAheadLog::open(dir.join(WAL_FILE), key.clone())?;
+        let operations = wal.read()?;
+        for operation in operations.iter() {
+            Self::replay(index.as_mut(), storage.as_mut(), operation)?;
+        }
+
+        if !operations.is_empty() {
+            let count = operations.len();
+            tracing::info!("Replayed {count} operation(s) from the log");
+        }
+
         Ok(Database {
             dir,
             params,
+            manifest: Mutex::new(manifest),
             index: RwLock::new(index),
             storage: RwLock::new(storage),
+            wal: Mutex::new(wal),
+            writes: AtomicUsize::new(operations.len()),
+            retention,
+            merge_threshold: options.merge_threshold,
+            files: FileOptions { compression: options.compression, key },
+            backups: AtomicUsize::new(0),
+            _lock: Some(lock),
         })
     }
 
-    fn dir() -> PathBuf {
-        match env::var("ODB_DIR") {
-            Ok(dir) => PathBuf::from(dir),
-            Err(_) => PathBuf::from("oasysdb"),
+    /// Inspect the data directory without opening the database.
+    ///
+    /// The state is loaded from the latest snapshot and nothing in the data
+    /// directory is modified. The directory isn't locked, so the server can
+    /// commit a new snapshot and remove the old ones while they're loaded.
+    /// In that case, the new snapshot is loaded again. The operations in the
+    /// write-ahead log are not included.
+    pub fn inspect(
+        dir: impl AsRef<Path>,
+        key: Option<&EncryptionKey>,
+    ) -> Result<DatabaseInfo, Box<dyn Error>> {
+        let dir = dir.as_ref();
+        if !dir.join(MANIFEST_FILE).try_exists()? {
+            let message = format!("{} has no snapshot manifest", dir.display());
+            return Err(message.into());
         }
-    }
 
-    fn setup_dir(&self) -> Result<(), Box<dyn Error>> {
-        if self.dir.try_exists()? {
-            return Ok(());
-        }
+        let mut attempts = 0;
+        let (manifest, (params, index, storage)) = loop {
+            let manifest = Self::load_manifest(dir)?;
+            let snapshot = Self::load_snapshot(dir, &manifest, key);
 
-        fs::create_dir_all(&self.dir)?;
-        fs::create_dir_all(self.dir.join(TMP_DIR))?;
+            attempts += 1;
+            let changed = Self::load_manifest(dir)? != manifest;
+            if !changed || attempts == INSPECT_ATTEMPTS {
+                break (manifest, snapshot?);
+            }
+        };
 
-        self.create_snapshot()?;
-        Ok(())
-    }
+        let mut metadata_keys = BTreeMap::new();
+        for (_, record) in storage.iter() {
+            for key in record.metadata.keys() {
+                *metadata_keys.entry(key.to_owned()).or_default() += 1;
+            }
+        }
 
-    fn load_binary<T: DeserializeOwned>(
-        path: impl AsRef<Path>,
-    ) -> Result<T, Box<dyn Error>> {
-        let file = OpenOptions::new().read(true).open(path)?;
-        let reader = BufReader::new(file);
-        Ok(bincode::deserialize_from(reader)?)
+        Ok(DatabaseInfo {
+            params,
+            generation: manifest.generation(),
+            count: storage.count(),
+            cluster_sizes: index.stats().cluster_sizes,
+            metadata_keys,
+            files: files::list_files(dir)?,
+        })
     }
 
-    fn persist_as_binary<T: Serialize>(
-        &self,
-        path: impl AsRef<Path>,
-        data: T,
-    ) -> Result<(), Box<dyn Error>> {
-        let file_name = path.as_ref().file_name().unwrap();
-        let tmp_file = self.dir.join(TMP_DIR).join(file_name);
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&tmp_file)?;
+    /// List the snapshot generations in the data directory.
+    pub fn snapshots(
+        dir: impl AsRef<Path>,
+    ) -> Result<Vec<SnapshotInfo>, Box<dyn Error>> {
+        let dir = dir.as_ref();
+        let manifest = Self::load_manifest(dir)?;
+
+        let mut snapshots = vec![];
+        for (generation, path) in Self::generations(dir)? {
+            // Newer generations were never committed.
+            if generation > manifest.generation() {
+                continue;
+            }
 
-        let writer = BufWriter::new(file);
-        bincode::serialize_into(writer, &data)?;
-        fs::rename(&tmp_file, &path)?;
-        Ok(())
-    }
+            let mut size = 0;
+            for entry in fs::read_dir(&path)? {
+                size += entry?.metadata()?.len();
+            }
 
-    pub fn create_snapshot(&self) -> Result<SnapshotStats, Box<dyn Error>> {
-        self.persist_as_binary(self.dir.join(PARAMS_FILE), self.params)?;
+            snapshots.push(SnapshotInfo {
+                generation,
+                full: path.join(STORAGE_FILE).try_exists()?,
+                size,
+                created: fs::metadata(path.join(PARAMS_FILE))?.modified()?,
+                current: generation == manifest.generation(),
+            });
+        }
 
-        let index = self.index.read().unwrap();
-        self.persist_as_binary(self.dir.join(INDEX_FILE), &*index)?;
+        snapshots.sort_by_key(|snapshot| snapshot.generation);
+        Ok(snapshots)
+    }
 
-        let storage = self.storage.read().unwrap();
-        self.persist_as_binary(self.dir.join(STORAGE_FILE), &*storage)?;
+    fn setup_dir(
+        dir: PathBuf,
+        params: &Parameters,
+    ) -> Result<(), Box<dyn Error>> {
+        let index: Box<dyn Index> = match params.index {
+            IndexType::Ivf => Box::new(
+                IvfIndex::new()
+                    .with_metric(params.metric)
+                    .with_density(params.density),
+            ),
+            IndexType::Hnsw { m, ef_construction } => Box::new(
+                HnswIndex::new()
+                    .with_metric(params.metric)
+                    .with_m(m)
+                    .with_ef_construction(ef_construction),
+            ),
+            IndexType::Flat => {
+                Box::new(FlatIndex::new().with_metric(params.metric))
+            }
+            IndexType::IvfPq { subspaces } => Box::new(IvfPqIndex::new(
+                IvfIndex::new()
+                    .with_metric(params.metric)
+                    .with_density(params.density),
+                subspaces,
+            )),
+        };
 
-        let count = storage.count();
-        tracing::info!("Created a snapshot with {count} record(s)");
+        let storage: Box<dyn Storage> = match params.storage {
+            StorageMode::Memory => Box::new(MemoryStorage::new()),
+            StorageMode::Mapped => Box::new(MappedStorage::new()),
+        };
 
-        Ok(SnapshotStats { count })
+        Self::initialize_dir(dir, params, index, storage)
     }
 
-    fn validate_dimension(&self, vector: &Vector) -> Result<(), Status> {
-        if vector.len() != self.params.dimension {
-            return Err(Status::invalid_argument(format!(
-                "Invalid vector dimension: expected {}, got {}",
-                self.params.dimension,
-                vector.len()
-            )));
-        }
-
-        Ok(())
-    }
-}
+    /// Create the directory layout and persist the first snapshot.
+    fn initialize_dir(
+        dir: PathBuf,
+        params: &Parameters,
+        index: Box<dyn Index>,
+        storage: Box<dyn Storage>,
+    ) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(dir.join(TMP_DIR))?;
+        fs::create_dir_all(dir.join(SNAPSHOTS_DIR))?;
 
-#[tonic::async_trait]
-impl DatabaseService for Arc<Database> {
-    async fn heartbeat(
-        &self,
-        _request: Request<()>,
-    ) -> Result<Response<protos::HeartbeatResponse>, Status> {
-        let response = protos::HeartbeatResponse {
-            version: env!("CARGO_PKG_VERSION").to_string(),
+        let db = Database {
+            wal: Mutex::new(WriteAheadLog::open(dir.join(WAL_FILE), None)?),
+            dir,
+            params: *params,
+            manifest: Mutex::new(Manifest::default()),
+            index: RwLock::new(index),
+            storage: RwLock::new(storage),
+            writes: AtomicUsize::new(0),
+            retention: 1,
+            merge_threshold: 0.0,
+            files: FileOptions::default(),
+            backups: AtomicUsize::new(0),
+            _lock: None,
         };
 
-        Ok(Response::new(response))
+        db.create_snapshot()?;
+        Ok(())
     }
 
-    async fn snapshot(
-        &self,
-        _request: Request<()>,
-    ) -> Result<Response<protos::SnapshotResponse>, Status> {
-        let stats = self.create_snapshot().map_err(|e| {
-            let message = format!("Failed to create a snapshot: {e}");
-            Status::internal(message)
-        })?;
+    /// Upgrade a data directory created by an older version.
+    ///
+    /// Legacy data directories store raw bincode snapshot files in the root
+    /// of the directory. The files are loaded and persisted as the first
+    /// snapshot generation in the current format before they are removed.
+    fn upgrade_legacy_dir(dir: &Path) -> Result<(), Box<dyn Error>> {
+        let params = Self::load_params(dir.join(PARAMS_FILE), None)?;
+        let path = dir.join(INDEX_FILE);
+        let index = Self::load_index(path, vec![], &params, None)?;
+        let storage: MemoryStorage =
+            Self::load_binary(dir.join(STORAGE_FILE), None)?;
+        let storage = Box::new(storage);
+        Self::initialize_dir(dir.to_path_buf(), &params, index, storage)?;
+
+        for file in [PARAMS_FILE, INDEX_FILE, STORAGE_FILE] {
+            fs::remove_file(dir.join(file))?;
+        }
 
-        Ok(Response::new(stats.into()))
+        let version = FORMAT_VERSION;
+        tracing::info!("Upgraded the data directory to format v{version}");
+        Ok(())
     }
 
-    async fn insert(
-        &self,
-        request: Request<protos::InsertRequest>,
-    ) -> Result<Response<protos::InsertResponse>, Status> {
-        let record = match request.into_inner().record {
-            Some(record) => Record::try_from(record)?,
+    /// Lock the data directory against the other processes.
+    fn lock_dir(dir: &Path) -> Result<File, Box<dyn Error>> {
+        match files::lock_file(dir.join(LOCK_FILE))? {
+            Some(lock) => Ok(lock),
             None => {
-                let message = "Record data is required for insertion";
-                return Err(Status::invalid_argument(message));
+                let message =
+                    format!("{} is in use by another process", dir.display());
+                Err(message.into())
             }
-        };
+        }
+    }
 
-        self.validate_dimension(&record.vector)?;
+    /// Encrypt the data files of an existing data directory with the key.
+    ///
+    /// This enables the encryption for a data directory created without
+    /// it. The files of the snapshot generations are encrypted in place and
+    /// the write-ahead log is rewritten with encrypted entries. The files
+    /// that are already encrypted with the key are kept as they are.
+    fn encrypt_existing(
+        dir: &Path,
+        manifest: &Manifest,
+        key: &EncryptionKey,
+    ) -> Result<(), Box<dyn Error>> {
+        // The vector segments can't be encrypted, so the storage mode is
+        // checked before any file is modified.
+        let path = Self::snapshot_dir(dir, manifest.base).join(PARAMS_FILE);
+        let params = match Self::encrypt_data_file(&path, key)? {
+            Some(bytes) => Self::decode_params(&bytes, Some(key))?,
+            None => Self::load_params(&path, Some(key))?,
+        };
 
-        let id = RecordID::new();
+        params.check_encryption()?;
 
-        // Insert the record into the storage.
-        // This operation must be done before updating the index. Otherwise,
-        // the index won't have access to the record data.
-        let mut storage = self.storage.write().unwrap();
-        storage.insert(&id, &record)?;
+        for generation in Self::retained_generations(dir, manifest, usize::MAX)?
+        {
+            let snapshot_dir = Self::snapshot_dir(dir, generation);
+            for entry in fs::read_dir(&snapshot_dir)? {
+                let path = entry?.path();
+                if let Some(bytes) = Self::encrypt_data_file(&path, key)? {
+                    Self::persist_bytes(dir, &path, &bytes)?;
+                }
+            }
 
-        let mut index = self.index.write().unwrap();
-        index.insert(&id, &record, storage.records())?;
+            files::sync_dir(&snapshot_dir)?;
+        }
 
-        tracing::info!("Inserted a new record with ID: {id}");
-        Ok(Response::new(protos::InsertResponse { id: id.to_string() }))
+        let path = dir.join(WAL_FILE);
+        WriteAheadLog::open(path, Some(key.clone()))?.encrypt_existing()?;
+        tracing::info!("Encrypted the existing data files");
+        Ok(())
     }
 
-    async fn get(
-        &self,
-        request: Request<protos::GetRequest>,
-    ) -> Result<Response<protos::GetResponse>, Status> {
-        let request = request.into_inner();
-        let id = request.id.parse::<RecordID>()?;
+    /// Encode a data file again encrypted with the key.
+    ///
+    /// Returns None if the file is already encrypted with the key.
+    fn encrypt_data_file(
+        path: &Path,
+        key: &EncryptionKey,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let bytes = fs::read(path)?;
+        let header = FileHeader::from_bytes(&bytes);
+        let version = header.map_or(0, |header| header.version);
+
+        // The layout of the legacy parameters depends on the format version,
+        // so they're encoded in the current format.
+        if path.ends_with(PARAMS_FILE) && version < 3 {
+            let params = Self::load_params(path, None)?;
+            let options = FileOptions {
+                compression: Compression::None,
+                key: Some(key.clone()),
+            };
 
-        let storage = self.storage.read().unwrap();
-        let record = storage.get(&id)?.to_owned();
+            return Ok(Some(params.encode(&options)?));
+        }
 
-        let response = protos::GetResponse { record: Some(record.into()) };
-        Ok(Response::new(response))
+        encrypt_file(&bytes, key).map_err(|e| {
+            let message = format!("Failed to encrypt {}: {e}", path.display());
+            message.into()
+        })
     }
 
-    async fn delete(
-        &self,
-        request: Request<protos::DeleteRequest>,
-    ) -> Result<Response<()>, Status> {
-        let request = request.into_inner();
-        let id = request.id.parse::<RecordID>()?;
+    /// Return the directory of a snapshot generation.
+    fn snapshot_dir(dir: &Path, generation: u64) -> PathBuf {
+        dir.join(SNAPSHOTS_DIR).join(generation.to_string())
+    }
 
-        let mut index = self.index.write().unwrap();
-        index.delete(&id)?;
+    /// Return the generation directories in the data directory.
+    fn generations(dir: &Path) -> Result<Vec<(u64, PathBuf)>, Box<dyn Error>> {
+        let mut generations = vec![];
+        for entry in fs::read_dir(dir.join(SNAPSHOTS_DIR))? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy();
+            if let Ok(generation) = name.parse::<u64>() {
+                generations.push((generation, path));
+            }
+        }
 
-        let mut storage = self.storage.write().unwrap();
-        storage.delete(&id)?;
+        Ok(generations)
+    }
 
-        tracing::info!("Deleted a record with ID: {id}");
-        Ok(Response::new(()))
+    /// Resolve the snapshot chain to load a generation.
+    ///
+    /// Each delta generation is based on the previous generation, so the
+    /// chain starts from the latest full generation up to the given one.
+    fn resolve_chain(
+        dir: &Path,
+        generation: u64,
+    ) -> Result<Manifest, Box<dyn Error>> {
+        let mut deltas = vec![];
+        let mut current = generation;
+        loop {
+            let snapshot_dir = Self::snapshot_dir(dir, current);
+            if !snapshot_dir.try_exists()? {
+                let message = match current == generation {
+                    true => format!("Snapshot {generation} is not available"),
+                    false => format!("Snapshot {generation} is incomplete"),
+                };
+
+                return Err(message.into());
+            }
+
+            if snapshot_dir.join(STORAGE_FILE).try_exists()? {
+                deltas.reverse();
+                return Ok(Manifest { base: current, deltas });
+            }
+
+            deltas.push(current);
+            current -= 1;
+        }
     }
 
-    async fn update(
-        &self,
-        request: Request<protos::UpdateRequest>,
-    ) -> Result<Response<()>, Status> {
-        let request = request.into_inner();
-        let id = request.id.parse::<RecordID>()?;
+    /// Return the generations needed to load the retained generations.
+    ///
+    /// The retained generations are the latest ones up to the retention
+    /// count. Older generations are also needed when they're part of the
+    /// chain of a retained generation.
+    fn retained_generations(
+        dir: &Path,
+        manifest: &Manifest,
+        retention: usize,
+    ) -> Result<HashSet<u64>, Box<dyn Error>> {
+        let mut retained: HashSet<u64> = manifest.chain().into_iter().collect();
+        let latest = manifest.generation();
+        let retention = retention.max(1) as u64;
+        let oldest = (latest + 1).saturating_sub(retention).max(1);
+
+        for generation in oldest..latest {
+            if !Self::snapshot_dir(dir, generation).try_exists()? {
+                continue;
+            }
 
-        let mut metadata = HashMap::new();
-        for (key, value) in request.metadata {
-            metadata.insert(key, value.try_into()?);
+            // Generations with an incomplete chain can't be restored.
+            if let Ok(chain) = Self::resolve_chain(dir, generation) {
+                retained.extend(chain.chain());
+            }
         }
 
-        let mut storage = self.storage.write().unwrap();
-        storage.update(&id, &metadata)?;
+        Ok(retained)
+    }
 
-        tracing::info!("Updated metadata for a record: {id}");
-        Ok(Response::new(()))
+    /// Roll the data directory back to an older snapshot generation.
+    ///
+    /// The write-ahead log is cleared before the manifest is replaced so
+    /// that the newer operations are never replayed on top of the older
+    /// state. The newer generations are removed when the directory is
+    /// cleaned up.
+    fn restore_generation(
+        dir: &Path,
+        generation: u64,
+    ) -> Result<Manifest, Box<dyn Error>> {
+        let manifest = Self::resolve_chain(dir, generation)?;
+        WriteAheadLog::open(dir.join(WAL_FILE), None)?.clear()?;
+
+        let path = dir.join(MANIFEST_FILE);
+        Self::persist_as_binary(dir, path, &manifest, &FileOptions::default())?;
+        files::sync_dir(dir)?;
+
+        tracing::warn!("Restored the database to snapshot {generation}");
+        Ok(manifest)
     }
 
-    async fn query(
+    /// Load the database state from the snapshot chain of the manifest.
+    fn load_snapshot(
+        dir: &Path,
+        manifest: &Manifest,
+        key: Option<&EncryptionKey>,
+    ) -> Result<SnapshotState, Box<dyn Error>> {
+        let base_dir = Self::snapshot_dir(dir, manifest.base);
+        let params = Self::load_params(base_dir.join(PARAMS_FILE), key)?;
+        let path = base_dir.join(INDEX_FILE);
+        let deltas = manifest.deltas.iter().map(|generation| {
+            Self::snapshot_dir(dir, *generation).join(INDEX_DELTA_FILE)
+        });
+
+        let index = Self::load_index(path, deltas, &params, key)?;
+        let storage = Self::load_storage(dir, &params, manifest, key)?;
+        Ok((params, index, storage))
+    }
+
+    /// Load the snapshot manifest from the data directory.
+    fn load_manifest(dir: &Path) -> Result<Manifest, Box<dyn Error>> {
+        let path = dir.join(MANIFEST_FILE);
+        let bytes = fs::read(&path)?;
+        let (version, data) = verify_file(&bytes, None).map_err(|e| {
+            let message = format!("Failed to load {}: {e}", path.display());
+            Box::<dyn Error>::from(message)
+        })?;
+
+        // Manifests prior to version 2 only contain the generation of
+        // a full snapshot without any deltas.
+        if version < 2 {
+            let generation: u64 = bincode::deserialize(&data)?;
+            return Ok(Manifest { base: generation, deltas: vec![] });
+        }
+
+        Ok(bincode::deserialize(&data)?)
+    }
+
+    /// Load the database parameters from a snapshot.
+    fn load_params(
+        path: impl AsRef<Path>,
+        key: Option<&EncryptionKey>,
+    ) -> Result<Parameters, Box<dyn Error>> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+        Self::decode_params(&bytes, key).map_err(|e| {
+            let message = format!("Failed to load {}: {e}", path.display());
+            message.into()
+        })
+    }
+
+    /// Decode the database parameters based on the format version.
+    fn decode_params(
+        bytes: &[u8],
+        key: Option<&EncryptionKey>,
+    ) -> Result<Parameters, Box<dyn Error>> {
+        let (version, data) = verify_file(bytes, key)?;
+
+        // Parameters prior to version 3 don't contain the storage mode.
+        if version < 3 {
+            let params: LegacyParameters = bincode::deserialize(&data)?;
+            return Ok(params.into());
+        }
+
+        // Parameters prior to version 7 don't contain the index type.
+        if version < 7 {
+            let params: StorageParameters = bincode::deserialize(&data)?;
+            return Ok(params.into());
+        }
+
+        Ok(bincode::deserialize(&data)?)
+    }
+
+    /// Load the index from a snapshot and apply the deltas in order.
+    ///
+    /// The index files don't contain the index type, so the index is loaded
+    /// based on the type in the parameters.
+    fn load_index(
+        path: impl AsRef<Path>,
+        deltas: impl IntoIterator<Item = PathBuf>,
+        params: &Parameters,
+        key: Option<&EncryptionKey>,
+    ) -> Result<Box<dyn Index>, Box<dyn Error>> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+        let (version, data) = verify_file(&bytes, key).map_err(|e| {
+            let message = format!("Failed to load {}: {e}", path.display());
+            Box::<dyn Error>::from(message)
+        })?;
+
+        match params.index {
+            IndexType::Ivf => {
+                // Indices prior to version 6 don't contain the locations.
+                let mut index: IvfIndex = match version < 6 {
+                    true => bincode::deserialize::<LegacyIndex>(&data)?.into(),
+                    false => bincode::deserialize(&data)?,
+                };
+
+                for path in deltas {
+                    index.apply_delta(Self::load_binary(path, key)?);
+                }
+
+                Ok(Box::new(index))
+            }
+            IndexType::Hnsw { .. } => {
+                let mut index: HnswIndex = bincode::deserialize(&data)?;
+                for path in deltas {
+                    index.apply_delta(Self::load_binary(path, key)?);
+                }
+
+                Ok(Box::new(index))
+            }
+            IndexType::Flat => {
+                let mut index: FlatIndex = bincode::deserialize(&data)?;
+                for path in deltas {
+                    index.apply_delta(Self::load_binary(path, key)?);
+                }
+
+                Ok(Box::new(index))
+            }
+            IndexType::IvfPq { .. } => {
+                let mut index: IvfPqIndex = bincode::deserialize(&data)?;
+                for path in deltas {
+                    index.apply_delta(Self::load_binary(path, key)?);
+                }
+
+                Ok(Box::new(index))
+            }
+        }
+    }
+
+    /// Load the storage from the snapshot chain.
+    ///
+    /// The snapshots are persisted without the storage mode, so the storage
+    /// is loaded based on the mode in the parameters. The mapped storage is
+    /// attached to the vector segments after the deltas are applied.
+    fn load_storage(
+        dir: &Path,
+        params: &Parameters,
+        manifest: &Manifest,
+        key: Option<&EncryptionKey>,
+    ) -> Result<Box<dyn Storage>, Box<dyn Error>> {
+        let base_dir = Self::snapshot_dir(dir, manifest.base);
+        let path = base_dir.join(STORAGE_FILE);
+        let deltas = manifest.deltas.iter().map(|generation| {
+            Self::snapshot_dir(dir, *generation).join(STORAGE_DELTA_FILE)
+        });
+
+        match params.storage {
+            StorageMode::Memory => {
+                let mut storage: MemoryStorage = Self::load_binary(path, key)?;
+                for path in deltas {
+                    storage.apply_delta(Self::load_binary(path, key)?);
+                }
+
+                Ok(Box::new(storage))
+            }
+            StorageMode::Mapped => {
+                let mut storage: MappedStorage = Self::load_binary(path, key)?;
+                let mut reserved = storage.used_slots();
+                for path in deltas {
+                    storage.apply_delta(Self::load_binary(path, key)?);
+                }
+
+                // The slots referenced by the other retained generations
+                // must not be reused so that they can still be restored.
+                for (generation, path) in Self::generations(dir)? {
+                    if generation == manifest.base {
+                        continue;
+                    }
+
+                    match path.join(STORAGE_FILE).try_exists()? {
+                        true => {
+                            let path = path.join(STORAGE_FILE);
+                            let base: MappedStorage =
+                                Self::load_binary(path, key)?;
+                            reserved.extend(base.used_slots());
+                        }
+                        false => {
+                            let path = path.join(STORAGE_DELTA_FILE);
+                            let delta: RecordDelta<MappedRecord> =
+                                Self::load_binary(path, key)?;
+                            let slots = delta.upserts.values();
+                            reserved.extend(slots.map(|record| record.slot));
+                        }
+                    }
+                }
+
+                let vectors_dir = dir.join(VECTORS_DIR);
+                let vectors =
+                    VectorSegments::open(vectors_dir, params.dimension)?;
+                storage.attach(vectors, &reserved);
+                Ok(Box::new(storage))
+            }
+        }
+    }
+
+    /// Remove leftovers of snapshots that were never committed.
+    fn cleanup_dir(
+        dir: &Path,
+        manifest: &Manifest,
+        retention: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        fs::remove_dir_all(dir.join(TMP_DIR))?;
+        fs::create_dir_all(dir.join(TMP_DIR))?;
+        Self::prune_snapshots(dir, manifest, retention)
+    }
+
+    /// Remove the generation directories that are no longer retained.
+    ///
+    /// Besides the superseded generations, this removes the generations
+    /// that were interrupted before the manifest was replaced.
+    fn prune_snapshots(
+        dir: &Path,
+        manifest: &Manifest,
+        retention: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let retained = Self::retained_generations(dir, manifest, retention)?;
+        for entry in fs::read_dir(dir.join(SNAPSHOTS_DIR))? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy();
+            let generation = name.parse::<u64>().ok();
+            if !generation.is_some_and(|g| retained.contains(&g)) {
+                fs::remove_dir_all(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn load_binary<T: DeserializeOwned>(
+        path: impl AsRef<Path>,
+        key: Option<&EncryptionKey>,
+    ) -> Result<T, Box<dyn Error>> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+        decode_file(&bytes, key).map_err(|e| {
+            let message = format!("Failed to load {}: {e}", path.display());
+            message.into()
+        })
+    }
+
+    /// Persist the data file atomically with the file options.
+    fn persist_as_binary<T: Encode + ?Sized>(
+        dir: &Path,
+        path: impl AsRef<Path>,
+        data: &T,
+        options: &FileOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        Self::persist_bytes(dir, path, &data.encode(options)?)
+    }
+
+    /// Replace the file atomically with the encoded bytes.
+    fn persist_bytes(
+        dir: &Path,
+        path: impl AsRef<Path>,
+        bytes: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let file_name = path.as_ref().file_name().unwrap();
+        let tmp_file = dir.join(TMP_DIR).join(file_name);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_file)?;
+
+        let mut writer = BufWriter::new(file);
+        writer.write_all(bytes)?;
+        writer.into_inner()?.sync_all()?;
+
+        fs::rename(&tmp_file, &path)?;
+        Ok(())
+    }
+
+    /// Persist the database state as a new snapshot generation.
+    ///
+    /// Most snapshots only persist the changes since the previous snapshot.
+    /// After the chain of deltas reaches the compaction threshold, the full
+    /// state is persisted as a new base and the previous chain is removed.
+    ///
+    /// The index and storage are locked only while a frozen view of their
+    /// state is captured and the write-ahead log is rotated. The records
+    /// are shared with the live storage until they are modified, so the
+    /// capture is cheap and writers aren't blocked while the snapshot is
+    /// written to the disk.
+    ///
+    /// The snapshot files are written into a temporary directory which is
+    /// moved into place as a whole before the manifest is replaced. If the
+    /// process stops at any point, the previous snapshot remains intact.
+    pub fn create_snapshot(&self) -> Result<SnapshotStats, Box<dyn Error>> {
+        // Holding the manifest lock prevents concurrent snapshots from
+        // writing the same generation.
+        let mut manifest = self.manifest.lock().unwrap();
+        let generation = manifest.generation() + 1;
+        let full =
+            manifest.base == 0 || manifest.deltas.len() >= COMPACTION_THRESHOLD;
+
+        // The log is rotated while holding the locks so that the sealed
+        // segments contain exactly the operations captured by the snapshot.
+        let storage = self.storage.read().unwrap();
+        let index = self.index.read().unwrap();
+
+        let storage_changes = storage.take_changes();
+        let index_changes = index.take_changes();
+        let writes = self.writes.swap(0, Ordering::Relaxed);
+
+        let count = storage.count();
+        let snapshot = match full {
+            true => Snapshot::Full(index.freeze(), storage.freeze()),
+            false => Snapshot::Delta(
+                index.delta(&index_changes),
+                storage.delta(&storage_changes),
+            ),
+        };
+
+        let vectors = storage.segments();
+        let segment = self.wal.lock().unwrap().rotate();
+
+        drop(index);
+        drop(storage);
+
+        let result = segment.and_then(|segment| {
+            let new_manifest = self.persist_snapshot(
+                generation,
+                &manifest,
+                &snapshot,
+                vectors.as_ref(),
+            )?;
+
+            Ok((segment, new_manifest))
+        });
+
+        let (segment, new_manifest) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                // The changes must be persisted by the next snapshot.
+                self.storage.read().unwrap().restore_changes(storage_changes);
+                self.index.read().unwrap().restore_changes(index_changes);
+                self.writes.fetch_add(writes, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
+
+        // The slots released before the capture are no longer referenced by
+        // the committed snapshot and can be reused once the older snapshots
+        // referencing them are no longer retained. Ongoing backups might
+        // still reference them, so they're recycled after the backups end.
+        if self.backups.load(Ordering::SeqCst) == 0 {
+            self.storage.read().unwrap().recycle(self.retention);
+        }
+
+        // The snapshot is committed at this point. Failing to remove the
+        // sealed segments only causes their operations to be skipped when
+        // the log is replayed.
+        if let Err(e) = self.wal.lock().unwrap().remove_segments(segment) {
+            tracing::warn!("Failed to remove the log segments: {e}");
+        }
+
+        // The generations beyond the retention are no longer needed. Failing
+        // to remove them doesn't affect the snapshot since they're pruned
+        // again on open.
+        *manifest = new_manifest;
+        let retention = self.retention;
+        if let Err(e) = Self::prune_snapshots(&self.dir, &manifest, retention) {
+            tracing::warn!("Failed to remove the old snapshots: {e}");
+        }
+
+        let kind = if full { "full" } else { "incremental" };
+        tracing::info!(
+            "Created {kind} snapshot {generation} with {count} record(s)"
+        );
+
+        Ok(SnapshotStats { count })
+    }
+
+    /// Write the captured state as a snapshot generation and commit it.
+    ///
+    /// The vector segments are flushed before the snapshot is committed
+    /// because the snapshot only references the vectors by their slots.
+    ///
+    /// Returns the manifest that includes the new generation.
+    fn persist_snapshot(
+        &self,
+        generation: u64,
+        manifest: &Manifest,
+        snapshot: &Snapshot,
+        vectors: Option<&VectorSegments>,
+    ) -> Result<Manifest, Box<dyn Error>> {
+        if let Some(vectors) = vectors {
+            vectors.flush()?;
+        }
+
+        let dir = &self.dir;
+        let tmp_dir = dir.join(TMP_DIR).join(generation.to_string());
+        if tmp_dir.try_exists()? {
+            fs::remove_dir_all(&tmp_dir)?;
+        }
+
+        fs::create_dir_all(&tmp_dir)?;
+        let options = &self.files;
+        let path = tmp_dir.join(PARAMS_FILE);
+        Self::persist_as_binary(dir, path, &self.params, options)?;
+
+        match snapshot {
+            Snapshot::Full(index, storage) => {
+                let path = tmp_dir.join(INDEX_FILE);
+                Self::persist_as_binary(dir, path, &**index, options)?;
+
+                let path = tmp_dir.join(STORAGE_FILE);
+                Self::persist_as_binary(dir, path, &**storage, options)?;
+            }
+            Snapshot::Delta(index, storage) => {
+                let path = tmp_dir.join(INDEX_DELTA_FILE);
+                Self::persist_as_binary(dir, path, &**index, options)?;
+
+                let path = tmp_dir.join(STORAGE_DELTA_FILE);
+                Self::persist_as_binary(dir, path, &**storage, options)?;
+            }
+        }
+
+        files::sync_dir(&tmp_dir)?;
+
+        let snapshot_dir = Self::snapshot_dir(dir, generation);
+        fs::rename(&tmp_dir, &snapshot_dir)?;
+        files::sync_dir(dir.join(SNAPSHOTS_DIR))?;
+
+        let new_manifest = match snapshot {
+            Snapshot::Full(..) => Manifest { base: generation, deltas: vec![] },
+            Snapshot::Delta(..) => {
+                let mut deltas = manifest.deltas.clone();
+                deltas.push(generation);
+                Manifest { base: manifest.base, deltas }
+            }
+        };
+
+        // Replacing the manifest commits the snapshot.
+        // The manifest is tiny and contains no records, so it's never
+        // compressed nor encrypted.
+        let path = dir.join(MANIFEST_FILE);
+        let options = FileOptions::default();
+        Self::persist_as_binary(dir, path, &new_manifest, &options)?;
+        files::sync_dir(dir)?;
+        Ok(new_manifest)
+    }
+
+    /// Write a consistent backup archive of the database.
+    ///
+    /// The database state is captured the same way as a full snapshot, so
+    /// writers are only blocked while the capture is taken. The archive
+    /// contains the files of a full snapshot and, with memory-mapped
+    /// storage, the vector segments which are copied in chunks while
+    /// holding the storage lock to prevent torn vectors.
+    pub fn create_backup(
+        &self,
+        writer: impl Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let storage = self.storage.read().unwrap();
+        let index = self.index.read().unwrap();
+
+        // The vector slots referenced by the capture must not be reused
+        // until the backup ends.
+        let _backup = BackupGuard::new(&self.backups);
+        let frozen_index = index.freeze();
+        let frozen_storage = storage.freeze();
+        let vectors = storage.segments();
+
+        drop(index);
+        drop(storage);
+
+        let mut archive = ArchiveWriter::new(writer)?;
+        let options = &self.files;
+        archive.write_file(PARAMS_FILE, &self.params.encode(options)?)?;
+        archive.write_file(INDEX_FILE, &frozen_index.encode(options)?)?;
+        let storage_file = frozen_storage.encode(options)?;
+        archive.write_file(STORAGE_FILE, &storage_file)?;
+
+        if let Some(vectors) = vectors {
+            let mut buffer = vec![0; MAX_BLOCK_SIZE];
+            for segment in 0..vectors.count() {
+                let name = VectorSegments::file_name(segment);
+                let name = format!("{VECTORS_DIR}/{name}");
+
+                let size = vectors.segment_size();
+                for offset in (0..size).step_by(MAX_BLOCK_SIZE) {
+                    let chunk =
+                        &mut buffer[..MAX_BLOCK_SIZE.min(size - offset)];
+                    let storage = self.storage.read().unwrap();
+                    vectors.copy_bytes(segment, offset, chunk)?;
+                    drop(storage);
+
+                    archive.write_block(&name, chunk)?;
+                }
+            }
+        }
+
+        archive.finish()?;
+        tracing::info!("Created a backup of the database");
+        Ok(())
+    }
+
+    /// Restore the data directory from a backup archive.
+    ///
+    /// The data directory must not exist. The archive is extracted as the
+    /// first snapshot generation and the manifest is written last, so the
+    /// directory is only usable when the restore completes. The partially
+    /// restored directory is removed if the restore fails.
+    ///
+    /// The snapshot files in the archive are validated with the key, so the
+    /// key used by the backed up database must be provided.
+    pub fn restore_backup(
+        dir: impl AsRef<Path>,
+        reader: impl Read,
+        key: Option<&EncryptionKey>,
+    ) -> Result<(), Box<dyn Error>> {
+        let dir = dir.as_ref();
+        if dir.try_exists()? {
+            let message = format!("{} already exists", dir.display());
+            return Err(message.into());
+        }
+
+        // The cleanup error is only logged so that the restore error which
+        // explains the failure is returned.
+        let result = Self::extract_backup(dir, reader, key);
+        if result.is_err() && dir.exists() {
+            if let Err(e) = fs::remove_dir_all(dir) {
+                tracing::warn!("Failed to remove {}: {e}", dir.display());
+            }
+        }
+
+        result
+    }
+
+    fn extract_backup(
+        dir: &Path,
+        reader: impl Read,
+        key: Option<&EncryptionKey>,
+    ) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(dir.join(TMP_DIR))?;
+        fs::create_dir_all(dir.join(VECTORS_DIR))?;
+
+        let snapshot_dir = Self::snapshot_dir(dir, 1);
+        fs::create_dir_all(&snapshot_dir)?;
+
+        let mut archive = ArchiveReader::new(reader)?;
+        let mut current: Option<(String, File)> = None;
+        while let Some(block) = archive.next_block()? {
+            // Blocks of the same file are written consecutively.
+            let (name, file) = match current.take() {
+                Some((name, file)) if name == block.name => (name, file),
+                previous => {
+                    if let Some((_, file)) = previous {
+                        file.sync_all()?;
+                    }
+
+                    let path = Self::archive_file_path(dir, &block.name)?;
+                    let file = OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(path)?;
+
+                    (block.name, file)
+                }
+            };
+
+            let file = &mut current.insert((name, file)).1;
+            file.write_all(&block.data)?;
+        }
+
+        if let Some((_, file)) = current {
+            file.sync_all()?;
+        }
+
+        // Validate the snapshot files before committing the restore.
+        let path = snapshot_dir.join(PARAMS_FILE);
+        let params = Self::load_params(path, key)?;
+        let path = snapshot_dir.join(INDEX_FILE);
+        Self::load_index(path, vec![], &params, key)?;
+        let manifest = Manifest { base: 1, deltas: vec![] };
+        Self::load_storage(dir, &params, &manifest, key)?;
+
+        files::sync_dir(&snapshot_dir)?;
+        files::sync_dir(dir.join(VECTORS_DIR))?;
+        let path = dir.join(MANIFEST_FILE);
+        Self::persist_as_binary(dir, path, &manifest, &FileOptions::default())?;
+        files::sync_dir(dir)?;
+
+        tracing::info!("Restored the database from the backup archive");
+        Ok(())
+    }
+
+    /// Return the path to extract a backup archive file into.
+    ///
+    /// The snapshot files are extracted as the first generation. Unknown
+    /// file names are rejected to prevent writing outside of the data
+    /// directory.
+    fn archive_file_path(
+        dir: &Path,
+        name: &str,
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        if [PARAMS_FILE, INDEX_FILE, STORAGE_FILE].contains(&name) {
+            return Ok(Self::snapshot_dir(dir, 1).join(name));
+        }
+
+        let segment = name
+            .strip_prefix(&format!("{VECTORS_DIR}/"))
+            .and_then(|file| file.strip_prefix("segment_"))
+            .and_then(|segment| segment.parse::<usize>().ok());
+
+        match segment {
+            Some(segment) => {
+                let file_name = VectorSegments::file_name(segment);
+                Ok(dir.join(VECTORS_DIR).join(file_name))
+            }
+            None => Err(format!("Unexpected archive file {name}").into()),
+        }
+    }
+
+    /// Return the number of writes since the previous snapshot.
+    pub fn pending_writes(&self) -> usize {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    /// Return the vector dimension configured for the database.
+    pub fn dimension(&self) -> usize {
+        self.params.dimension
+    }
+
+    /// Log an operation to the write-ahead log and apply it.
+    ///
+    /// The operation is applied only after it's persisted in the log. Both
+    /// locks are held during the process so that the order of operations
+    /// in the log matches the order they are applied.
+    #[allow(clippy::result_large_err)]
+    fn commit(&self, operation: Operation) -> Result<(), Status> {
+        let mut storage = self.storage.write().unwrap();
+        let mut index = self.index.write().unwrap();
+        self.validate(storage.as_ref(), &operation)?;
+
+        self.wal.lock().unwrap().append(&operation).map_err(|e| {
+            let message = format!("Failed to write to the log: {e}");
+            Status::internal(message)
+        })?;
+
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        Self::apply(index.as_mut(), storage.as_mut(), &operation)
+    }
+
+    /// Insert the records in bulk and return the number of records.
+    ///
+    /// The records are applied directly without the write-ahead log, so
+    /// they're only persisted by the next snapshot. The dimensions are
+    /// validated as the records are inserted and the import stops at the
+    /// first invalid record.
+    pub fn import(
+        &self,
+        records: impl IntoIterator<Item = Result<Record, Box<dyn Error>>>,
+    ) -> Result<usize, Box<dyn Error>> {
+        let mut storage = self.storage.write().unwrap();
+        let mut index = self.index.write().unwrap();
+
+        let mut count = 0;
+        for record in records {
+            let record = record?;
+            self.validate_dimension(&record.vector).map_err(|e| {
+                format!(
+                    "Failed to import record {}: {}",
+                    count + 1,
+                    e.message()
+                )
+            })?;
+
+            let operation = Operation::Insert(RecordID::new(), record);
+            Self::apply(index.as_mut(), storage.as_mut(), &operation)?;
+            count += 1;
+        }
+
+        self.writes.fetch_add(count, Ordering::Relaxed);
+        Ok(count)
+    }
+
+    /// Pass all records to the writer in the order of their IDs.
+    ///
+    /// The IDs are collected first and the records are then read in batches
+    /// so that writers are only blocked briefly. Records deleted during the
+    /// export are skipped, and records inserted during it aren't included.
+    pub fn export(
+        &self,
+        mut write: impl FnMut(RecordID, Record) -> Result<(), Box<dyn Error>>,
+    ) -> Result<usize, Box<dyn Error>> {
+        let mut ids = self.storage.read().unwrap().ids();
+        ids.sort_unstable();
+
+        let mut count = 0;
+        for batch in ids.chunks(READ_BATCH_SIZE) {
+            let storage = self.storage.read().unwrap();
+            let records: Vec<(RecordID, Record)> = batch
+                .iter()
+                .zip(storage.get_many(batch))
+                .filter_map(|(id, record)| Some((*id, record?.into_owned())))
+                .collect();
+
+            drop(storage);
+
+            for (id, record) in records {
+                write(id, record)?;
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Retrain the index centroids with KMeans and reassign all records.
+    ///
+    /// The centroids are fitted on a random sample of the vectors, or on
+    /// all vectors if the sample size isn't specified. The codebooks of the
+    /// IVF-PQ index are trained on the same sample. The new index is
+    /// built while the queries and writes continue on the current index.
+    /// The writes made in the meantime are reconciled before the new index
+    /// is swapped in. The new index is persisted by the next snapshot.
+    #[allow(clippy::result_large_err)]
+    pub fn retrain(
+        &self,
+        sample: Option<usize>,
+    ) -> Result<RetrainStats, Status> {
+        if !matches!(
+            self.params.index,
+            IndexType::Ivf | IndexType::IvfPq { .. }
+        ) {
+            let message = "Retraining is only supported by the IVF indexes";
+            return Err(Status::failed_precondition(message));
+        }
+
+        let ids = self.storage.read().unwrap().ids();
+
+        // The clusters are half full on average so that the new records
+        // can be inserted without splitting the clusters right away.
+        let half_density = (self.params.density / 2).max(1);
+        let n_clusters = ids.len().div_ceil(half_density);
+
+        let training_ids = match sample {
+            Some(size) => {
+                let size = size.max(n_clusters);
+                let mut rng = rand::thread_rng();
+                ids.choose_multiple(&mut rng, size).copied().collect()
+            }
+            None => ids.clone(),
+        };
+
+        let mut vectors = Vec::with_capacity(training_ids.len());
+        for batch in training_ids.chunks(READ_BATCH_SIZE) {
+            let storage = self.storage.read().unwrap();
+            let records = storage.get_many(batch).into_iter().flatten();
+            vectors.extend(records.map(|record| record.vector.clone()));
+        }
+
+        let index = IvfIndex::new()
+            .with_metric(self.params.metric)
+            .with_density(self.params.density)
+            .with_merge_threshold(self.merge_threshold)
+            .train(&vectors, n_clusters)?;
+
+        match self.params.index {
+            IndexType::IvfPq { subspaces } => {
+                let index =
+                    IvfPqIndex::new(index, subspaces).train(&vectors)?;
+                drop(vectors);
+                self.reassign(index, ids)
+            }
+            _ => {
+                drop(vectors);
+                self.reassign(index, ids)
+            }
+        }
+    }
+
+    /// Assign the records to the retrained index and swap it in.
+    #[allow(clippy::result_large_err)]
+    fn reassign(
+        &self,
+        mut index: impl ClusteredIndex + 'static,
+        ids: Vec<RecordID>,
+    ) -> Result<RetrainStats, Status> {
+        for batch in ids.chunks(READ_BATCH_SIZE) {
+            let storage = self.storage.read().unwrap();
+            for (id, record) in batch.iter().zip(storage.get_many(batch)) {
+                if let Some(record) = record {
+                    index.assign(id, &record.vector);
+                }
+            }
+        }
+
+        // Both locks are held from here so that no writes are missed.
+        let storage = self.storage.read().unwrap();
+        let mut current = self.index.write().unwrap();
+
+        let assigned: HashSet<RecordID> = ids.into_iter().collect();
+        let live: HashSet<RecordID> = storage.ids().into_iter().collect();
+        for id in live.difference(&assigned) {
+            index.assign(id, &storage.get(id)?.vector);
+        }
+
+        index.retain(|id| live.contains(id));
+
+        let stats = RetrainStats {
+            records: live.len(),
+            clusters: index.stats().cluster_sizes.len(),
+        };
+
+        *current = Box::new(index);
+        tracing::info!(
+            "Retrained the index into {} cluster(s) from {} record(s)",
+            stats.clusters,
+            stats.records
+        );
+
+        Ok(stats)
+    }
+
+    /// Check the consistency between the index and the storage.
+    pub fn verify(&self) -> ConsistencyReport {
+        let storage = self.storage.read().unwrap();
+        let index = self.index.read().unwrap();
+        Self::check_consistency(index.as_ref(), storage.as_ref())
+    }
+
+    /// Repair the inconsistencies by rebuilding the index from the storage.
+    ///
+    /// This returns the inconsistencies found before the repair. The storage
+    /// is the source of truth, so the dangling IDs are dropped and the
+    /// orphaned records are indexed again. The repaired state is persisted
+    /// by the next snapshot.
+    #[allow(clippy::result_large_err)]
+    pub fn repair(&self) -> Result<ConsistencyReport, Status> {
+        let mut storage = self.storage.write().unwrap();
+        let mut index = self.index.write().unwrap();
+
+        let report = Self::check_consistency(index.as_ref(), storage.as_ref());
+        if report.is_consistent() {
+            return Ok(report);
+        }
+
+        storage.recount();
+        index.rebuild(storage.as_ref())?;
+
+        tracing::info!("Rebuilt the index from {} record(s)", storage.count());
+        Ok(report)
+    }
+
+    fn check_consistency(
+        index: &dyn Index,
+        storage: &dyn Storage,
+    ) -> ConsistencyReport {
+        let ids: HashSet<RecordID> = storage.ids().into_iter().collect();
+        let mut report = ConsistencyReport {
+            count: storage.count(),
+            records: ids.len(),
+            ..Default::default()
+        };
+
+        for (cluster_id, size) in index.stats().cluster_sizes.iter().enumerate()
+        {
+            if *size == 0 {
+                report.empty_clusters.push(cluster_id);
+            }
+        }
+
+        let mut indexed = HashSet::new();
+        for id in index.ids() {
+            if !indexed.insert(id) {
+                report.duplicates.push(id);
+            } else if !ids.contains(&id) {
+                report.dangling.push(id);
+            }
+        }
+
+        report.orphaned = ids.difference(&indexed).copied().collect();
+        report
+    }
+
+    /// Apply an operation to the index and storage.
+    #[allow(clippy::result_large_err)]
+    fn apply(
+        index: &mut dyn Index,
+        storage: &mut dyn Storage,
+        operation: &Operation,
+    ) -> Result<(), Status> {
+        match operation {
+            Operation::Insert(id, record) => {
+                // Insert the record into the storage.
+                // This operation must be done before updating the index.
+                // Otherwise, the index won't have access to the record data.
+                storage.insert(id, record)?;
+                index.insert(id, record, storage)
+            }
+            Operation::Delete(id) => {
+                index.delete(id, storage)?;
+                storage.delete(id)
+            }
+            Operation::Update(id, metadata) => storage.update(id, metadata),
+        }
+    }
+
+    /// Apply a logged operation while opening the database.
+    ///
+    /// The log might contain operations that are already included in the
+    /// snapshot when the process stops after a snapshot is committed but
+    /// before the sealed log segments are removed. Those operations are
+    /// skipped.
+    #[allow(clippy::result_large_err)]
+    fn replay(
+        index: &mut dyn Index,
+        storage: &mut dyn Storage,
+        operation: &Operation,
+    ) -> Result<(), Status> {
+        let applied = match operation {
+            Operation::Insert(id, _) => storage.get(id).is_ok(),
+            Operation::Delete(id) => storage.get(id).is_err(),
+            Operation::Update(id, _) => storage.get(id).is_err(),
+        };
+
+        if applied {
+            return Ok(());
+        }
+
+        Self::apply(index, storage, operation)
+    }
+
+    /// Check that an operation can be applied before it's logged.
+    ///
+    /// The replay skips the operations which look like they're already
+    /// applied, so an operation failing after it's logged would leave the
+    /// replayed database different from the one before the restart.
+    #[allow(clippy::result_large_err)]
+    fn validate(
+        &self,
+        storage: &dyn Storage,
+        operation: &Operation,
+    ) -> Result<(), Status> {
+        match operation {
+            Operation::Insert(id, record) => {
+                if storage.get(id).is_ok() {
+                    let message = format!("Record already exists: {id}");
+                    return Err(Status::already_exists(message));
+                }
+
+                self.validate_dimension(&record.vector)
+            }
+            Operation::Delete(id) | Operation::Update(id, _) => {
+                storage.get(id).map(|_| ())
+            }
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn validate_dimension(&self, vector: &Vector) -> Result<(), Status> {
+        if vector.len() != self.params.dimension {
+            return Err(Status::invalid_argument(format!(
+                "Invalid vector dimension: expected {}, got {}",
+                self.params.dimension,
+                vector.len()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl DatabaseService for Arc<Database> {
+    async fn heartbeat(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<protos::HeartbeatResponse>, Status> {
+        let response = protos::HeartbeatResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        };
+
+        Ok(Response::new(response))
+    }
+
+    async fn parameters(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<protos::ParametersResponse>, Status> {
+        let params = &self.params;
+        let response = protos::ParametersResponse {
+            dimension: params.dimension as i32,
+            metric: params.metric.as_str().to_string(),
+            density: params.density as i32,
+            storage: params.storage.as_str().to_string(),
+            index: params.index.as_str().to_string(),
+        };
+
+        Ok(Response::new(response))
+    }
+
+    async fn snapshot(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<protos::SnapshotResponse>, Status> {
+        let stats = self.create_snapshot().map_err(|e| {
+            let message = format!("Failed to create a snapshot: {e}");
+            Status::internal(message)
+        })?;
+
+        Ok(Response::new(stats.into()))
+    }
+
+    #[allow(clippy::result_large_err)]
+    async fn retrain(
+        &self,
+        request: Request<protos::RetrainRequest>,
+    ) -> Result<Response<protos::RetrainResponse>, Status> {
+        let sample = match request.into_inner().sample {
+            0 => None,
+            sample => Some(sample as usize),
+        };
+
+        // The retraining runs on a blocking thread because it's CPU-bound
+        // and can take a while for large databases.
+        let db = self.clone();
+        let stats =
+            tokio::task::spawn_blocking(move || Database::retrain(&db, sample))
+                .await
+                .map_err(|e| Status::internal(e.to_string()))??;
+
+        Ok(Response::new(stats.into()))
+    }
+
+    type BackupStream = ReceiverStream<Result<protos::BackupChunk, Status>>;
+
+    async fn backup(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<Self::BackupStream>, Status> {
+        let (sender, receiver) = mpsc::channel(BACKUP_BUFFER);
+        let db = self.clone();
+
+        // The archive is written on a blocking thread because the backup
+        // reads the snapshot data synchronously.
+        tokio::task::spawn_blocking(move || {
+            let mut writer = ChunkWriter::new(sender.clone());
+            let result = db.create_backup(&mut writer);
+            if let Err(e) = result.and_then(|_| Ok(writer.flush()?)) {
+                let message = format!("Failed to create a backup: {e}");
+                let _ = sender.blocking_send(Err(Status::internal(message)));
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    type ExportStream = ReceiverStream<Result<protos::ExportRecord, Status>>;
+
+    async fn export(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<Self::ExportStream>, Status> {
+        let (sender, receiver) = mpsc::channel(READ_BATCH_SIZE);
+        let db = self.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let result = Database::export(&db, |id, record| {
+                let id = id.to_string();
+                let record = Some(record.into());
+                let message = protos::ExportRecord { id, record };
+                sender
+                    .blocking_send(Ok(message))
+                    .map_err(|_| "The export stream is closed".into())
+            });
+
+            if let Err(e) = result {
+                let message = format!("Failed to export the records: {e}");
+                let _ = sender.blocking_send(Err(Status::internal(message)));
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn insert(
+        &self,
+        request: Request<protos::InsertRequest>,
+    ) -> Result<Response<protos::InsertResponse>, Status> {
+        let record = match request.into_inner().record {
+            Some(record) => Record::try_from(record)?,
+            None => {
+                let message = "Record data is required for insertion";
+                return Err(Status::invalid_argument(message));
+            }
+        };
+
+        self.validate_dimension(&record.vector)?;
+
+        let id = RecordID::new();
+        self.commit(Operation::Insert(id, record))?;
+
+        tracing::info!("Inserted a new record with ID: {id}");
+        Ok(Response::new(protos::InsertResponse { id: id.to_string() }))
+    }
+
+    async fn get(
+        &self,
+        request: Request<protos::GetRequest>,
+    ) -> Result<Response<protos::GetResponse>, Status> {
+        let request = request.into_inner();
+        let id = request.id.parse::<RecordID>()?;
+
+        let storage = self.storage.read().unwrap();
+        let record = storage.get(&id)?.into_owned();
+
+        let response = protos::GetResponse { record: Some(record.into()) };
+        Ok(Response::new(response))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<protos::DeleteRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        let id = request.id.parse::<RecordID>()?;
+
+        self.commit(Operation::Delete(id))?;
+
+        tracing::info!("Deleted a record with ID: {id}");
+        Ok(Response::new(()))
+    }
+
+    async fn update(
+        &self,
+        request: Request<protos::UpdateRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        let id = request.id.parse::<RecordID>()?;
+
+        let mut metadata = HashMap::new();
+        for (key, value) in request.metadata {
+            metadata.insert(key, value.try_into()?);
+        }
+
+        self.commit(Operation::Update(id, metadata))?;
+
+        tracing::info!("Updated metadata for a record: {id}");
+        Ok(Response::new(()))
+    }
+
+    async fn query(
         &self,
         request: Request<protos::QueryRequest>,
     ) -> Result<Response<protos::QueryResponse>, Status> {
@@ -332,22 +2042,89 @@ impl DatabaseService for Arc<Database> {
         };
 
         let storage = self.storage.read().unwrap();
-        let records = storage.records();
+        let results = match params.exact {
+            true => {
+                // Exact queries bypass the index to serve as ground truth
+                // for the recall of the approximate queries.
+                let ids = storage.ids();
+                let metric = self.params.metric;
+                let storage = storage.as_ref();
+                FlatIndex::scan(
+                    &ids, &vector, k, &filter, &params, metric, storage,
+                )
+            }
+            false => {
+                let index = self.index.read().unwrap();
+                index.query(&vector, k, &filter, &params, storage.as_ref())?
+            }
+        };
 
-        let index = self.index.read().unwrap();
-        let results = index
-            .query(&vector, k, &filter, &params, records)?
-            .into_iter()
-            .map(Into::into)
-            .collect();
+        let results = results.into_iter().map(Into::into).collect();
 
         Ok(Response::new(protos::QueryResponse { results }))
     }
 }
 
+/// Guard tracking an ongoing backup until it's dropped.
+struct BackupGuard<'a> {
+    backups: &'a AtomicUsize,
+}
+
+impl<'a> BackupGuard<'a> {
+    fn new(backups: &'a AtomicUsize) -> Self {
+        backups.fetch_add(1, Ordering::SeqCst);
+        BackupGuard { backups }
+    }
+}
+
+impl Drop for BackupGuard<'_> {
+    fn drop(&mut self) {
+        self.backups.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Writer sending the written bytes to a backup stream in chunks.
+struct ChunkWriter {
+    sender: mpsc::Sender<Result<protos::BackupChunk, Status>>,
+    buffer: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn new(sender: mpsc::Sender<Result<protos::BackupChunk, Status>>) -> Self {
+        ChunkWriter { sender, buffer: Vec::with_capacity(MAX_BLOCK_SIZE) }
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(bytes);
+        if self.buffer.len() >= MAX_BLOCK_SIZE {
+            self.flush()?;
+        }
+
+        Ok(bytes.len())
+    }
+
+    /// Stops the backup if the client disconnected from the stream.
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let data = std::mem::take(&mut self.buffer);
+        let chunk = protos::BackupChunk { data };
+        self.sender.blocking_send(Ok(chunk)).map_err(|_| {
+            let message = "The backup stream is closed";
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, message)
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    const TEST_DIR: &str = "oasysdb";
     use uuid::Uuid;
 
     #[test]
@@ -364,6 +2141,19 @@ mod tests {
         assert_eq!(response.get_ref().version, env!("CARGO_PKG_VERSION"));
     }
 
+    #[tokio::test]
+    async fn test_parameters() {
+        let params = Parameters::default();
+        let db = setup_db();
+
+        // The parameters are available even if the database is empty.
+        let response = db.parameters(Request::new(())).await.unwrap();
+        let response = response.get_ref();
+        assert_eq!(response.dimension as usize, params.dimension);
+        assert_eq!(response.metric, params.metric.as_str());
+        assert_eq!(response.index, params.index.as_str());
+    }
+
     #[tokio::test]
     async fn test_insert() {
         let params = Parameters::default();
@@ -379,17 +2169,873 @@ mod tests {
 
         let response = db.insert(request).await.unwrap();
         assert!(response.get_ref().id.parse::<Uuid>().is_ok());
-        assert_eq!(db.storage.read().unwrap().records().len(), 1);
+        assert_eq!(db.storage.read().unwrap().count(), 1);
     }
 
-    fn setup_db() -> Arc<Database> {
-        if Database::dir().exists() {
-            fs::remove_dir_all(Database::dir()).unwrap();
+    #[test]
+    fn test_commit_invalid() {
+        let params = Parameters::default();
+        let db = setup_db();
+
+        let id = RecordID::new();
+        let record = Record::random(params.dimension);
+        db.commit(Operation::Insert(id, record.clone())).unwrap();
+
+        // The invalid operations are rejected before they're logged.
+        let invalid = Record::random(params.dimension + 1);
+        let missing = RecordID::new();
+        assert!(db.commit(Operation::Insert(id, record)).is_err());
+        assert!(db.commit(Operation::Insert(missing, invalid)).is_err());
+        assert!(db.commit(Operation::Delete(missing)).is_err());
+        assert!(db.commit(Operation::Update(missing, HashMap::new())).is_err());
+
+        let operations = db.wal.lock().unwrap().read().unwrap();
+        assert_eq!(operations.len(), 1);
+
+        drop(db);
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+        assert_eq!(db.storage.read().unwrap().count(), 1);
+        assert!(db.verify().is_consistent());
+    }
+
+    #[test]
+    fn test_replay_wal() {
+        let params = Parameters::default();
+        let db = setup_db();
+
+        let ids: Vec<RecordID> = (0..3).map(|_| RecordID::new()).collect();
+        for id in ids.iter() {
+            let record = Record::random(params.dimension);
+            db.commit(Operation::Insert(*id, record)).unwrap();
+        }
+
+        let mut metadata = HashMap::new();
+        metadata.insert("key".to_string(), Value::Boolean(true));
+        db.commit(Operation::Update(ids[0], metadata.clone())).unwrap();
+        db.commit(Operation::Delete(ids[1])).unwrap();
+
+        // Reopen the database without creating a snapshot.
+        drop(db);
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+
+        let storage = db.storage.read().unwrap();
+        assert_eq!(storage.count(), 2);
+        assert_eq!(storage.get(&ids[0]).unwrap().metadata, metadata);
+        assert!(storage.get(&ids[1]).is_err());
+        drop(storage);
+
+        // Replaying the log on top of a snapshot that already contains
+        // the operations must not duplicate the records.
+        let generation = db.manifest.lock().unwrap().generation();
+        let snapshot_dir = Database::snapshot_dir(&db.dir, generation);
+
+        let index = db.index.read().unwrap().freeze();
+        let path = snapshot_dir.join(INDEX_FILE);
+        let options = FileOptions::default();
+        Database::persist_as_binary(&db.dir, path, &*index, &options).unwrap();
+
+        let storage = db.storage.read().unwrap().freeze();
+        let path = snapshot_dir.join(STORAGE_FILE);
+        Database::persist_as_binary(&db.dir, path, &*storage, &options)
+            .unwrap();
+        drop(storage);
+
+        drop(db);
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+        assert_eq!(db.storage.read().unwrap().count(), 2);
+
+        db.create_snapshot().unwrap();
+        let operations = db.wal.lock().unwrap().read().unwrap();
+        assert!(operations.is_empty());
+    }
+
+    #[test]
+    fn test_create_snapshot() {
+        let params = Parameters::default();
+        let db = setup_db();
+
+        let id = RecordID::new();
+        let record = Record::random(params.dimension);
+        db.commit(Operation::Insert(id, record)).unwrap();
+        assert_eq!(db.pending_writes(), 1);
+
+        db.create_snapshot().unwrap();
+        assert_eq!(db.pending_writes(), 0);
+
+        let manifest = db.manifest.lock().unwrap().clone();
+        assert_eq!(manifest.chain(), vec![1, 2]);
+
+        let delta_dir = Database::snapshot_dir(&db.dir, 2);
+        assert!(delta_dir.join(STORAGE_DELTA_FILE).exists());
+        assert!(!delta_dir.join(STORAGE_FILE).exists());
+
+        // Simulate a snapshot interrupted before the manifest is replaced.
+        let partial_dir = Database::snapshot_dir(&db.dir, 3);
+        fs::create_dir_all(&partial_dir).unwrap();
+        fs::write(partial_dir.join(STORAGE_DELTA_FILE), b"partial").unwrap();
+
+        drop(db);
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+        assert_eq!(*db.manifest.lock().unwrap(), manifest);
+        assert!(db.storage.read().unwrap().get(&id).is_ok());
+        assert!(!partial_dir.exists());
+    }
+
+    #[test]
+    fn test_compact_snapshots() {
+        let params = Parameters::default();
+        let db = setup_db();
+
+        let mut ids = vec![];
+        for _ in 0..COMPACTION_THRESHOLD {
+            let id = RecordID::new();
+            let record = Record::random(params.dimension);
+            db.commit(Operation::Insert(id, record)).unwrap();
+            db.create_snapshot().unwrap();
+            ids.push(id);
+        }
+
+        db.commit(Operation::Delete(ids[0])).unwrap();
+        db.create_snapshot().unwrap();
+
+        let generation = COMPACTION_THRESHOLD as u64 + 2;
+        let manifest = db.manifest.lock().unwrap().clone();
+        assert_eq!(manifest.chain(), vec![generation]);
+
+        // The retained generations still need the previous chain.
+        let snapshots = Database::snapshots(TEST_DIR).unwrap();
+        assert_eq!(snapshots.len(), generation as usize);
+
+        drop(db);
+        let options = OpenParameters { retention: 1, ..Default::default() };
+        let db = Database::open(TEST_DIR, &options).unwrap();
+        let snapshots = fs::read_dir(db.dir.join(SNAPSHOTS_DIR)).unwrap();
+        assert_eq!(snapshots.count(), 1);
+
+        let storage = db.storage.read().unwrap();
+        assert_eq!(storage.count(), COMPACTION_THRESHOLD - 1);
+        assert!(storage.get(&ids[0]).is_err());
+    }
+
+    #[test]
+    fn test_maintenance_mode() {
+        let params = Parameters::default();
+        let db = setup_db();
+
+        // The directory is locked while the database is open.
+        let options =
+            OpenParameters { maintenance: true, ..Default::default() };
+        assert!(Database::open(TEST_DIR, &options).is_err());
+
+        for _ in 0..COMPACTION_THRESHOLD + 1 {
+            let record = Record::random(params.dimension);
+            db.commit(Operation::Insert(RecordID::new(), record)).unwrap();
+            db.create_snapshot().unwrap();
+        }
+
+        let count = Database::snapshots(TEST_DIR).unwrap().len();
+        drop(db);
+
+        // The retained generations are kept regardless of the retention.
+        let options = OpenParameters {
+            retention: 1,
+            maintenance: true,
+            ..Default::default()
+        };
+
+        let db = Database::open(TEST_DIR, &options).unwrap();
+        assert_eq!(Database::snapshots(TEST_DIR).unwrap().len(), count);
+
+        db.create_snapshot().unwrap();
+        assert_eq!(Database::snapshots(TEST_DIR).unwrap().len(), count + 1);
+    }
+
+    #[test]
+    fn test_restore_generation() {
+        let params = Parameters::default();
+        let db = setup_db();
+
+        let ids: Vec<RecordID> = (0..3).map(|_| RecordID::new()).collect();
+        for id in ids.iter() {
+            let record = Record::random(params.dimension);
+            db.commit(Operation::Insert(*id, record)).unwrap();
+            db.create_snapshot().unwrap();
+        }
+
+        // The operations after the last snapshot are discarded as well.
+        db.commit(Operation::Delete(ids[0])).unwrap();
+
+        let snapshots = Database::snapshots(TEST_DIR).unwrap();
+        let generations: Vec<u64> =
+            snapshots.iter().map(|snapshot| snapshot.generation).collect();
+        assert_eq!(generations, vec![1, 2, 3, 4]);
+        assert!(snapshots[3].current && !snapshots[2].current);
+
+        drop(db);
+        let options = OpenParameters { restore: Some(3), ..Default::default() };
+        let db = Database::open(TEST_DIR, &options).unwrap();
+        assert_eq!(db.manifest.lock().unwrap().chain(), vec![1, 2, 3]);
+        assert!(!Database::snapshot_dir(&db.dir, 4).exists());
+
+        let storage = db.storage.read().unwrap();
+        assert_eq!(storage.count(), 2);
+        assert!(storage.get(&ids[0]).is_ok());
+        assert!(storage.get(&ids[2]).is_err());
+        drop(storage);
+
+        // The next snapshot continues from the restored generation.
+        db.create_snapshot().unwrap();
+        assert_eq!(db.manifest.lock().unwrap().generation(), 4);
+
+        drop(db);
+        let options = OpenParameters { restore: Some(9), ..Default::default() };
+        assert!(Database::open(TEST_DIR, &options).is_err());
+    }
+
+    #[test]
+    fn test_configure_existing() {
+        let params = Parameters::default();
+        let db = setup_db_with_params(&params);
+        let id = RecordID::new();
+        let record = Record::random(params.dimension);
+        db.commit(Operation::Insert(id, record)).unwrap();
+        drop(db);
+
+        // The existing database must be kept without the overwrite flag.
+        assert!(Database::is_configured(TEST_DIR).unwrap());
+        assert!(Database::configure(TEST_DIR, &params, false).is_err());
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+        assert_eq!(db.storage.read().unwrap().count(), 1);
+        drop(db);
+
+        Database::configure(TEST_DIR, &params, true).unwrap();
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+        assert_eq!(db.storage.read().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_upgrade_legacy_dir() {
+        let params = Parameters::default();
+        let dir = Path::new(TEST_DIR);
+        if dir.exists() {
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        let id = RecordID::new();
+        let record = Record::random(params.dimension);
+
+        let mut storage = MemoryStorage::new();
+        storage.insert(&id, &record).unwrap();
+
+        let mut index = IvfIndex::new();
+        index.insert(&id, &record, &storage).unwrap();
+
+        // Legacy data directories contain raw bincode files.
+        fs::create_dir_all(dir).unwrap();
+        let write = |file: &str, bytes: Vec<u8>| {
+            fs::write(dir.join(file), bytes).unwrap();
+        };
+
+        let legacy_params = LegacyParameters {
+            dimension: params.dimension,
+            metric: params.metric,
+            density: params.density,
+        };
+
+        write(PARAMS_FILE, bincode::serialize(&legacy_params).unwrap());
+        write(INDEX_FILE, bincode::serialize(&index).unwrap());
+        write(STORAGE_FILE, bincode::serialize(&storage).unwrap());
+
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+        assert_eq!(db.params, params);
+        assert!(db.storage.read().unwrap().get(&id).is_ok());
+        assert!(dir.join(MANIFEST_FILE).exists());
+        assert!(!dir.join(STORAGE_FILE).exists());
+    }
+
+    #[test]
+    fn test_mapped_storage() {
+        let params =
+            Parameters { storage: StorageMode::Mapped, ..Default::default() };
+        let db = setup_db_with_params(&params);
+
+        let records: Vec<(RecordID, Record)> = (0..3)
+            .map(|_| (RecordID::new(), Record::random(params.dimension)))
+            .collect();
+
+        for (id, record) in records.iter().take(2) {
+            db.commit(Operation::Insert(*id, record.clone())).unwrap();
+        }
+
+        db.create_snapshot().unwrap();
+
+        // The operations after the snapshot are replayed from the log.
+        let (id, record) = &records[2];
+        db.commit(Operation::Insert(*id, record.clone())).unwrap();
+        db.commit(Operation::Delete(records[0].0)).unwrap();
+
+        drop(db);
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+        assert!(db.dir.join(VECTORS_DIR).exists());
+
+        let storage = db.storage.read().unwrap();
+        assert_eq!(storage.count(), 2);
+        assert!(storage.get(&records[0].0).is_err());
+        for (id, record) in records.iter().skip(1) {
+            assert_eq!(storage.get(id).unwrap().into_owned(), *record);
+        }
+    }
+
+    #[test]
+    fn test_compressed_snapshot() {
+        let params = Parameters::default();
+        drop(setup_db());
+
+        let compression = Compression::Zstd;
+        let options = OpenParameters { compression, ..Default::default() };
+        let db = Database::open(TEST_DIR, &options).unwrap();
+
+        let id = RecordID::new();
+        let record = Record::random(params.dimension);
+        db.commit(Operation::Insert(id, record.clone())).unwrap();
+        db.create_snapshot().unwrap();
+
+        let generation = db.manifest.lock().unwrap().generation();
+        let path = Database::snapshot_dir(&db.dir, generation)
+            .join(STORAGE_DELTA_FILE);
+        let header = FileHeader::from_bytes(&fs::read(path).unwrap()).unwrap();
+        assert_eq!(header.compression, compression);
+
+        // The codec is read from the header regardless of the options.
+        drop(db);
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+        let storage = db.storage.read().unwrap();
+        assert_eq!(storage.get(&id).unwrap().into_owned(), record);
+    }
+
+    #[test]
+    fn test_encrypted_snapshot() {
+        let params = Parameters::default();
+        let db = setup_db();
+        let unencrypted_id = RecordID::new();
+        let record = Record::random(params.dimension);
+        db.commit(Operation::Insert(unencrypted_id, record)).unwrap();
+        db.create_snapshot().unwrap();
+
+        let unlogged_id = RecordID::new();
+        let record = Record::random(params.dimension);
+        db.commit(Operation::Insert(unlogged_id, record)).unwrap();
+        drop(db);
+
+        // The unencrypted files are only accepted to encrypt them.
+        let key = EncryptionKey::from_hex(&"ab".repeat(32)).unwrap();
+        let options =
+            OpenParameters { key: Some(key.clone()), ..Default::default() };
+        assert!(Database::open(TEST_DIR, &options).is_err());
+
+        let encrypt_options =
+            OpenParameters { encrypt_existing: true, ..options.clone() };
+        let db = Database::open(TEST_DIR, &encrypt_options).unwrap();
+        assert_eq!(db.storage.read().unwrap().count(), 2);
+        drop(db);
+
+        let db = Database::open(TEST_DIR, &options).unwrap();
+        let id = RecordID::new();
+        let record = Record::random(params.dimension);
+        db.commit(Operation::Insert(id, record.clone())).unwrap();
+        db.create_snapshot().unwrap();
+
+        // The operations after the snapshot are only in the log.
+        let other_id = RecordID::new();
+        db.commit(Operation::Insert(other_id, record.clone())).unwrap();
+        drop(db);
+
+        assert!(Database::open(TEST_DIR, &OpenParameters::default()).is_err());
+
+        let wrong_key = EncryptionKey::from_hex(&"cd".repeat(32)).unwrap();
+        let wrong_options =
+            OpenParameters { key: Some(wrong_key), ..Default::default() };
+
+        assert!(Database::open(TEST_DIR, &wrong_options).is_err());
+
+        let db = Database::open(TEST_DIR, &options).unwrap();
+        let storage = db.storage.read().unwrap();
+        assert_eq!(storage.get(&id).unwrap().into_owned(), record);
+        assert_eq!(storage.get(&other_id).unwrap().into_owned(), record);
+        assert!(storage.get(&unencrypted_id).is_ok());
+        assert!(storage.get(&unlogged_id).is_ok());
+        drop(storage);
+        drop(db);
+
+        // The older generations are encrypted as well.
+        let options = OpenParameters { restore: Some(2), ..options };
+        let db = Database::open(TEST_DIR, &options).unwrap();
+        assert_eq!(db.storage.read().unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_inspect() {
+        let params = Parameters::default();
+        let db = setup_db_with_params(&params);
+        for _ in 0..10 {
+            let record = Record::random(params.dimension);
+            db.commit(Operation::Insert(RecordID::new(), record)).unwrap();
+        }
+
+        // Records only in the write-ahead log are not inspected.
+        let info = Database::inspect(TEST_DIR, None).unwrap();
+        assert_eq!(info.count, 0);
+
+        db.create_snapshot().unwrap();
+        let info = Database::inspect(TEST_DIR, None).unwrap();
+        assert_eq!(info.params, params);
+        assert_eq!(info.count, 10);
+        assert_eq!(info.cluster_sizes.iter().sum::<usize>(), 10);
+        assert_eq!(info.metadata_keys.get("key"), Some(&10));
+
+        let manifest = PathBuf::from(MANIFEST_FILE);
+        assert!(info.files.iter().any(|(path, _)| *path == manifest));
+    }
+
+    #[test]
+    fn test_import() {
+        let params = Parameters::default();
+        let db = setup_db_with_params(&params);
+
+        let records = (0..100).map(|_| Ok(Record::random(params.dimension)));
+        assert_eq!(db.import(records).unwrap(), 100);
+        db.create_snapshot().unwrap();
+        drop(db);
+
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+        assert_eq!(db.storage.read().unwrap().count(), 100);
+        assert!(db.verify().is_consistent());
+
+        let records = [Ok(Record::random(params.dimension + 1))];
+        assert!(db.import(records).is_err());
+    }
+
+    #[test]
+    fn test_export() {
+        let params = Parameters::default();
+        let db = setup_db_with_params(&params);
+
+        let mut records: Vec<(RecordID, Record)> = (0..10)
+            .map(|_| (RecordID::new(), Record::random(params.dimension)))
+            .collect();
+
+        for (id, record) in records.iter() {
+            db.commit(Operation::Insert(*id, record.clone())).unwrap();
+        }
+
+        let mut exported = vec![];
+        let count = Database::export(&db, |id, record| {
+            exported.push((id, record));
+            Ok(())
+        })
+        .unwrap();
+
+        records.sort_by_key(|(id, _)| *id);
+        assert_eq!(count, records.len());
+        assert_eq!(exported, records);
+    }
+
+    #[test]
+    fn test_retrain() {
+        let params = Parameters::default();
+        let db = setup_db_with_params(&params);
+
+        for _ in 0..500 {
+            let record = Record::random(params.dimension);
+            db.commit(Operation::Insert(RecordID::new(), record)).unwrap();
+        }
+
+        let stats = Database::retrain(&db, Some(100)).unwrap();
+        assert_eq!(stats.records, 500);
+        assert!(stats.clusters <= 500_usize.div_ceil(params.density / 2));
+        assert!(db.verify().is_consistent());
+
+        // The retrained index is persisted by the next snapshot.
+        let sizes = db.index.read().unwrap().stats().cluster_sizes;
+        db.create_snapshot().unwrap();
+        drop(db);
+
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+        assert_eq!(db.index.read().unwrap().stats().cluster_sizes, sizes);
+    }
+
+    #[test]
+    fn test_hnsw_index() {
+        let index = IndexType::Hnsw { m: 8, ef_construction: 32 };
+        let params = Parameters { index, ..Default::default() };
+        let db = setup_db_with_params(&params);
+
+        let mut ids = vec![];
+        for _ in 0..50 {
+            let id = RecordID::new();
+            let record = Record::random(params.dimension);
+            db.commit(Operation::Insert(id, record)).unwrap();
+            ids.push(id);
+        }
+
+        db.create_snapshot().unwrap();
+        for id in ids.iter().take(10) {
+            db.commit(Operation::Delete(*id)).unwrap();
+        }
+
+        // The graph is restored from the full snapshot and the deltas.
+        db.create_snapshot().unwrap();
+        drop(db);
+
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+        assert_eq!(db.params.index, index);
+        assert!(db.verify().is_consistent());
+
+        let storage = db.storage.read().unwrap();
+        let vector = storage.get(&ids[20]).unwrap().vector.clone();
+        let results = db
+            .index
+            .read()
+            .unwrap()
+            .query(
+                &vector,
+                1,
+                &Filters::None,
+                &QueryParameters::default(),
+                storage.as_ref(),
+            )
+            .unwrap();
+
+        assert_eq!(results[0].id, ids[20]);
+    }
+
+    #[test]
+    fn test_flat_index() {
+        let params =
+            Parameters { index: IndexType::Flat, ..Default::default() };
+        let db = setup_db_with_params(&params);
+
+        let mut ids = vec![];
+        for _ in 0..50 {
+            let id = RecordID::new();
+            let record = Record::random(params.dimension);
+            db.commit(Operation::Insert(id, record)).unwrap();
+            ids.push(id);
+        }
+
+        db.create_snapshot().unwrap();
+        for id in ids.iter().take(10) {
+            db.commit(Operation::Delete(*id)).unwrap();
+        }
+
+        // The records are restored from the full snapshot and the deltas.
+        db.create_snapshot().unwrap();
+        drop(db);
+
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+        assert_eq!(db.params.index, IndexType::Flat);
+        assert!(db.verify().is_consistent());
+        assert_eq!(db.index.read().unwrap().stats().records, 40);
+    }
+
+    #[test]
+    fn test_ivfpq_index() {
+        let index = IndexType::IvfPq { subspaces: 3 };
+        let params = Parameters { index, ..Default::default() };
+        assert!(Database::configure(TEST_DIR, &params, true).is_err());
+
+        // The vectors can be kept in memory instead of the mapped storage.
+        let index = IndexType::IvfPq { subspaces: 16 };
+        let params = Parameters { index, ..Default::default() };
+        assert!(Database::configure(TEST_DIR, &params, true).is_ok());
+
+        let storage = StorageMode::Mapped;
+        let params = Parameters { index, storage, ..Default::default() };
+        let db = setup_db_with_params(&params);
+
+        let mut ids = vec![];
+        for _ in 0..100 {
+            let id = RecordID::new();
+            let record = Record::random(params.dimension);
+            db.commit(Operation::Insert(id, record)).unwrap();
+            ids.push(id);
+        }
+
+        // The codebooks are trained along with the centroids.
+        Database::retrain(&db, None).unwrap();
+        db.create_snapshot().unwrap();
+        for id in ids.iter().take(10) {
+            db.commit(Operation::Delete(*id)).unwrap();
+        }
+
+        db.create_snapshot().unwrap();
+        drop(db);
+
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+        assert_eq!(db.params.index, index);
+        assert!(db.verify().is_consistent());
+
+        let storage = db.storage.read().unwrap();
+        let vector = storage.get(&ids[20]).unwrap().vector.clone();
+        let results = db
+            .index
+            .read()
+            .unwrap()
+            .query(
+                &vector,
+                1,
+                &Filters::None,
+                &QueryParameters::default(),
+                storage.as_ref(),
+            )
+            .unwrap();
+
+        assert_eq!(results[0].id, ids[20]);
+    }
+
+    #[test]
+    fn test_ivfpq_encrypted() {
+        let key = EncryptionKey::from_hex(&"ab".repeat(32)).unwrap();
+        let options = OpenParameters {
+            key: Some(key),
+            encrypt_existing: true,
+            ..Default::default()
+        };
+
+        // The vector segments of the mapped storage can't be encrypted.
+        let index = IndexType::IvfPq { subspaces: 16 };
+        let storage = StorageMode::Mapped;
+        let params = Parameters { index, storage, ..Default::default() };
+        assert!(params.check_encryption().is_err());
+
+        Database::configure(TEST_DIR, &params, true).unwrap();
+        assert!(Database::open(TEST_DIR, &options).is_err());
+
+        // The IVF-PQ index keeps the vectors in memory when encrypted.
+        let params = Parameters { index, ..Default::default() };
+        assert!(params.check_encryption().is_ok());
+
+        Database::configure(TEST_DIR, &params, true).unwrap();
+        let db = Database::open(TEST_DIR, &options).unwrap();
+
+        let mut ids = vec![];
+        for _ in 0..100 {
+            let id = RecordID::new();
+            let record = Record::random(params.dimension);
+            db.commit(Operation::Insert(id, record)).unwrap();
+            ids.push(id);
+        }
+
+        Database::retrain(&db, None).unwrap();
+        db.create_snapshot().unwrap();
+        drop(db);
+
+        assert!(Database::open(TEST_DIR, &OpenParameters::default()).is_err());
+        let db = Database::open(TEST_DIR, &options).unwrap();
+        assert_eq!(db.params, params);
+        assert_eq!(db.storage.read().unwrap().count(), ids.len());
+        assert!(db.verify().is_consistent());
+    }
+
+    #[test]
+    fn test_ivfpq_snapshot() {
+        let index = IndexType::IvfPq { subspaces: 16 };
+        let storage = StorageMode::Mapped;
+        let params = Parameters { index, storage, ..Default::default() };
+        let db = setup_db_with_params(&params);
+
+        let records = (0..300).map(|_| Ok(Record::random(params.dimension)));
+        db.import(records).unwrap();
+        let stats = db.index.read().unwrap().stats();
+
+        // The snapshot persists the clusters without retraining the index.
+        db.create_snapshot().unwrap();
+        drop(db);
+
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+        assert_eq!(db.index.read().unwrap().stats(), stats);
+        assert!(db.verify().is_consistent());
+    }
+
+    #[test]
+    fn test_query_parameters_ef() {
+        let value = protos::QueryParameters::default();
+        let params = QueryParameters::try_from(value).unwrap();
+        assert_eq!(params.ef, QueryParameters::default().ef);
+
+        let value = protos::QueryParameters { ef: 16, ..Default::default() };
+        let params = QueryParameters::try_from(value).unwrap();
+        assert_eq!(params.ef, 16);
+
+        let value = protos::QueryParameters { ef: -1, ..Default::default() };
+        assert!(QueryParameters::try_from(value).is_err());
+    }
+
+    #[test]
+    fn test_query_parameters_rerank() {
+        let value = protos::QueryParameters::default();
+        let params = QueryParameters::try_from(value).unwrap();
+        assert_eq!(params.rerank, QueryParameters::default().rerank);
+
+        let value =
+            protos::QueryParameters { rerank: 10, ..Default::default() };
+        let params = QueryParameters::try_from(value).unwrap();
+        assert_eq!(params.rerank, 10);
+
+        let value =
+            protos::QueryParameters { rerank: -1, ..Default::default() };
+        assert!(QueryParameters::try_from(value).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exact_query() {
+        let params = Parameters::default();
+        let db = setup_db();
+
+        let mut records = vec![];
+        for _ in 0..500 {
+            let record = Record::random(params.dimension);
+            db.commit(Operation::Insert(RecordID::new(), record.clone()))
+                .unwrap();
+            records.push(record);
         }
 
+        let vector = Vector::random(params.dimension);
+        let query_params = QueryParameters { probes: 1, ..Default::default() };
+        let request = Request::new(protos::QueryRequest {
+            vector: Some(vector.clone().into()),
+            k: 10,
+            filter: String::new(),
+            params: Some(protos::QueryParameters {
+                probes: query_params.probes as i32,
+                radius: query_params.radius,
+                ef: query_params.ef as i32,
+                exact: true,
+                rerank: query_params.rerank as i32,
+            }),
+        });
+
+        let response = db.query(request).await.unwrap();
+        let results = &response.get_ref().results;
+
+        // The exact query visits all clusters regardless of the probes.
+        let mut distances: Vec<f32> = records
+            .iter()
+            .map(|record| {
+                let distance = params.metric.distance(&record.vector, &vector);
+                distance.unwrap() as f32
+            })
+            .collect();
+
+        distances.sort_by(f32::total_cmp);
+        let actual: Vec<f32> = results.iter().map(|r| r.distance).collect();
+        assert_eq!(actual, distances[..10]);
+    }
+
+    #[test]
+    fn test_verify_and_repair() {
         let params = Parameters::default();
-        Database::configure(&params);
-        Arc::new(Database::open().unwrap())
+        let db = setup_db_with_params(&params);
+
+        let ids: Vec<RecordID> = (0..10).map(|_| RecordID::new()).collect();
+        for id in ids.iter() {
+            let record = Record::random(params.dimension);
+            db.commit(Operation::Insert(*id, record)).unwrap();
+        }
+
+        assert!(db.verify().is_consistent());
+
+        // Remove a record from each side and count a record twice.
+        let mut storage = db.storage.write().unwrap();
+        let mut index = db.index.write().unwrap();
+        index.delete(&ids[0], storage.as_ref()).unwrap();
+        drop(index);
+        storage.delete(&ids[1]).unwrap();
+        let record = storage.get(&ids[2]).unwrap().into_owned();
+        storage.insert(&ids[2], &record).unwrap();
+        drop(storage);
+
+        let report = db.verify();
+        assert_eq!(report.orphaned, vec![ids[0]]);
+        assert_eq!(report.dangling, vec![ids[1]]);
+        assert_eq!((report.count, report.records), (10, 9));
+        assert!(!report.is_consistent());
+
+        assert_eq!(db.repair().unwrap(), report);
+        assert!(db.verify().is_consistent());
+
+        // The rebuilt index must locate the records to delete them.
+        db.commit(Operation::Delete(ids[3])).unwrap();
+        assert!(db.verify().is_consistent());
+
+        // The repaired state must survive a reopen.
+        db.create_snapshot().unwrap();
+        drop(db);
+
+        let db = Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+        assert!(db.verify().is_consistent());
+        assert_eq!(db.storage.read().unwrap().count(), 8);
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        for storage in [StorageMode::Memory, StorageMode::Mapped] {
+            let params = Parameters { storage, ..Default::default() };
+            let db = setup_db_with_params(&params);
+
+            let records: Vec<(RecordID, Record)> = (0..3)
+                .map(|_| (RecordID::new(), Record::random(params.dimension)))
+                .collect();
+
+            for (id, record) in records.iter() {
+                db.commit(Operation::Insert(*id, record.clone())).unwrap();
+            }
+
+            let mut archive = vec![];
+            db.create_backup(&mut archive).unwrap();
+
+            // The directory must be removed before restoring the backup.
+            assert!(Database::restore_backup(
+                TEST_DIR,
+                archive.as_slice(),
+                None
+            )
+            .is_err());
+            drop(db);
+            fs::remove_dir_all(TEST_DIR).unwrap();
+
+            // An incomplete archive must not leave a usable directory.
+            let incomplete = &archive[..archive.len() - 1];
+            assert!(
+                Database::restore_backup(TEST_DIR, incomplete, None).is_err()
+            );
+            assert!(!Path::new(TEST_DIR).exists());
+
+            Database::restore_backup(TEST_DIR, archive.as_slice(), None)
+                .unwrap();
+            let db =
+                Database::open(TEST_DIR, &OpenParameters::default()).unwrap();
+            assert_eq!(db.params, params);
+
+            let storage = db.storage.read().unwrap();
+            assert_eq!(storage.count(), records.len());
+            for (id, record) in records.iter() {
+                assert_eq!(storage.get(id).unwrap().into_owned(), *record);
+            }
+        }
+    }
+
+    fn setup_db() -> Arc<Database> {
+        setup_db_with_params(&Parameters::default())
+    }
+
+    fn setup_db_with_params(params: &Parameters) -> Arc<Database> {
+        if Path::new(TEST_DIR).exists() {
+            fs::remove_dir_all(TEST_DIR).unwrap();
+        }
+
+        Database::configure(TEST_DIR, params, true).unwrap();
+        Arc::new(Database::open(TEST_DIR, &OpenParameters::default()).unwrap())
     }
 
     impl Default for Parameters {
@@ -398,6 +3044,8 @@ mod tests {
                 dimension: 128,
                 metric: Metric::Euclidean,
                 density: 64,
+                storage: StorageMode::Memory,
+                index: IndexType::Ivf,
             }
         }
     }
@@ -0,0 +1,145 @@
+use super::*;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::fmt;
+
+/// Environment variable containing the hex-encoded encryption key.
+const KEY_ENV: &str = "ODB_ENCRYPTION_KEY";
+
+/// Size of the encryption key in bytes.
+const KEY_SIZE: usize = 32;
+
+/// Size of the random nonce prepended to the encrypted data.
+const NONCE_SIZE: usize = 24;
+
+/// Key to encrypt the data files at rest.
+///
+/// The data is encrypted with XChaCha20-Poly1305 which authenticates the
+/// ciphertext, so data encrypted with a different key or tampered with is
+/// detected when it's decrypted. Each encryption uses a random nonce which
+/// is prepended to the ciphertext.
+///
+/// The associated data is authenticated along with the ciphertext without
+/// being encrypted, so the unencrypted header of a file can't be modified
+/// without failing the decryption.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey {
+    bytes: [u8; KEY_SIZE],
+}
+
+impl EncryptionKey {
+    /// Load the key from a key file or the environment variable.
+    ///
+    /// The key file takes precedence over the environment variable. Both
+    /// contain the key as 64 hexadecimal characters. Returns None if no
+    /// key is configured which disables the encryption.
+    pub fn load(file: Option<&Path>) -> Result<Option<Self>, Box<dyn Error>> {
+        if let Some(file) = file {
+            let content = fs::read_to_string(file).map_err(|e| {
+                format!("Failed to read the key file {}: {e}", file.display())
+            })?;
+
+            return Ok(Some(Self::from_hex(content.trim())?));
+        }
+
+        match env::var(KEY_ENV) {
+            Ok(key) => Ok(Some(Self::from_hex(key.trim())?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Decode the key from 64 hexadecimal characters.
+    pub fn from_hex(value: &str) -> Result<Self, Box<dyn Error>> {
+        let invalid = || "Encryption key must be 64 hexadecimal characters";
+        if value.len() != KEY_SIZE * 2 || !value.is_ascii() {
+            return Err(invalid().into());
+        }
+
+        let mut bytes = [0; KEY_SIZE];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let pair = &value[i * 2..i * 2 + 2];
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+        }
+
+        Ok(EncryptionKey { bytes })
+    }
+
+    /// Encrypt the data and prepend the nonce to the ciphertext.
+    pub fn encrypt(
+        &self,
+        data: &[u8],
+        associated: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let payload = Payload { msg: data, aad: associated };
+        let ciphertext = self
+            .cipher()
+            .encrypt(XNonce::from_slice(&nonce), payload)
+            .map_err(|_| "Failed to encrypt the data")?;
+
+        let mut bytes = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&ciphertext);
+        Ok(bytes)
+    }
+
+    /// Decrypt the data encrypted with this key.
+    ///
+    /// This fails if the data was encrypted with a different key or was
+    /// modified after it was encrypted, including its associated data.
+    pub fn decrypt(
+        &self,
+        data: &[u8],
+        associated: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        if data.len() < NONCE_SIZE {
+            return Err("Encrypted data is truncated".into());
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+        let payload = Payload { msg: ciphertext, aad: associated };
+        let message = "Failed to decrypt the data: the encryption key is wrong";
+        let data = self
+            .cipher()
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| message)?;
+
+        Ok(data)
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.bytes.into())
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    /// The key material is never printed.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        let key = EncryptionKey::from_hex(&"ab".repeat(KEY_SIZE)).unwrap();
+        let data = b"sensitive embeddings";
+
+        let encrypted = key.encrypt(data, b"header").unwrap();
+        assert_ne!(&encrypted[NONCE_SIZE..], data);
+        assert_eq!(key.decrypt(&encrypted, b"header").unwrap(), data);
+        assert!(key.decrypt(&encrypted, b"other").is_err());
+
+        let other = EncryptionKey::from_hex(&"cd".repeat(KEY_SIZE)).unwrap();
+        assert!(other.decrypt(&encrypted, b"header").is_err());
+    }
+
+    #[test]
+    fn test_from_hex_invalid() {
+        assert!(EncryptionKey::from_hex("abcd").is_err());
+        assert!(EncryptionKey::from_hex(&"zz".repeat(KEY_SIZE)).is_err());
+    }
+}
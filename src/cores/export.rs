@@ -0,0 +1,259 @@
+use super::*;
+use serde_json::{json, Map, Value as JsonValue};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+// Export format name constants.
+const JSONL: &str = "jsonl";
+const NPY: &str = "npy";
+
+/// Size of the NumPy header reserved before the number of rows is known.
+const NPY_HEADER_SIZE: usize = 128;
+
+/// Format of the exported records.
+///
+/// ### Jsonl
+/// JSON object per line with the record ID, vector, and metadata. The
+/// file can be imported again with the JSONL import format.
+///
+/// ### Npy
+/// NumPy array file containing the vectors as a 2-dimensional f32 array
+/// with a sidecar JSONL file containing the ID and metadata of each row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Jsonl,
+    Npy,
+}
+
+impl ExportFormat {
+    /// Return the format name as a string slice.
+    pub fn as_str(&self) -> &str {
+        match self {
+            ExportFormat::Jsonl => JSONL,
+            ExportFormat::Npy => NPY,
+        }
+    }
+}
+
+impl From<&str> for ExportFormat {
+    fn from(value: &str) -> Self {
+        let value = value.to_lowercase();
+        match value.as_str() {
+            JSONL => ExportFormat::Jsonl,
+            NPY => ExportFormat::Npy,
+            _ => panic!("Export format should be jsonl or npy"),
+        }
+    }
+}
+
+impl From<String> for ExportFormat {
+    fn from(value: String) -> Self {
+        ExportFormat::from(value.as_str())
+    }
+}
+
+/// Writer of the exported records.
+///
+/// The records are written as they're received, so the export doesn't
+/// need to hold all records in memory. The NumPy header is rewritten with
+/// the number of rows when the export finishes.
+#[derive(Debug)]
+pub struct ExportWriter {
+    format: ExportFormat,
+    writer: BufWriter<File>,
+    sidecar: Option<BufWriter<File>>,
+    dimension: usize,
+    count: usize,
+}
+
+impl ExportWriter {
+    /// Create the export files for the format.
+    ///
+    /// The dimension is required to write the vectors as a NumPy array.
+    pub fn create(
+        path: impl AsRef<Path>,
+        format: ExportFormat,
+        dimension: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        let sidecar = match format {
+            ExportFormat::Jsonl => None,
+            ExportFormat::Npy => {
+                writer.write_all(&npy_header(0, dimension)?)?;
+                let file = File::create(Self::sidecar_path(path))?;
+                Some(BufWriter::new(file))
+            }
+        };
+
+        Ok(ExportWriter { format, writer, sidecar, dimension, count: 0 })
+    }
+
+    /// Return the path of the sidecar file with the IDs and metadata of
+    /// the rows in the NumPy array.
+    pub fn sidecar_path(path: impl AsRef<Path>) -> PathBuf {
+        path.as_ref().with_extension("metadata.jsonl")
+    }
+
+    /// Write a record to the export files.
+    pub fn write(
+        &mut self,
+        id: &RecordID,
+        record: &Record,
+    ) -> Result<(), Box<dyn Error>> {
+        let metadata = json_metadata(&record.metadata);
+        match self.sidecar.as_mut() {
+            None => {
+                let vector = record.vector.as_slice();
+                let line = json!({
+                    "id": id.to_string(),
+                    "vector": vector,
+                    "metadata": metadata,
+                });
+
+                writeln!(self.writer, "{line}")?;
+            }
+            Some(sidecar) => {
+                if record.vector.len() != self.dimension {
+                    let message = format!(
+                        "Invalid vector dimension of {id}: expected {}, got {}",
+                        self.dimension,
+                        record.vector.len()
+                    );
+
+                    return Err(message.into());
+                }
+
+                for value in record.vector.as_slice() {
+                    self.writer.write_all(&value.to_le_bytes())?;
+                }
+
+                let line =
+                    json!({ "id": id.to_string(), "metadata": metadata });
+                writeln!(sidecar, "{line}")?;
+            }
+        }
+
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Flush the export files and return the number of records.
+    pub fn finish(self) -> Result<usize, Box<dyn Error>> {
+        let mut file = self.writer.into_inner()?;
+        if let Some(sidecar) = self.sidecar {
+            sidecar.into_inner()?.sync_all()?;
+        }
+
+        if self.format == ExportFormat::Npy {
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&npy_header(self.count, self.dimension)?)?;
+        }
+
+        file.sync_all()?;
+        Ok(self.count)
+    }
+}
+
+/// Encode the NumPy header of a 2-dimensional f32 array.
+///
+/// The header is padded to a fixed size so that it can be rewritten in
+/// place once the number of rows is known.
+fn npy_header(
+    rows: usize,
+    dimension: usize,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let dict = format!(
+        "{{'descr': '<f4', 'fortran_order': False, \
+        'shape': ({rows}, {dimension}), }}"
+    );
+
+    let length = NPY_HEADER_SIZE - NPY_MAGIC.len() - 4;
+    if dict.len() >= length {
+        return Err("NumPy array shape is too large".into());
+    }
+
+    let mut header = NPY_MAGIC.to_vec();
+    header.extend_from_slice(&[1, 0]);
+    header.extend_from_slice(&(length as u16).to_le_bytes());
+    header.extend_from_slice(format!("{dict:<0$}", length - 1).as_bytes());
+    header.push(b'\n');
+    Ok(header)
+}
+
+fn json_metadata(metadata: &HashMap<String, Value>) -> Map<String, JsonValue> {
+    let mut map = Map::new();
+    for (key, value) in metadata.iter() {
+        let value = match value {
+            Value::Text(text) => JsonValue::from(text.as_str()),
+            Value::Number(number) => JsonValue::from(*number),
+            Value::Boolean(boolean) => JsonValue::from(*boolean),
+        };
+
+        map.insert(key.to_owned(), value);
+    }
+
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_jsonl() {
+        let path = export_path("records.jsonl");
+        let records = export_records(&path, ExportFormat::Jsonl);
+
+        // The exported file can be imported again.
+        let reader = ImportReader::open(&path, ImportFormat::Jsonl).unwrap();
+        let imported = reader.collect::<Result<Vec<Record>, _>>().unwrap();
+        let expected: Vec<Record> = records.into_iter().map(|r| r.1).collect();
+        assert_eq!(imported, expected);
+    }
+
+    #[test]
+    fn test_export_npy() {
+        let path = export_path("vectors.npy");
+        let records = export_records(&path, ExportFormat::Npy);
+
+        let reader = ImportReader::open(&path, ImportFormat::Npy).unwrap();
+        let vectors = reader
+            .map(|record| record.unwrap().vector)
+            .collect::<Vec<Vector>>();
+
+        assert_eq!(vectors.len(), records.len());
+        for (vector, (_, record)) in vectors.iter().zip(records.iter()) {
+            assert_eq!(*vector, record.vector);
+        }
+
+        let sidecar = fs::read_to_string(ExportWriter::sidecar_path(&path));
+        let lines = sidecar.unwrap();
+        let first: JsonValue =
+            serde_json::from_str(lines.lines().next().unwrap()).unwrap();
+        assert_eq!(first["id"], records[0].0.to_string());
+    }
+
+    fn export_records(
+        path: &Path,
+        format: ExportFormat,
+    ) -> Vec<(RecordID, Record)> {
+        let records: Vec<(RecordID, Record)> =
+            (0..3).map(|_| (RecordID::new(), Record::random(8))).collect();
+
+        let mut writer = ExportWriter::create(path, format, 8).unwrap();
+        for (id, record) in records.iter() {
+            writer.write(id, record).unwrap();
+        }
+
+        assert_eq!(writer.finish().unwrap(), records.len());
+        records
+    }
+
+    fn export_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join("oasysdb_export_tests");
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+}
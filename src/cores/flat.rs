@@ -0,0 +1,316 @@
+use super::*;
+use std::collections::BinaryHeap;
+
+/// Number of records compared by a thread at a time during a scan.
+const SCAN_BATCH_SIZE: usize = 1024;
+
+/// Flat index changes since the previous snapshot.
+///
+/// Fields:
+/// - inserts: IDs of the inserted records.
+/// - deletes: IDs of the deleted records.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FlatDelta {
+    pub inserts: Vec<RecordID>,
+    pub deletes: Vec<RecordID>,
+}
+
+/// Flat index searched exhaustively.
+///
+/// The query vector is compared with the vectors of all records, so the
+/// results are exact but the query time grows linearly with the number of
+/// records. This makes the index suitable for small collections.
+///
+/// The vectors are read from the storage, so the index only contains the
+/// IDs of the records. Like the other indexes, the index keeps track of
+/// the records changed since the previous snapshot.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlatIndex {
+    records: HashSet<RecordID>,
+    metric: Metric,
+
+    #[serde(skip)]
+    changes: Mutex<HashSet<RecordID>>,
+}
+
+impl FlatIndex {
+    /// Create a new flat index with default parameters.
+    ///
+    /// Default parameters:
+    /// - metric: Euclidean
+    pub fn new() -> Self {
+        FlatIndex {
+            records: HashSet::new(),
+            metric: Metric::Euclidean,
+            changes: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Configure the metric used for distance calculations.
+    pub fn with_metric(mut self, metric: Metric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    /// Apply the changes from a snapshot delta.
+    pub fn apply_delta(&mut self, delta: FlatDelta) {
+        for id in delta.deletes.iter() {
+            self.records.remove(id);
+        }
+
+        self.records.extend(delta.inserts);
+    }
+
+    /// Compare the vector with the records in parallel to find the exact
+    /// nearest neighbors.
+    ///
+    /// Besides the flat index, this is used by the exact queries on the
+    /// other indexes to scan all records in the storage.
+    /// - ids: IDs of the records to compare with.
+    pub fn scan(
+        ids: &[RecordID],
+        vector: &Vector,
+        k: usize,
+        filters: &Filters,
+        params: &QueryParameters,
+        metric: Metric,
+        storage: &dyn Storage,
+    ) -> Vec<QueryResult> {
+        let nearest = |mut results: BinaryHeap<QueryResult>, result| {
+            results.push(result);
+            if results.len() > k {
+                results.pop();
+            }
+
+            results
+        };
+
+        ids.par_chunks(SCAN_BATCH_SIZE)
+            .map(|batch| {
+                let records = storage.get_many(batch);
+                let mut results = BinaryHeap::new();
+                for (id, record) in batch.iter().zip(records) {
+                    let record = match record {
+                        Some(record) => record,
+                        None => continue,
+                    };
+
+                    let distance = metric.distance(&record.vector, vector);
+                    let distance = match distance {
+                        Some(distance) => distance as f32,
+                        None => continue,
+                    };
+
+                    if distance > params.radius
+                        || !filters.apply(&record.metadata)
+                    {
+                        continue;
+                    }
+
+                    let result = QueryResult {
+                        id: *id,
+                        metadata: record.metadata.clone(),
+                        distance,
+                    };
+
+                    results = nearest(results, result);
+                }
+
+                results
+            })
+            .reduce(BinaryHeap::new, |results, other| {
+                other.into_iter().fold(results, nearest)
+            })
+            .into_sorted_vec()
+    }
+}
+
+impl Index for FlatIndex {
+    fn insert(
+        &mut self,
+        id: &RecordID,
+        _record: &Record,
+        _storage: &dyn Storage,
+    ) -> Result<(), Status> {
+        self.records.insert(*id);
+        self.changes.get_mut().unwrap().insert(*id);
+        Ok(())
+    }
+
+    fn delete(
+        &mut self,
+        id: &RecordID,
+        _storage: &dyn Storage,
+    ) -> Result<(), Status> {
+        if self.records.remove(id) {
+            self.changes.get_mut().unwrap().insert(*id);
+        }
+
+        Ok(())
+    }
+
+    /// The query parameters other than the radius are ignored because all
+    /// records are compared.
+    fn query(
+        &self,
+        vector: &Vector,
+        k: usize,
+        filters: &Filters,
+        params: &QueryParameters,
+        storage: &dyn Storage,
+    ) -> Result<Vec<QueryResult>, Status> {
+        let ids = self.ids();
+        let metric = self.metric;
+        Ok(Self::scan(&ids, vector, k, filters, params, metric, storage))
+    }
+
+    /// All records, including the ones removed by the rebuild, are tracked
+    /// as changed for the next snapshot.
+    fn rebuild(&mut self, storage: &dyn Storage) -> Result<(), Status> {
+        let records: HashSet<RecordID> = storage.ids().into_iter().collect();
+
+        let changes = self.changes.get_mut().unwrap();
+        changes.extend(self.records.iter());
+        changes.extend(records.iter());
+
+        self.records = records;
+        Ok(())
+    }
+
+    fn stats(&self) -> IndexStats {
+        IndexStats { records: self.records.len(), cluster_sizes: vec![] }
+    }
+
+    fn ids(&self) -> Vec<RecordID> {
+        self.records.iter().copied().collect()
+    }
+
+    /// The tracked changes aren't included in the copy.
+    fn freeze(&self) -> Box<dyn Encode> {
+        Box::new(FlatIndex {
+            records: self.records.clone(),
+            metric: self.metric,
+            changes: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn take_changes(&self) -> IndexChanges {
+        let changes = std::mem::take(&mut *self.changes.lock().unwrap());
+        IndexChanges::Records(changes)
+    }
+
+    fn restore_changes(&self, changes: IndexChanges) {
+        match changes {
+            IndexChanges::Records(changes) => {
+                self.changes.lock().unwrap().extend(changes)
+            }
+            _ => unreachable!("Flat index should track record changes"),
+        }
+    }
+
+    fn delta(&self, changes: &IndexChanges) -> Box<dyn Encode> {
+        let changes = match changes {
+            IndexChanges::Records(changes) => changes,
+            _ => unreachable!("Flat index should track record changes"),
+        };
+
+        let mut delta = FlatDelta::default();
+        for id in changes.iter() {
+            match self.records.contains(id) {
+                true => delta.inserts.push(*id),
+                false => delta.deletes.push(*id),
+            }
+        }
+
+        Box::new(delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query() {
+        let params = Parameters::default();
+        let (index, storage) = setup_index(&params, 2000);
+
+        let query = Record::random(params.dimension).vector;
+        let query_params = QueryParameters::default();
+        let results = index
+            .query(&query, 10, &Filters::None, &query_params, &storage)
+            .unwrap();
+
+        // The results must match a sequential comparison with all records.
+        let mut distances: Vec<f32> = storage
+            .iter()
+            .map(|(_, record)| {
+                let distance = params.metric.distance(&record.vector, &query);
+                distance.unwrap() as f32
+            })
+            .collect();
+
+        distances.sort_by(f32::total_cmp);
+        let expected = &distances[..10];
+        let actual: Vec<f32> = results.iter().map(|r| r.distance).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_query_with_filters() {
+        let params = Parameters::default();
+        let (index, storage) = setup_index(&params, 100);
+
+        let query = Record::random(params.dimension).vector;
+        let filters = Filters::try_from("number > 1090").unwrap();
+        let query_params = QueryParameters::default();
+        let results =
+            index.query(&query, 20, &filters, &query_params, &storage).unwrap();
+
+        assert_eq!(results.len(), 9);
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        let params = Parameters::default();
+        let (mut index, storage, ..) = setup_index(&params, 100);
+
+        // Create a snapshot copy of the index.
+        let mut copy = FlatIndex::new();
+        copy.apply_delta(collect_delta(&index));
+        assert_eq!(copy.records, index.records);
+
+        for id in index.ids().iter().step_by(3) {
+            index.delete(id, &storage).unwrap();
+        }
+
+        copy.apply_delta(collect_delta(&index));
+        assert_eq!(copy.records, index.records);
+    }
+
+    fn collect_delta(index: &FlatIndex) -> FlatDelta {
+        let delta = index.delta(&index.take_changes());
+        let bytes = delta.encode(&FileOptions::default()).unwrap();
+        decode_file(&bytes, None).unwrap()
+    }
+
+    fn setup_index(
+        params: &Parameters,
+        count: usize,
+    ) -> (FlatIndex, MemoryStorage) {
+        let mut index = FlatIndex::new().with_metric(params.metric);
+        let mut storage = MemoryStorage::new();
+
+        for i in 0..count {
+            let id = RecordID::new();
+            let mut record = Record::random(params.dimension);
+            let value = Value::Number((1000 + i) as f64);
+            record.metadata.insert("number".to_string(), value);
+
+            storage.insert(&id, &record).unwrap();
+            index.insert(&id, &record, &storage).unwrap();
+        }
+
+        (index, storage)
+    }
+}
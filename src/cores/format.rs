@@ -0,0 +1,535 @@
+use super::*;
+use std::borrow::Cow;
+
+/// Magic bytes at the start of every data file.
+const MAGIC: [u8; 4] = *b"ODB\0";
+
+// Compression codec name constants.
+const NONE: &str = "none";
+const LZ4: &str = "lz4";
+const ZSTD: &str = "zstd";
+
+/// Compression level used by the Zstandard codec.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Current version of the on-disk format.
+///
+/// This version must be bumped whenever the layout of the persisted data
+/// changes so that older data files can be detected and upgraded.
+///
+/// Versions:
+/// - 0: Raw bincode files without the header.
+/// - 1: Snapshot manifest pointing to a single generation.
+/// - 2: Snapshot manifest with a chain of incremental generations.
+/// - 3: Storage mode in the database parameters.
+/// - 4: Compression codec in the file header.
+/// - 5: Encryption flag in the file header.
+/// - 6: Record locations in the index.
+/// - 7: Index type in the database parameters.
+pub const FORMAT_VERSION: u32 = 7;
+
+/// First format version with the encryption flag in the header.
+const ENCRYPTED_VERSION: u32 = 5;
+
+/// Size of the encoded file header in bytes.
+const HEADER_SIZE: usize = 24;
+
+/// Size of the file header prior to format version 4.
+const LEGACY_HEADER_SIZE: usize = 20;
+
+/// Compression codec of the data files.
+///
+/// The codec is recorded in the file header so that the files are
+/// decompressed transparently regardless of the codec configured when
+/// they're loaded.
+///
+/// ### Lz4
+/// Fast compression with a moderate ratio.
+///
+/// ### Zstd
+/// Zstandard compression with a higher ratio at a higher CPU cost.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    /// Return the codec name as a string slice.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Compression::None => NONE,
+            Compression::Lz4 => LZ4,
+            Compression::Zstd => ZSTD,
+        }
+    }
+
+    /// Compress the data with the codec.
+    pub fn compress(&self, data: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self {
+            Compression::None => Ok(data),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(&data)),
+            Compression::Zstd => Ok(zstd::encode_all(&data[..], ZSTD_LEVEL)?),
+        }
+    }
+
+    /// Decompress the data compressed with the codec.
+    pub fn decompress<'a>(
+        &self,
+        data: &'a [u8],
+    ) -> Result<Cow<'a, [u8]>, Box<dyn Error>> {
+        match self {
+            Compression::None => Ok(Cow::Borrowed(data)),
+            Compression::Lz4 => {
+                let data = lz4_flex::decompress_size_prepended(data)?;
+                Ok(Cow::Owned(data))
+            }
+            Compression::Zstd => Ok(Cow::Owned(zstd::decode_all(data)?)),
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Compression {
+    fn from(value: &str) -> Self {
+        let value = value.to_lowercase();
+        match value.as_str() {
+            NONE => Compression::None,
+            LZ4 => Compression::Lz4,
+            ZSTD => Compression::Zstd,
+            _ => panic!("Compression should be none, lz4, or zstd"),
+        }
+    }
+}
+
+impl From<String> for Compression {
+    fn from(value: String) -> Self {
+        Compression::from(value.as_str())
+    }
+}
+
+/// Options to encode the data files.
+///
+/// Fields:
+/// - compression: Codec to compress the data.
+/// - key: Key to encrypt the data after it's compressed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileOptions {
+    pub compression: Compression,
+    pub key: Option<EncryptionKey>,
+}
+
+/// Header of the data files persisted by the database.
+///
+/// Fields:
+/// - version: Format version of the data.
+/// - compression: Codec used to compress the data.
+/// - encrypted: Whether the data is encrypted.
+/// - length: Length of the stored data following the header.
+/// - checksum: CRC32 checksum of the stored data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHeader {
+    pub version: u32,
+    pub compression: Compression,
+    pub encrypted: bool,
+    pub length: u64,
+    pub checksum: u32,
+}
+
+impl FileHeader {
+    /// Create a header describing the given stored data.
+    pub fn new(data: &[u8], compression: Compression, encrypted: bool) -> Self {
+        FileHeader {
+            version: FORMAT_VERSION,
+            compression,
+            encrypted,
+            length: data.len() as u64,
+            checksum: crc32fast::hash(data),
+        }
+    }
+
+    /// Return the size of the encoded header in bytes.
+    pub fn size(&self) -> usize {
+        match self.version < 4 {
+            true => LEGACY_HEADER_SIZE,
+            false => HEADER_SIZE,
+        }
+    }
+
+    /// Encode the header with the magic bytes prefix.
+    ///
+    /// The codec and the encryption flag are stored in the bytes after the
+    /// checksum and the remaining bytes are reserved.
+    pub fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&MAGIC);
+        bytes[4..8].copy_from_slice(&self.version.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.length.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes[20] = self.compression.to_byte();
+        bytes[21] = self.encrypted as u8;
+        bytes
+    }
+
+    /// Decode the header from the start of the file content.
+    ///
+    /// Returns None if the content doesn't start with the magic bytes which
+    /// means that the file was written in the legacy format. Headers prior
+    /// to version 4 don't contain the codec and are never compressed.
+    /// Headers prior to version 5 are never encrypted.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < LEGACY_HEADER_SIZE || bytes[0..4] != MAGIC {
+            return None;
+        }
+
+        // Unwraps are safe because the slice lengths are fixed.
+        let mut header = FileHeader {
+            version: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            compression: Compression::None,
+            encrypted: false,
+            length: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            checksum: u32::from_le_bytes(bytes[16..20].try_into().unwrap()),
+        };
+
+        if header.version >= 4 {
+            let byte = *bytes.get(20)?;
+            header.compression = Compression::from_byte(byte)?;
+        }
+
+        if header.version >= 5 {
+            header.encrypted = match *bytes.get(21)? {
+                0 => false,
+                1 => true,
+                _ => return None,
+            };
+        }
+
+        Some(header)
+    }
+}
+
+/// Return the header fields authenticated with the encrypted data.
+///
+/// The length and checksum describe the encrypted data itself, so they're
+/// verified separately. The encryption flag is implied by the decryption.
+fn associated_data(version: u32, compression: Compression) -> [u8; 9] {
+    let mut bytes = [0; 9];
+    bytes[0..4].copy_from_slice(&MAGIC);
+    bytes[4..8].copy_from_slice(&version.to_le_bytes());
+    bytes[8] = compression.to_byte();
+    bytes
+}
+
+/// Data that can be persisted as a data file.
+///
+/// This trait allows the snapshot process to persist the frozen state of
+/// a storage implementation without knowing its concrete type.
+pub trait Encode: Send {
+    /// Serialize, compress, and encrypt the data with the file header.
+    fn encode(&self, options: &FileOptions) -> Result<Vec<u8>, Box<dyn Error>>;
+}
+
+impl<T: Serialize + Send> Encode for T {
+    fn encode(&self, options: &FileOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+        encode_file(self, options)
+    }
+}
+
+/// Serialize, compress, and encrypt the data and prefix it with the file
+/// header.
+pub fn encode_file<T: Serialize>(
+    data: &T,
+    options: &FileOptions,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let compression = options.compression;
+    let data = compression.compress(bincode::serialize(data)?)?;
+    seal_file(data, FORMAT_VERSION, compression, options.key.as_ref())
+}
+
+/// Encrypt the compressed data and prefix it with the file header.
+fn seal_file(
+    mut data: Vec<u8>,
+    version: u32,
+    compression: Compression,
+    key: Option<&EncryptionKey>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    if let Some(key) = key {
+        data = key.encrypt(&data, &associated_data(version, compression))?;
+    }
+
+    let mut header = FileHeader::new(&data, compression, key.is_some());
+    header.version = version;
+
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + data.len());
+    bytes.extend_from_slice(&header.to_bytes());
+    bytes.extend_from_slice(&data);
+    Ok(bytes)
+}
+
+/// Verify the file header and deserialize the data.
+///
+/// Files without the header are deserialized as legacy raw bincode data
+/// to allow upgrading data directories created by older versions.
+pub fn decode_file<T: DeserializeOwned>(
+    bytes: &[u8],
+    key: Option<&EncryptionKey>,
+) -> Result<T, Box<dyn Error>> {
+    let (_, data) = verify_file(bytes, key)?;
+    Ok(bincode::deserialize(&data)?)
+}
+
+/// Format version and decompressed data of a verified file.
+pub type VerifiedFile<'a> = (u32, Cow<'a, [u8]>);
+
+/// Verify the file header and return the format version and the data.
+///
+/// The data is decrypted with the key and decompressed with the codec
+/// recorded in the header. If a key is provided, the file must be
+/// encrypted with it. Otherwise, unencrypted data could be swapped in to
+/// bypass the authentication. Existing unencrypted files are encrypted
+/// explicitly with `encrypt_file` instead.
+///
+/// Files without the header are returned as is with version 0. This allows
+/// callers to deserialize the data with the layout of the older version.
+pub fn verify_file<'a>(
+    bytes: &'a [u8],
+    key: Option<&EncryptionKey>,
+) -> Result<VerifiedFile<'a>, Box<dyn Error>> {
+    let unencrypted = "Data file is not encrypted but a key is provided";
+    let (header, data) = match read_file(bytes)? {
+        Some(file) => file,
+        None if key.is_some() => return Err(unencrypted.into()),
+        None => return Ok((0, Cow::Borrowed(bytes))),
+    };
+
+    let data = match (header.encrypted, key) {
+        (false, None) => header.compression.decompress(data)?,
+        (false, Some(_)) => return Err(unencrypted.into()),
+        (true, Some(key)) => {
+            let associated =
+                associated_data(header.version, header.compression);
+            let data = key.decrypt(data, &associated)?;
+            Cow::Owned(header.compression.decompress(&data)?.into_owned())
+        }
+        (true, None) => {
+            return Err("Data file is encrypted but no key is provided".into())
+        }
+    };
+
+    Ok((header.version, data))
+}
+
+/// Encrypt an existing data file with the key.
+///
+/// Unencrypted files are encoded again with the same codec. The format
+/// version is kept so that they're loaded the same way as before, except
+/// that files prior to the encryption flag are marked with the version
+/// introducing it. Their layout only differs for the legacy parameters
+/// which must be encoded in the current format instead. Returns None if
+/// the file is already encrypted with the key.
+pub fn encrypt_file(
+    bytes: &[u8],
+    key: &EncryptionKey,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let (header, data) = match read_file(bytes)? {
+        Some(file) => file,
+        None => return Err("Data file has no header".into()),
+    };
+
+    // The decryption fails if the file is encrypted with another key.
+    if header.encrypted {
+        let associated = associated_data(header.version, header.compression);
+        key.decrypt(data, &associated)?;
+        return Ok(None);
+    }
+
+    let version = header.version.max(ENCRYPTED_VERSION);
+    let data = data.to_vec();
+    let sealed = seal_file(data, version, header.compression, Some(key))?;
+    Ok(Some(sealed))
+}
+
+/// Header and stored data of a data file.
+type StoredFile<'a> = (FileHeader, &'a [u8]);
+
+/// Validate the file header and return it with the stored data.
+///
+/// Returns None if the file doesn't start with the magic bytes which means
+/// that it was written in the legacy format.
+fn read_file(bytes: &[u8]) -> Result<Option<StoredFile<'_>>, Box<dyn Error>> {
+    let header = match FileHeader::from_bytes(bytes) {
+        Some(header) => header,
+        None if bytes.starts_with(&MAGIC) => {
+            return Err("Data file header is invalid".into());
+        }
+        None => return Ok(None),
+    };
+
+    if header.version > FORMAT_VERSION {
+        let message = format!(
+            "Unsupported format version {}: the latest supported is {}",
+            header.version, FORMAT_VERSION
+        );
+
+        return Err(message.into());
+    }
+
+    let data = bytes.get(header.size()..).unwrap_or_default();
+    if data.len() as u64 != header.length {
+        let message = format!(
+            "Data file is truncated: expected {} bytes, found {}",
+            header.length,
+            data.len()
+        );
+
+        return Err(message.into());
+    }
+
+    if crc32fast::hash(data) != header.checksum {
+        return Err("Data file is corrupted: checksum mismatch".into());
+    }
+
+    Ok(Some((header, data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let params = Parameters::default();
+        for compression in
+            [Compression::None, Compression::Lz4, Compression::Zstd]
+        {
+            let options = FileOptions { compression, key: None };
+            let bytes = encode_file(&params, &options).unwrap();
+
+            let header = FileHeader::from_bytes(&bytes).unwrap();
+            assert_eq!(header.version, FORMAT_VERSION);
+            assert_eq!(header.compression, compression);
+
+            let decoded: Parameters = decode_file(&bytes, None).unwrap();
+            assert_eq!(decoded, params);
+        }
+    }
+
+    #[test]
+    fn test_compress() {
+        let data = vec![Record::random(128); 16];
+        let raw = encode_file(&data, &FileOptions::default()).unwrap();
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let options = FileOptions { compression, key: None };
+            let bytes = encode_file(&data, &options).unwrap();
+            assert!(bytes.len() < raw.len());
+
+            let decoded: Vec<Record> = decode_file(&bytes, None).unwrap();
+            assert_eq!(decoded, data);
+        }
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let params = Parameters::default();
+        let key = EncryptionKey::from_hex(&"ab".repeat(32)).unwrap();
+        let compression = Compression::Lz4;
+        let options = FileOptions { compression, key: Some(key.clone()) };
+
+        let bytes = encode_file(&params, &options).unwrap();
+        assert!(FileHeader::from_bytes(&bytes).unwrap().encrypted);
+
+        let decoded: Parameters = decode_file(&bytes, Some(&key)).unwrap();
+        assert_eq!(decoded, params);
+
+        let other = EncryptionKey::from_hex(&"cd".repeat(32)).unwrap();
+        assert!(decode_file::<Parameters>(&bytes, None).is_err());
+        assert!(decode_file::<Parameters>(&bytes, Some(&other)).is_err());
+
+        // The header can't be modified without failing the decryption.
+        let mut swapped = bytes.clone();
+        swapped[20] = Compression::Zstd.to_byte();
+        assert!(decode_file::<Parameters>(&swapped, Some(&key)).is_err());
+    }
+
+    #[test]
+    fn test_unencrypted_with_key() {
+        let params = Parameters::default();
+        let key = EncryptionKey::from_hex(&"ab".repeat(32)).unwrap();
+
+        // Unencrypted data must not be accepted in place of encrypted data.
+        let bytes = encode_file(&params, &FileOptions::default()).unwrap();
+        assert!(decode_file::<Parameters>(&bytes, Some(&key)).is_err());
+
+        let legacy = bincode::serialize(&params).unwrap();
+        assert!(decode_file::<Parameters>(&legacy, Some(&key)).is_err());
+        assert!(encrypt_file(&legacy, &key).is_err());
+
+        let encrypted = encrypt_file(&bytes, &key).unwrap().unwrap();
+        let decoded: Parameters = decode_file(&encrypted, Some(&key)).unwrap();
+        assert_eq!(decoded, params);
+        assert!(encrypt_file(&encrypted, &key).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decode_version_3() {
+        let params = Parameters::default();
+        let data = bincode::serialize(&params).unwrap();
+
+        // Headers prior to version 4 don't contain the codec.
+        let header = FileHeader::new(&data, Compression::None, false);
+        let mut bytes = header.to_bytes()[..LEGACY_HEADER_SIZE].to_vec();
+        bytes[4..8].copy_from_slice(&3u32.to_le_bytes());
+        bytes.extend_from_slice(&data);
+
+        let (version, _) = verify_file(&bytes, None).unwrap();
+        assert_eq!(version, 3);
+        assert_eq!(decode_file::<Parameters>(&bytes, None).unwrap(), params);
+    }
+
+    #[test]
+    fn test_decode_corrupted() {
+        let params = Parameters::default();
+        let mut bytes = encode_file(&params, &FileOptions::default()).unwrap();
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        assert!(decode_file::<Parameters>(&bytes, None).is_err());
+
+        bytes.truncate(last);
+        assert!(decode_file::<Parameters>(&bytes, None).is_err());
+    }
+
+    #[test]
+    fn test_decode_newer_version() {
+        let params = Parameters::default();
+        let mut bytes = encode_file(&params, &FileOptions::default()).unwrap();
+        bytes[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(decode_file::<Parameters>(&bytes, None).is_err());
+    }
+
+    #[test]
+    fn test_decode_legacy() {
+        let params = Parameters::default();
+        let bytes = bincode::serialize(&params).unwrap();
+        let decoded: Parameters = decode_file(&bytes, None).unwrap();
+        assert_eq!(decoded, params);
+    }
+}
@@ -0,0 +1,626 @@
+use super::*;
+use rand::Rng;
+use std::cmp::{min, Reverse};
+use std::collections::BinaryHeap;
+
+/// Maximum layer of a node in the graph.
+///
+/// With the default parameters, the probability of a node reaching this
+/// layer is negligible, so this only bounds the pathological cases.
+const MAX_LAYER: usize = 16;
+
+/// Node of the HNSW graph.
+///
+/// The vector of the node is read from the storage, so the node only
+/// contains the IDs of its neighbors on each layer starting from the bottom.
+/// The top layer of the node is the number of layers minus one.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HnswNode {
+    neighbors: Vec<Vec<RecordID>>,
+}
+
+impl HnswNode {
+    fn new(layer: usize) -> Self {
+        HnswNode { neighbors: vec![vec![]; layer + 1] }
+    }
+
+    fn layer(&self) -> usize {
+        self.neighbors.len() - 1
+    }
+}
+
+/// HNSW graph changes since the previous snapshot.
+///
+/// Fields:
+/// - entry: Entry point of the graph after the changes.
+/// - upserts: Nodes inserted or with changed neighbors.
+/// - deletes: IDs of the deleted nodes.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HnswDelta {
+    pub entry: Option<RecordID>,
+    pub upserts: HashMap<RecordID, HnswNode>,
+    pub deletes: HashSet<RecordID>,
+}
+
+/// Hierarchical Navigable Small World graph index.
+///
+/// HNSW organizes the records into layers of proximity graphs where each
+/// layer contains a fraction of the records of the layer below it. The
+/// search starts from the sparse top layer and greedily descends to the
+/// bottom layer containing all records, which gives a high recall with
+/// a low latency at the cost of the memory used by the links.
+///
+/// Like the IVF index, the graph keeps track of the nodes changed since
+/// the previous snapshot to support incremental snapshots.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HnswIndex {
+    nodes: HashMap<RecordID, HnswNode>,
+    entry: Option<RecordID>,
+
+    // Index parameters.
+    metric: Metric,
+    m: usize,
+    ef_construction: usize,
+
+    #[serde(skip)]
+    changes: Mutex<HashSet<RecordID>>,
+}
+
+impl HnswIndex {
+    /// Create a new HNSW index with default parameters.
+    ///
+    /// Default parameters:
+    /// - metric: Euclidean
+    /// - m: 16
+    /// - ef_construction: 200
+    pub fn new() -> Self {
+        HnswIndex {
+            nodes: HashMap::new(),
+            entry: None,
+            metric: Metric::Euclidean,
+            m: 16,
+            ef_construction: 200,
+            changes: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Configure the metric used for distance calculations.
+    pub fn with_metric(mut self, metric: Metric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    /// Configure the max number of neighbors per node on the upper layers.
+    ///
+    /// The nodes on the bottom layer can have twice as many neighbors.
+    pub fn with_m(mut self, m: usize) -> Self {
+        self.m = m.max(2);
+        self
+    }
+
+    /// Configure the number of candidates explored during the insertion.
+    pub fn with_ef_construction(mut self, ef_construction: usize) -> Self {
+        self.ef_construction = ef_construction.max(1);
+        self
+    }
+
+    /// Apply the changes from a snapshot delta.
+    pub fn apply_delta(&mut self, delta: HnswDelta) {
+        for id in delta.deletes.iter() {
+            self.nodes.remove(id);
+        }
+
+        self.nodes.extend(delta.upserts);
+        self.entry = delta.entry;
+    }
+
+    fn track_change(&mut self, id: &RecordID) {
+        self.changes.get_mut().unwrap().insert(*id);
+    }
+
+    /// Draw the top layer of a new node.
+    ///
+    /// The probability of a node reaching a layer decreases exponentially
+    /// with the layer, normalized by the number of neighbors.
+    fn random_layer(&self) -> usize {
+        let normalizer = 1.0 / (self.m as f64).ln();
+        let uniform = 1.0 - rand::thread_rng().gen::<f64>();
+        let layer = (-uniform.ln() * normalizer).floor() as usize;
+        min(layer, MAX_LAYER)
+    }
+
+    /// Return the max number of neighbors of a node on a layer.
+    fn max_neighbors(&self, layer: usize) -> usize {
+        if layer == 0 {
+            self.m * 2
+        } else {
+            self.m
+        }
+    }
+
+    /// Calculate the distance between a vector and the vector of a node.
+    ///
+    /// Missing records are placed at an infinite distance.
+    fn candidate(
+        &self,
+        vector: &Vector,
+        id: &RecordID,
+        storage: &dyn Storage,
+    ) -> Candidate {
+        let distance = storage
+            .get(id)
+            .ok()
+            .and_then(|record| self.metric.distance(&record.vector, vector))
+            .map_or(f32::INFINITY, |distance| distance as f32);
+
+        Candidate { id: *id, distance }
+    }
+
+    /// Search a layer for the nearest nodes starting from the entry nodes.
+    ///
+    /// This returns at most ef nodes sorted by their distance to the vector.
+    /// Links to the deleted nodes are skipped.
+    fn search_layer(
+        &self,
+        vector: &Vector,
+        entries: &[Candidate],
+        ef: usize,
+        layer: usize,
+        storage: &dyn Storage,
+    ) -> Vec<Candidate> {
+        let mut visited: HashSet<RecordID> =
+            entries.iter().map(|c| c.id).collect();
+        let mut candidates: BinaryHeap<Reverse<Candidate>> =
+            entries.iter().copied().map(Reverse).collect();
+        let mut results: BinaryHeap<Candidate> =
+            entries.iter().copied().collect();
+
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(Reverse(current)) = candidates.pop() {
+            let furthest = results.peek().map_or(f32::INFINITY, |c| c.distance);
+            if current.distance > furthest && results.len() >= ef {
+                break;
+            }
+
+            let neighbors = match self.nodes.get(&current.id) {
+                Some(node) => node.neighbors.get(layer),
+                None => continue,
+            };
+
+            for neighbor in neighbors.into_iter().flatten() {
+                if !visited.insert(*neighbor) {
+                    continue;
+                }
+
+                if !self.nodes.contains_key(neighbor) {
+                    continue;
+                }
+
+                let candidate = self.candidate(vector, neighbor, storage);
+                let furthest =
+                    results.peek().map_or(f32::INFINITY, |c| c.distance);
+
+                if results.len() < ef || candidate.distance < furthest {
+                    candidates.push(Reverse(candidate));
+                    results.push(candidate);
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        results.into_sorted_vec()
+    }
+
+    /// Add a link from a node to a new neighbor on a layer.
+    ///
+    /// If the node has too many neighbors, only the nearest ones are kept.
+    /// Links to the deleted nodes are dropped along the way.
+    fn link(
+        &mut self,
+        id: &RecordID,
+        neighbor: &RecordID,
+        layer: usize,
+        storage: &dyn Storage,
+    ) {
+        let mut links = self.links(id, layer);
+        links.retain(|link| self.nodes.contains_key(link));
+        if !links.contains(neighbor) {
+            links.push(*neighbor);
+        }
+
+        let max = self.max_neighbors(layer);
+        if links.len() > max {
+            links = self.closest(id, links, max, storage);
+        }
+
+        self.set_links(id, layer, links);
+    }
+
+    /// Return the neighbors of a node on a layer.
+    fn links(&self, id: &RecordID, layer: usize) -> Vec<RecordID> {
+        self.nodes
+            .get(id)
+            .and_then(|node| node.neighbors.get(layer))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn set_links(&mut self, id: &RecordID, layer: usize, links: Vec<RecordID>) {
+        if let Some(node) = self.nodes.get_mut(id) {
+            node.neighbors[layer] = links;
+            self.track_change(id);
+        }
+    }
+
+    /// Select the n nearest candidates to a node.
+    fn closest(
+        &self,
+        id: &RecordID,
+        candidates: Vec<RecordID>,
+        n: usize,
+        storage: &dyn Storage,
+    ) -> Vec<RecordID> {
+        let record = match storage.get(id) {
+            Ok(record) => record,
+            Err(_) => return candidates.into_iter().take(n).collect(),
+        };
+
+        let mut candidates = candidates
+            .iter()
+            .map(|candidate| self.candidate(&record.vector, candidate, storage))
+            .collect::<Vec<Candidate>>();
+
+        candidates.sort();
+        candidates.into_iter().take(n).map(|c| c.id).collect()
+    }
+}
+
+impl Index for HnswIndex {
+    /// Insert a new record into the graph.
+    ///
+    /// The record must be inserted into the storage first because the
+    /// vectors of the nodes are read from the storage.
+    fn insert(
+        &mut self,
+        id: &RecordID,
+        record: &Record,
+        storage: &dyn Storage,
+    ) -> Result<(), Status> {
+        let vector = &record.vector;
+        let layer = self.random_layer();
+        self.track_change(id);
+
+        let entry = match self.entry {
+            Some(entry) => entry,
+            None => {
+                self.nodes.insert(*id, HnswNode::new(layer));
+                self.entry = Some(*id);
+                return Ok(());
+            }
+        };
+
+        let top_layer = self.nodes[&entry].layer();
+        let mut nearest = vec![self.candidate(vector, &entry, storage)];
+        for layer in (layer + 1..=top_layer).rev() {
+            nearest = self.search_layer(vector, &nearest, 1, layer, storage);
+        }
+
+        let mut node = HnswNode::new(layer);
+        for layer in (0..=min(layer, top_layer)).rev() {
+            let ef = self.ef_construction;
+            nearest = self.search_layer(vector, &nearest, ef, layer, storage);
+
+            let max = self.max_neighbors(layer);
+            let neighbors = nearest.iter().take(max).map(|c| c.id).collect();
+            node.neighbors[layer] = neighbors;
+        }
+
+        let links = node.neighbors.clone();
+        self.nodes.insert(*id, node);
+        for (layer, neighbors) in links.iter().enumerate() {
+            for neighbor in neighbors.iter() {
+                self.link(neighbor, id, layer, storage);
+            }
+        }
+
+        if layer > top_layer {
+            self.entry = Some(*id);
+        }
+
+        Ok(())
+    }
+
+    /// Delete a record from the graph by its ID.
+    ///
+    /// The neighbors linking to the deleted node are reconnected to its
+    /// other neighbors so that the graph stays navigable. This method must
+    /// be called before the record is deleted from the storage.
+    ///
+    /// If the entry point is deleted, the node on the highest layer is
+    /// chosen as the new entry point. This requires a scan over all nodes
+    /// but it rarely happens.
+    fn delete(
+        &mut self,
+        id: &RecordID,
+        storage: &dyn Storage,
+    ) -> Result<(), Status> {
+        let node = match self.nodes.remove(id) {
+            Some(node) => node,
+            None => return Ok(()),
+        };
+
+        self.track_change(id);
+        for (layer, neighbors) in node.neighbors.iter().enumerate() {
+            for neighbor in neighbors.iter() {
+                let links = self.links(neighbor, layer);
+                if !links.contains(id) {
+                    continue;
+                }
+
+                let mut candidates = links;
+                candidates.extend(neighbors.iter().filter(|n| *n != neighbor));
+                candidates.sort_unstable();
+                candidates.dedup();
+                candidates.retain(|n| n != id && self.nodes.contains_key(n));
+
+                let max = self.max_neighbors(layer);
+                let links = self.closest(neighbor, candidates, max, storage);
+                self.set_links(neighbor, layer, links);
+            }
+        }
+
+        if self.entry == Some(*id) {
+            self.entry = self
+                .nodes
+                .iter()
+                .max_by_key(|(_, node)| node.layer())
+                .map(|(id, _)| *id);
+        }
+
+        Ok(())
+    }
+
+    /// Search for the nearest neighbors of a given vector.
+    ///
+    /// The graph is searched with the ef query parameter as the number of
+    /// candidates, which is raised to k if it's lower. The filters and the
+    /// radius are applied to the candidates, so fewer than k results might
+    /// be returned with restrictive filters unless ef is increased.
+    fn query(
+        &self,
+        vector: &Vector,
+        k: usize,
+        filters: &Filters,
+        params: &QueryParameters,
+        storage: &dyn Storage,
+    ) -> Result<Vec<QueryResult>, Status> {
+        let entry = match self.entry {
+            Some(entry) => entry,
+            None => return Ok(vec![]),
+        };
+
+        let mut nearest = vec![self.candidate(vector, &entry, storage)];
+        for layer in (1..=self.nodes[&entry].layer()).rev() {
+            nearest = self.search_layer(vector, &nearest, 1, layer, storage);
+        }
+
+        let ef = params.ef.max(k);
+        let candidates = self.search_layer(vector, &nearest, ef, 0, storage);
+
+        let mut results = vec![];
+        for candidate in candidates {
+            if results.len() == k || candidate.distance > params.radius {
+                break;
+            }
+
+            let record = match storage.get(&candidate.id) {
+                Ok(record) => record,
+                Err(_) => continue,
+            };
+
+            if !filters.apply(&record.metadata) {
+                continue;
+            }
+
+            results.push(QueryResult {
+                id: candidate.id,
+                metadata: record.metadata.clone(),
+                distance: candidate.distance,
+            });
+        }
+
+        Ok(results)
+    }
+
+    /// Rebuild the graph from the records in the storage.
+    ///
+    /// All nodes, including the ones removed by the rebuild, are tracked
+    /// as changed for the next snapshot.
+    fn rebuild(&mut self, storage: &dyn Storage) -> Result<(), Status> {
+        let mut index = HnswIndex::new()
+            .with_metric(self.metric)
+            .with_m(self.m)
+            .with_ef_construction(self.ef_construction);
+
+        for (id, record) in storage.iter() {
+            index.insert(&id, &record, storage)?;
+        }
+
+        let changes = self.changes.get_mut().unwrap();
+        changes.extend(self.nodes.keys());
+        changes.extend(index.nodes.keys());
+
+        self.nodes = index.nodes;
+        self.entry = index.entry;
+        Ok(())
+    }
+
+    fn stats(&self) -> IndexStats {
+        IndexStats { records: self.nodes.len(), cluster_sizes: vec![] }
+    }
+
+    fn ids(&self) -> Vec<RecordID> {
+        self.nodes.keys().copied().collect()
+    }
+
+    /// The tracked changes aren't included in the copy.
+    fn freeze(&self) -> Box<dyn Encode> {
+        Box::new(HnswIndex {
+            nodes: self.nodes.clone(),
+            entry: self.entry,
+            metric: self.metric,
+            m: self.m,
+            ef_construction: self.ef_construction,
+            changes: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn take_changes(&self) -> IndexChanges {
+        let changes = std::mem::take(&mut *self.changes.lock().unwrap());
+        IndexChanges::Records(changes)
+    }
+
+    fn restore_changes(&self, changes: IndexChanges) {
+        match changes {
+            IndexChanges::Records(changes) => {
+                self.changes.lock().unwrap().extend(changes)
+            }
+            _ => unreachable!("HNSW index should track record changes"),
+        }
+    }
+
+    /// The delta contains the changed nodes with their neighbors.
+    fn delta(&self, changes: &IndexChanges) -> Box<dyn Encode> {
+        let changes = match changes {
+            IndexChanges::Records(changes) => changes,
+            _ => unreachable!("HNSW index should track record changes"),
+        };
+
+        let mut delta = HnswDelta { entry: self.entry, ..Default::default() };
+        for id in changes.iter() {
+            match self.nodes.get(id) {
+                Some(node) => {
+                    delta.upserts.insert(*id, node.clone());
+                }
+                None => {
+                    delta.deletes.insert(*id);
+                }
+            }
+        }
+
+        Box::new(delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query() {
+        let params = Parameters::default();
+        let (index, storage, ids) = setup_index(&params, 500);
+
+        let query = Vector::from(vec![1.0; params.dimension]);
+        let query_params = QueryParameters::default();
+        let result = index
+            .query(&query, 10, &Filters::None, &query_params, &storage)
+            .unwrap();
+
+        assert_eq!(result.len(), 10);
+        assert_eq!(result[0].id, ids[1]);
+        assert!(result.windows(2).all(|r| r[0].distance <= r[1].distance));
+
+        let metadata_filters = Filters::try_from("number > 1050").unwrap();
+        let result = index
+            .query(&query, 10, &metadata_filters, &query_params, &storage)
+            .unwrap();
+
+        assert_eq!(result.len(), 10);
+        assert_eq!(result[0].id, ids[51]);
+    }
+
+    #[test]
+    fn test_delete() {
+        let params = Parameters::default();
+        let (mut index, mut storage, ids) = setup_index(&params, 500);
+
+        for id in ids.iter().step_by(2) {
+            index.delete(id, &storage).unwrap();
+            storage.delete(id).unwrap();
+        }
+
+        assert_eq!(index.nodes.len(), 250);
+        assert!(index.nodes.contains_key(&index.entry.unwrap()));
+
+        // The remaining records are still reachable from the entry point.
+        let query = Vector::from(vec![1.0; params.dimension]);
+        let query_params = QueryParameters::default();
+        let result = index
+            .query(&query, 10, &Filters::None, &query_params, &storage)
+            .unwrap();
+
+        assert_eq!(result.len(), 10);
+        assert!(result.iter().all(|r| storage.get(&r.id).is_ok()));
+        assert_eq!(result[0].id, ids[1]);
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        let params = Parameters::default();
+        let (mut index, mut storage, ids) = setup_index(&params, 200);
+
+        // Create a snapshot copy of the index.
+        let mut copy = HnswIndex::new();
+        copy.apply_delta(collect_delta(&index));
+        assert_eq!(copy.nodes, index.nodes);
+
+        for id in ids.iter().step_by(3) {
+            index.delete(id, &storage).unwrap();
+            storage.delete(id).unwrap();
+        }
+
+        copy.apply_delta(collect_delta(&index));
+        assert_eq!(copy.nodes, index.nodes);
+        assert_eq!(copy.entry, index.entry);
+    }
+
+    fn collect_delta(index: &HnswIndex) -> HnswDelta {
+        let delta = index.delta(&index.take_changes());
+        let bytes = delta.encode(&FileOptions::default()).unwrap();
+        decode_file(&bytes, None).unwrap()
+    }
+
+    /// Create an index with sequential records to predict the results.
+    fn setup_index(
+        params: &Parameters,
+        count: usize,
+    ) -> (HnswIndex, MemoryStorage, Vec<RecordID>) {
+        let mut index = HnswIndex::new()
+            .with_metric(params.metric)
+            .with_ef_construction(64);
+        let mut storage = MemoryStorage::new();
+
+        let mut ids = vec![];
+        for i in 0..count {
+            let id = RecordID::new();
+            let vector = Vector::from(vec![i as f32; params.dimension]);
+
+            let mut metadata = HashMap::new();
+            let value = Value::Number((1000 + i) as f64);
+            metadata.insert("number".to_string(), value);
+
+            let record = Record { vector, metadata };
+            storage.insert(&id, &record).unwrap();
+            index.insert(&id, &record, &storage).unwrap();
+            ids.push(id);
+        }
+
+        (index, storage, ids)
+    }
+}
@@ -0,0 +1,413 @@
+use super::*;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+
+// Import format name constants.
+const FVECS: &str = "fvecs";
+const BVECS: &str = "bvecs";
+const NPY: &str = "npy";
+const JSONL: &str = "jsonl";
+
+/// Magic bytes at the start of a NumPy array file.
+pub const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+/// Format of the dataset file to import.
+///
+/// ### Fvecs and Bvecs
+/// ANN benchmark formats where each vector is stored as its dimension
+/// (i32) followed by its components as f32 or u8 respectively.
+///
+/// ### Npy
+/// NumPy array file containing a 2-dimensional array of f32, f64, or u8
+/// values in C order with a row for each vector.
+///
+/// ### Jsonl
+/// JSON object per line with a vector and optional metadata:
+/// `{"vector": [0.1, 0.2], "metadata": {"key": "value"}}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    Fvecs,
+    Bvecs,
+    Npy,
+    Jsonl,
+}
+
+impl ImportFormat {
+    /// Return the format name as a string slice.
+    pub fn as_str(&self) -> &str {
+        match self {
+            ImportFormat::Fvecs => FVECS,
+            ImportFormat::Bvecs => BVECS,
+            ImportFormat::Npy => NPY,
+            ImportFormat::Jsonl => JSONL,
+        }
+    }
+
+    /// Detect the format from the file extension.
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            FVECS => Some(ImportFormat::Fvecs),
+            BVECS => Some(ImportFormat::Bvecs),
+            NPY => Some(ImportFormat::Npy),
+            JSONL => Some(ImportFormat::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for ImportFormat {
+    fn from(value: &str) -> Self {
+        let value = value.to_lowercase();
+        match value.as_str() {
+            FVECS => ImportFormat::Fvecs,
+            BVECS => ImportFormat::Bvecs,
+            NPY => ImportFormat::Npy,
+            JSONL => ImportFormat::Jsonl,
+            _ => panic!("Import format should be fvecs, bvecs, npy, or jsonl"),
+        }
+    }
+}
+
+impl From<String> for ImportFormat {
+    fn from(value: String) -> Self {
+        ImportFormat::from(value.as_str())
+    }
+}
+
+/// Type of the values in a NumPy array.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NpyType {
+    F32,
+    F64,
+    U8,
+}
+
+impl NpyType {
+    fn size(&self) -> usize {
+        match self {
+            NpyType::F32 => 4,
+            NpyType::F64 => 8,
+            NpyType::U8 => 1,
+        }
+    }
+}
+
+/// Layout of the NumPy array after the header.
+#[derive(Debug)]
+struct NpyArray {
+    kind: NpyType,
+    rows: usize,
+    dimension: usize,
+}
+
+/// Reader of the records from a dataset file.
+///
+/// The records are read one by one, so files larger than the memory can
+/// be imported. Each record is returned as a result, so a malformed entry
+/// stops the import with an error pointing at its position.
+#[derive(Debug)]
+pub struct ImportReader {
+    reader: BufReader<File>,
+    format: ImportFormat,
+    npy: Option<NpyArray>,
+    position: usize,
+}
+
+impl ImportReader {
+    /// Open the dataset file with the format.
+    pub fn open(
+        path: impl AsRef<Path>,
+        format: ImportFormat,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let npy = match format {
+            ImportFormat::Npy => Some(Self::read_npy_header(&mut reader)?),
+            _ => None,
+        };
+
+        Ok(ImportReader { reader, format, npy, position: 0 })
+    }
+
+    fn read_record(&mut self) -> Result<Option<Record>, Box<dyn Error>> {
+        let vector = match self.format {
+            ImportFormat::Fvecs => self.read_vecs(4)?,
+            ImportFormat::Bvecs => self.read_vecs(1)?,
+            ImportFormat::Npy => self.read_npy_row()?,
+            ImportFormat::Jsonl => return self.read_json_line(),
+        };
+
+        Ok(vector.map(|vector| Record { vector, metadata: HashMap::new() }))
+    }
+
+    /// Read a vector from the fvecs or bvecs file.
+    fn read_vecs(
+        &mut self,
+        size: usize,
+    ) -> Result<Option<Vector>, Box<dyn Error>> {
+        let mut dimension = [0; 4];
+        match self.reader.read_exact(&mut dimension) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let dimension = i32::from_le_bytes(dimension);
+        if dimension <= 0 {
+            return Err(format!("Invalid vector dimension: {dimension}").into());
+        }
+
+        let mut bytes = vec![0; dimension as usize * size];
+        self.reader.read_exact(&mut bytes)?;
+
+        let vector = match size {
+            1 => bytes.iter().map(|&value| value as f32).collect(),
+            _ => bytes
+                .chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect::<Vec<f32>>(),
+        };
+
+        Ok(Some(vector.into()))
+    }
+
+    fn read_npy_row(&mut self) -> Result<Option<Vector>, Box<dyn Error>> {
+        // Unwrap is safe because the header is read when the file is opened.
+        let array = self.npy.as_ref().unwrap();
+        if self.position >= array.rows {
+            return Ok(None);
+        }
+
+        let size = array.kind.size();
+        let mut bytes = vec![0; array.dimension * size];
+        self.reader.read_exact(&mut bytes)?;
+
+        let chunks = bytes.chunks_exact(size);
+        let vector = match array.kind {
+            NpyType::F32 => chunks
+                .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect::<Vec<f32>>(),
+            NpyType::F64 => chunks
+                .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()))
+                .map(|value| value as f32)
+                .collect(),
+            NpyType::U8 => bytes.iter().map(|&value| value as f32).collect(),
+        };
+
+        Ok(Some(vector.into()))
+    }
+
+    fn read_json_line(&mut self) -> Result<Option<Record>, Box<dyn Error>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+
+            // Blank lines are allowed between the records.
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+
+        let object: serde_json::Value = serde_json::from_str(&line)?;
+        let vector = object
+            .get("vector")
+            .and_then(|vector| vector.as_array())
+            .ok_or("Record must contain a vector array")?
+            .iter()
+            .map(|value| value.as_f64().map(|value| value as f32))
+            .collect::<Option<Vec<f32>>>()
+            .ok_or("Vector must only contain numbers")?;
+
+        let mut metadata = HashMap::new();
+        let entries =
+            object.get("metadata").and_then(|value| value.as_object());
+        for (key, value) in entries.into_iter().flatten() {
+            let value = match value {
+                serde_json::Value::String(text) => Value::Text(text.to_owned()),
+                serde_json::Value::Bool(boolean) => Value::Boolean(*boolean),
+                serde_json::Value::Number(number) => {
+                    Value::Number(number.as_f64().unwrap_or_default())
+                }
+                _ => {
+                    let message = format!(
+                        "Metadata value of {key} must be a string, number, \
+                        or boolean"
+                    );
+
+                    return Err(message.into());
+                }
+            };
+
+            metadata.insert(key.to_owned(), value);
+        }
+
+        Ok(Some(Record { vector: vector.into(), metadata }))
+    }
+
+    /// Read the NumPy header and validate the array layout.
+    fn read_npy_header(
+        reader: &mut impl Read,
+    ) -> Result<NpyArray, Box<dyn Error>> {
+        let mut preamble = [0; 8];
+        reader.read_exact(&mut preamble)?;
+        if &preamble[..6] != NPY_MAGIC {
+            return Err("The file is not a NumPy array".into());
+        }
+
+        // Version 1 stores the header length as u16 and the later versions
+        // as u32.
+        let length = match preamble[6] {
+            1 => {
+                let mut length = [0; 2];
+                reader.read_exact(&mut length)?;
+                u16::from_le_bytes(length) as usize
+            }
+            _ => {
+                let mut length = [0; 4];
+                reader.read_exact(&mut length)?;
+                u32::from_le_bytes(length) as usize
+            }
+        };
+
+        let mut header = vec![0; length];
+        reader.read_exact(&mut header)?;
+        let header = String::from_utf8_lossy(&header);
+
+        let field = |name: &str| -> Option<String> {
+            let start = header.find(&format!("'{name}'"))? + name.len() + 2;
+            let value = header[start..].trim_start().strip_prefix(':')?;
+            Some(value.trim_start().to_string())
+        };
+
+        let descr = field("descr").ok_or("NumPy header has no descr")?;
+        let kind = match descr.split('\'').nth(1) {
+            Some("<f4") => NpyType::F32,
+            Some("<f8") => NpyType::F64,
+            Some("|u1") | Some("<u1") => NpyType::U8,
+            _ => return Err("NumPy array must contain f32, f64, or u8".into()),
+        };
+
+        let fortran = field("fortran_order").unwrap_or_default();
+        if fortran.starts_with("True") {
+            return Err("NumPy array must be stored in C order".into());
+        }
+
+        let shape = field("shape").ok_or("NumPy header has no shape")?;
+        let shape = shape
+            .trim_start_matches('(')
+            .split(')')
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::parse::<usize>)
+            .collect::<Result<Vec<usize>, _>>()?;
+
+        match shape.as_slice() {
+            [rows, dimension] => {
+                Ok(NpyArray { kind, rows: *rows, dimension: *dimension })
+            }
+            _ => Err("NumPy array must be 2-dimensional".into()),
+        }
+    }
+}
+
+impl Iterator for ImportReader {
+    type Item = Result<Record, Box<dyn Error>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.read_record().map_err(|e| {
+            let position = self.position + 1;
+            let message = format!("Failed to read record {position}: {e}");
+            message.into()
+        });
+
+        self.position += 1;
+        record.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_fvecs() {
+        let mut bytes = vec![];
+        for vector in [[1.0f32, 2.0], [3.0, 4.0]] {
+            bytes.extend_from_slice(&2i32.to_le_bytes());
+            for value in vector {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        let records = read_records("vectors.fvecs", &bytes);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].vector.to_vec(), vec![3.0, 4.0]);
+    }
+
+    #[test]
+    fn test_read_bvecs() {
+        let mut bytes = 3i32.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[1, 2, 255]);
+
+        let records = read_records("vectors.bvecs", &bytes);
+        assert_eq!(records[0].vector.to_vec(), vec![1.0, 2.0, 255.0]);
+    }
+
+    #[test]
+    fn test_read_npy() {
+        let header = "{'descr': '<f4', 'fortran_order': False, \
+            'shape': (2, 3), }";
+
+        let mut bytes = NPY_MAGIC.to_vec();
+        bytes.extend_from_slice(&[1, 0]);
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        for value in 0..6 {
+            bytes.extend_from_slice(&(value as f32).to_le_bytes());
+        }
+
+        let records = read_records("vectors.npy", &bytes);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].vector.to_vec(), vec![3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn test_read_jsonl() {
+        let lines = "{\"vector\": [1, 2.5], \"metadata\": {\"a\": \"x\"}}\n\
+            \n\
+            {\"vector\": [3, 4], \"metadata\": {\"b\": true, \"c\": 1}}\n";
+
+        let records = read_records("records.jsonl", lines.as_bytes());
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].vector.to_vec(), vec![1.0, 2.5]);
+        assert_eq!(records[0].metadata["a"], Value::Text("x".to_string()));
+        assert_eq!(records[1].metadata["b"], Value::Boolean(true));
+        assert_eq!(records[1].metadata["c"], Value::Number(1.0));
+
+        let path = write_file("invalid.jsonl", b"{\"vector\": [\"a\"]}\n");
+        let mut reader = ImportReader::open(path, ImportFormat::Jsonl).unwrap();
+        let error = reader.next().unwrap().unwrap_err().to_string();
+        assert!(error.starts_with("Failed to read record 1"));
+    }
+
+    fn read_records(name: &str, bytes: &[u8]) -> Vec<Record> {
+        let path = write_file(name, bytes);
+        let format = ImportFormat::from_path(&path).unwrap();
+        let reader = ImportReader::open(path, format).unwrap();
+        reader.collect::<Result<Vec<Record>, _>>().unwrap()
+    }
+
+    fn write_file(name: &str, bytes: &[u8]) -> PathBuf {
+        let dir = env::temp_dir().join("oasysdb_import_tests");
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+}
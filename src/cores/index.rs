@@ -1,10 +1,20 @@
 use super::*;
 use std::cmp::{min, Ordering};
 use std::collections::BinaryHeap;
+use std::fmt::Debug;
 use std::rc::Rc;
 
+// Index type name constants.
+const IVF: &str = "ivf";
+const HNSW: &str = "hnsw";
+const FLAT: &str = "flat";
+const IVF_PQ: &str = "ivfpq";
+
 type ClusterIndex = usize;
 
+/// Location of a record in the index as the cluster and slot indices.
+type RecordLocation = (ClusterIndex, usize);
+
 /// ANNS search result containing the metadata of the record.
 ///
 /// We exclude the vector data from the result because it doesn't provide
@@ -55,34 +65,294 @@ impl From<QueryResult> for protos::QueryResult {
     }
 }
 
-/// ANNS Index interface.
+/// Record visited during the search with its distance to the query.
+///
+/// Unlike the query result, the candidate doesn't contain the metadata, so
+/// it's cheap to collect many of them before the final results are chosen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+    pub id: RecordID,
+    pub distance: f32,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance.partial_cmp(&other.distance).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Type of the ANNS index with its build parameters.
+///
+/// ### Ivf
+/// Clusters of records that grow by splitting. This is the default index
+/// with a low memory overhead.
+///
+/// ### Hnsw
+/// Layered proximity graph with a higher recall and a lower latency.
+/// - m: Max number of neighbors per node on the upper layers.
+/// - ef_construction: Number of candidates explored during the insertion.
+///
+/// ### Flat
+/// List of records compared exhaustively with the query. The results are
+/// exact, but the query time grows linearly with the number of records.
+///
+/// ### IvfPq
+/// IVF clusters with the vectors compressed by product quantization. The
+/// candidates are ranked by the compressed vectors before the top ones are
+/// re-ranked with the full-precision vectors.
+/// - subspaces: Number of subspaces, each encoded into 1 byte.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum IndexType {
+    Ivf,
+    Hnsw { m: usize, ef_construction: usize },
+    Flat,
+    IvfPq { subspaces: usize },
+}
+
+impl IndexType {
+    /// Return the index type name as a string slice.
+    pub fn as_str(&self) -> &str {
+        match self {
+            IndexType::Ivf => IVF,
+            IndexType::Hnsw { .. } => HNSW,
+            IndexType::Flat => FLAT,
+            IndexType::IvfPq { .. } => IVF_PQ,
+        }
+    }
+}
+
+impl From<&str> for IndexType {
+    /// Parse the index type with its default build parameters.
+    fn from(value: &str) -> Self {
+        let value = value.to_lowercase();
+        match value.as_str() {
+            IVF => IndexType::Ivf,
+            HNSW => IndexType::Hnsw { m: 16, ef_construction: 200 },
+            FLAT => IndexType::Flat,
+            IVF_PQ => IndexType::IvfPq { subspaces: 16 },
+            _ => panic!("Index type should be ivf, hnsw, flat, or ivfpq"),
+        }
+    }
+}
+
+impl From<String> for IndexType {
+    fn from(value: String) -> Self {
+        IndexType::from(value.as_str())
+    }
+}
+
+/// Index statistics.
+///
+/// Fields:
+/// - records: Number of indexed records.
+/// - cluster_sizes: Number of records in each cluster of a clustered index.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IndexStats {
+    pub records: usize,
+    pub cluster_sizes: Vec<usize>,
+}
+
+/// Changes tracked by the index since the previous snapshot.
+///
+/// Clustered indexes track the changed clusters while graph indexes track
+/// the changed records.
+#[derive(Debug)]
+pub enum IndexChanges {
+    Clusters(HashSet<ClusterIndex>),
+    Records(HashSet<RecordID>),
+}
+
+/// ANNS index interface.
+///
+/// The database accesses the index only through this interface, so the
+/// index implementations can coexist. The implementation is selected by
+/// the index type of the database parameters.
+///
+/// Like the storage, the index keeps track of the changes since the
+/// previous snapshot and provides frozen copies of its state so that
+/// snapshots are persisted without blocking the writers.
+#[allow(clippy::result_large_err)]
+pub trait Index: Debug + Send + Sync {
+    /// Insert a new record into the index.
+    ///
+    /// The record must be inserted into the storage first because the
+    /// index might read the vectors of the other records.
+    fn insert(
+        &mut self,
+        id: &RecordID,
+        record: &Record,
+        storage: &dyn Storage,
+    ) -> Result<(), Status>;
+
+    /// Delete a record from the index by its ID.
+    ///
+    /// This must be called before the record is deleted from the storage.
+    /// Deleting a missing record is a no-op.
+    fn delete(
+        &mut self,
+        id: &RecordID,
+        storage: &dyn Storage,
+    ) -> Result<(), Status>;
+
+    /// Search for the nearest neighbors of a given vector.
+    fn query(
+        &self,
+        vector: &Vector,
+        k: usize,
+        filters: &Filters,
+        params: &QueryParameters,
+        storage: &dyn Storage,
+    ) -> Result<Vec<QueryResult>, Status>;
+
+    /// Rebuild the index from the records in the storage.
+    fn rebuild(&mut self, storage: &dyn Storage) -> Result<(), Status>;
+
+    /// Return the statistics of the index.
+    fn stats(&self) -> IndexStats;
+
+    /// Return the IDs of the indexed records.
+    ///
+    /// The IDs indexed more than once are returned multiple times.
+    fn ids(&self) -> Vec<RecordID>;
+
+    /// Create a frozen copy of the index for a full snapshot.
+    fn freeze(&self) -> Box<dyn Encode>;
+
+    /// Take the changes tracked since the previous snapshot.
+    fn take_changes(&self) -> IndexChanges;
+
+    /// Restore the taken changes when the snapshot fails.
+    fn restore_changes(&self, changes: IndexChanges);
+
+    /// Collect the delta of the changes for a delta snapshot.
+    fn delta(&self, changes: &IndexChanges) -> Box<dyn Encode>;
+
+    /// Configure the fraction of the density below which a cluster is
+    /// merged into its neighbors when records are deleted.
+    fn set_merge_threshold(&mut self, _merge_threshold: f32) {}
+}
+
+/// Clustered index whose records can be reassigned to new centroids.
+///
+/// After the centroids are retrained, the records are assigned to the new
+/// clusters in batches while the current index keeps serving the queries.
+pub trait ClusteredIndex: Index {
+    /// Assign a record to the nearest cluster.
+    ///
+    /// Unlike the insert method, this method doesn't update the centroid
+    /// or split the cluster when it's full.
+    fn assign(&mut self, id: &RecordID, vector: &Vector);
+
+    /// Keep only the records for which the predicate returns true.
+    ///
+    /// Empty clusters are removed afterwards. Because this can move the
+    /// clusters around, all clusters are tracked as changed.
+    fn retain(&mut self, predicate: impl Fn(&RecordID) -> bool);
+}
+
+/// Index changes since the previous snapshot.
+///
+/// Fields:
+/// - len: Number of clusters after the changes are applied.
+/// - clusters: Changed clusters with their centroid and record IDs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct IvfDelta {
+    pub len: usize,
+    pub clusters: Vec<(ClusterIndex, Vector, Vec<RecordID>)>,
+}
+
+/// Inverted file (IVF) index.
 ///
 /// OasysDB uses a modified version of IVF index algorithm. This custom index
 /// implementation allows OasysDB to maintain a balanced index structure
 /// allowing the clusters to grow to accommodate data growth.
+///
+/// Similar to the storage, the index keeps track of the clusters changed
+/// since the previous snapshot to support incremental snapshots.
+///
+/// The index also maps each record to its location in the clusters so that
+/// records can be deleted in constant time.
 #[repr(C)]
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Index {
+pub struct IvfIndex {
     centroids: Vec<Vector>,
     clusters: Vec<Vec<RecordID>>,
+    locations: HashMap<RecordID, RecordLocation>,
 
     // Index parameters.
     metric: Metric,
     density: usize,
+
+    // The merge threshold is an open-time parameter, so it isn't persisted
+    // with the index and must be configured after the index is loaded.
+    #[serde(skip)]
+    merge_threshold: f32,
+
+    #[serde(skip)]
+    changes: Mutex<HashSet<ClusterIndex>>,
+
+    // Fixed-size codes of the records in the same slot order as the
+    // clusters. Only the IVF-PQ index configures the code size and it
+    // persists the codes itself. The codes are empty otherwise.
+    #[serde(skip)]
+    codes: Vec<Vec<u8>>,
+    #[serde(skip)]
+    code_size: usize,
+}
+
+/// Index prior to format version 6.
+///
+/// The record locations are calculated when the index is upgraded.
+#[derive(Debug, Deserialize)]
+pub struct LegacyIndex {
+    centroids: Vec<Vector>,
+    clusters: Vec<Vec<RecordID>>,
+    metric: Metric,
+    density: usize,
+}
+
+impl From<LegacyIndex> for IvfIndex {
+    fn from(value: LegacyIndex) -> Self {
+        let mut index = IvfIndex::new()
+            .with_metric(value.metric)
+            .with_density(value.density);
+
+        index.centroids = value.centroids;
+        index.clusters = value.clusters;
+        for cluster_id in 0..index.clusters.len() {
+            index.relocate(cluster_id);
+        }
+
+        index
+    }
 }
 
-impl Index {
+impl IvfIndex {
     /// Create a new index instance with default parameters.
     ///
     /// Default parameters:
     /// - metric: Euclidean
     /// - density: 256
+    /// - merge_threshold: 0.0
     pub fn new() -> Self {
-        Index {
+        IvfIndex {
             centroids: vec![],
             clusters: vec![],
+            locations: HashMap::new(),
             metric: Metric::Euclidean,
             density: 256,
+            merge_threshold: 0.0,
+            changes: Mutex::new(HashSet::new()),
+            codes: vec![],
+            code_size: 0,
         }
     }
 
@@ -98,119 +368,260 @@ impl Index {
         self
     }
 
-    /// Insert a new record into the index.
+    /// Configure the fraction of the density below which a cluster is
+    /// merged into its neighbors when records are deleted.
     ///
-    /// This method required the reference to all the records because
-    /// during the cluster splitting process, the record assignments
-    /// will be re-calculated
-    pub fn insert(
+    /// A threshold of 0 disables the merging.
+    pub fn with_merge_threshold(mut self, merge_threshold: f32) -> Self {
+        self.merge_threshold = merge_threshold;
+        self
+    }
+
+    /// Return the number of records in each cluster.
+    pub fn cluster_sizes(&self) -> Vec<usize> {
+        self.clusters.iter().map(Vec::len).collect()
+    }
+
+    /// Return the metric used for distance calculations.
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    /// Return the number of records in the index.
+    pub fn count(&self) -> usize {
+        self.locations.len()
+    }
+
+    /// Return the record IDs of the clusters nearest to the vector.
+    /// - probes: Number of clusters to return.
+    pub fn nearest_clusters(
+        &self,
+        vector: &Vector,
+        probes: usize,
+    ) -> Vec<&[RecordID]> {
+        let nearest_clusters = self.sort_nearest_centroids(vector);
+        nearest_clusters
+            .into_iter()
+            .take(probes)
+            .map(|cluster_id| self.clusters[cluster_id].as_slice())
+            .collect()
+    }
+
+    /// Return the record IDs and their codes of the clusters nearest to
+    /// the vector.
+    /// - probes: Number of clusters to return.
+    pub fn nearest_codes(
+        &self,
+        vector: &Vector,
+        probes: usize,
+    ) -> Vec<(&[RecordID], &[u8])> {
+        let nearest_clusters = self.sort_nearest_centroids(vector);
+        nearest_clusters
+            .into_iter()
+            .take(probes)
+            .map(|cluster_id| {
+                let codes = self.codes.get(cluster_id).map(Vec::as_slice);
+                (self.clusters[cluster_id].as_slice(), codes.unwrap_or(&[]))
+            })
+            .collect()
+    }
+
+    /// Return the codes of the clusters.
+    pub fn codes(&self) -> &[Vec<u8>] {
+        &self.codes
+    }
+
+    /// Replace the codes of all clusters.
+    /// - code_size: Number of bytes of each code.
+    /// - codes: Codes of each cluster in the slot order of the records.
+    pub fn set_codes(&mut self, code_size: usize, codes: Vec<Vec<u8>>) {
+        self.code_size = code_size;
+        self.codes = codes;
+        self.codes.resize(self.clusters.len(), vec![]);
+    }
+
+    /// Replace the codes of a cluster from a snapshot delta.
+    pub fn set_cluster_codes(
         &mut self,
-        id: &RecordID,
-        record: &Record,
-        records: &HashMap<RecordID, Record>,
+        cluster_id: ClusterIndex,
+        codes: Vec<u8>,
+    ) {
+        if self.code_size > 0 {
+            self.codes[cluster_id] = codes;
+        }
+    }
+
+    /// Encode the codes of all records and track all clusters as changed.
+    /// - code_size: Number of bytes of each code.
+    /// - encode: Function encoding the record into a code.
+    #[allow(clippy::result_large_err)]
+    pub fn encode_codes(
+        &mut self,
+        code_size: usize,
+        mut encode: impl FnMut(&RecordID) -> Result<Box<[u8]>, Status>,
     ) -> Result<(), Status> {
-        let vector = &record.vector;
-        let nearest_centroid = self.find_nearest_centroid(vector);
+        let mut codes = Vec::with_capacity(self.clusters.len());
+        for cluster in self.clusters.iter() {
+            let mut cluster_codes =
+                Vec::with_capacity(cluster.len() * code_size);
+            for id in cluster {
+                cluster_codes.extend_from_slice(&encode(id)?);
+            }
 
-        // If the index is empty, the record's vector will be
-        // the first centroid.
-        if nearest_centroid.is_none() {
-            let cluster_id = self.insert_centroid(vector);
-            self.clusters[cluster_id].push(*id);
-            return Ok(());
+            codes.push(cluster_codes);
         }
 
-        let nearest_centroid = nearest_centroid.unwrap();
-        if self.clusters[nearest_centroid].len() < self.density {
-            self.update_centroid(&nearest_centroid, vector);
-            self.clusters[nearest_centroid].push(*id);
-        } else {
-            // If the cluster is full, insert the record into the cluster
-            // and split the cluster with KMeans algorithm.
-            self.clusters[nearest_centroid].push(*id);
-            self.split_cluster(&nearest_centroid, records);
+        self.set_codes(code_size, codes);
+        let len = self.clusters.len();
+        self.changes.get_mut().unwrap().extend(0..len);
+        Ok(())
+    }
+
+    /// Create a copy of the index without the tracked changes.
+    pub fn copy(&self) -> Self {
+        IvfIndex {
+            centroids: self.centroids.clone(),
+            clusters: self.clusters.clone(),
+            locations: self.locations.clone(),
+            metric: self.metric,
+            density: self.density,
+            merge_threshold: self.merge_threshold,
+            changes: Mutex::new(HashSet::new()),
+            codes: self.codes.clone(),
+            code_size: self.code_size,
         }
+    }
 
-        Ok(())
+    /// Collect the delta of the changed clusters.
+    pub fn cluster_delta(&self, changes: &HashSet<ClusterIndex>) -> IvfDelta {
+        let len = self.clusters.len();
+        let mut changes = changes
+            .iter()
+            .filter(|&&cluster_id| cluster_id < len)
+            .copied()
+            .collect::<Vec<ClusterIndex>>();
+
+        // Sorting the clusters allows new clusters to be appended in order
+        // when the delta is applied.
+        changes.sort_unstable();
+
+        let clusters = changes
+            .into_iter()
+            .map(|i| (i, self.centroids[i].clone(), self.clusters[i].clone()))
+            .collect();
+
+        IvfDelta { len, clusters }
     }
 
-    /// Delete a record from the index by its ID.
+    /// Train a new empty index with the centroids fitted by KMeans.
     ///
-    /// This method will iterate over all the clusters and remove the record
-    /// from the cluster if it exists. This method doesn't update the value of
-    /// the cluster's centroid.
-    pub fn delete(&mut self, id: &RecordID) -> Result<(), Status> {
-        // Find the cluster and record indices where the record is stored.
-        let cluster_record_index =
-            self.clusters.iter().enumerate().find_map(|(i, cluster)| {
-                cluster.par_iter().position_first(|x| x == id).map(|x| (i, x))
-            });
-
-        if let Some((cluster_ix, record_ix)) = cluster_record_index {
-            // If the cluster has only one record, remove the cluster and
-            // centroid from the index. This won't happen often.
-            if self.clusters[cluster_ix].len() == 1 {
-                self.clusters.remove(cluster_ix);
-                self.centroids.remove(cluster_ix);
-            } else {
-                self.clusters[cluster_ix].remove(record_ix);
-            }
+    /// The new index has the same parameters as this index. The records
+    /// should be assigned to the new index with the assign method.
+    #[allow(clippy::result_large_err)]
+    pub fn train(
+        &self,
+        vectors: &[Vector],
+        n_clusters: usize,
+    ) -> Result<Self, Status> {
+        let mut index = self.empty();
+
+        let n_clusters = min(n_clusters, vectors.len());
+        if n_clusters == 0 {
+            return Ok(index);
         }
 
-        Ok(())
+        let vectors = vectors.iter().collect::<Vec<&Vector>>();
+        let mut kmeans = KMeans::new(n_clusters).with_metric(self.metric);
+        kmeans.fit(Rc::from(vectors)).map_err(|e| {
+            let message = format!("Failed to train the centroids: {e}");
+            Status::internal(message)
+        })?;
+
+        index.centroids = kmeans.centroids().to_vec();
+        index.clusters = vec![vec![]; n_clusters];
+        Ok(index)
     }
 
-    /// Search for the nearest neighbors of a given vector.
+    /// Apply the changes from a snapshot delta.
     ///
-    /// This method uses the IVF search algorithm to find the nearest neighbors
-    /// of the query vector. The filtering process of the search is done within
-    /// the boundaries of the nearest clusters to the query vector.
-    pub fn query(
-        &self,
-        vector: &Vector,
-        k: usize,
-        filters: &Filters,
-        params: &QueryParameters,
-        records: &HashMap<RecordID, Record>,
-    ) -> Result<Vec<QueryResult>, Status> {
-        let QueryParameters { probes, radius } = params.to_owned();
-        let probes = min(probes, self.centroids.len());
+    /// The record locations aren't included in the delta, so they're
+    /// recalculated for the changed clusters.
+    pub fn apply_delta(&mut self, delta: IvfDelta) {
+        // Records can move between the changed clusters, so the records of
+        // the replaced clusters are unlinked before the new ones are linked.
+        let changed = delta.clusters.iter().map(|(cluster_id, ..)| *cluster_id);
+        let removed = delta.len..self.clusters.len();
+        for cluster_id in changed.chain(removed) {
+            for id in self.clusters.get(cluster_id).into_iter().flatten() {
+                self.locations.remove(id);
+            }
+        }
 
-        let nearest_clusters = self.sort_nearest_centroids(vector);
-        let mut results = BinaryHeap::new();
+        self.centroids.truncate(delta.len);
+        self.clusters.truncate(delta.len);
 
-        for cluster_id in nearest_clusters.iter().take(probes) {
-            for record_id in &self.clusters[*cluster_id] {
-                let record = match records.get(record_id) {
-                    Some(record) => record,
-                    None => continue,
-                };
+        for (cluster_id, centroid, records) in delta.clusters {
+            if cluster_id < self.clusters.len() {
+                self.centroids[cluster_id] = centroid;
+                self.clusters[cluster_id] = records;
+            } else {
+                self.centroids.push(centroid);
+                self.clusters.push(records);
+            }
 
-                let distance = self.metric.distance(&record.vector, vector);
-                let distance = match distance {
-                    Some(distance) => distance as f32,
-                    None => continue,
-                };
+            self.relocate(cluster_id);
+        }
 
-                // Check if the record is within the search radius and
-                // the record's metadata passes the filters.
-                if distance > radius || !filters.apply(&record.metadata) {
-                    continue;
-                }
+        // The codes of the changed clusters are set by the IVF-PQ index.
+        if self.code_size > 0 {
+            self.codes.resize(delta.len, vec![]);
+        }
+    }
 
-                results.push(QueryResult {
-                    id: *record_id,
-                    metadata: record.metadata.clone(),
-                    distance,
-                });
+    /// Create an empty index with the same parameters.
+    fn empty(&self) -> Self {
+        IvfIndex::new()
+            .with_metric(self.metric)
+            .with_density(self.density)
+            .with_merge_threshold(self.merge_threshold)
+    }
 
-                if results.len() > k {
-                    results.pop();
-                }
-            }
+    fn track_change(&mut self, cluster_id: ClusterIndex) {
+        self.changes.get_mut().unwrap().insert(cluster_id);
+    }
+
+    /// Append a record with its code to a cluster and record its location.
+    fn push_record(
+        &mut self,
+        cluster_id: ClusterIndex,
+        id: &RecordID,
+        code: &[u8],
+    ) {
+        let slot = self.clusters[cluster_id].len();
+        self.clusters[cluster_id].push(*id);
+        self.locations.insert(*id, (cluster_id, slot));
+
+        if self.code_size > 0 {
+            self.codes[cluster_id].extend_from_slice(code);
         }
+    }
 
-        Ok(results.into_sorted_vec())
+    /// Return the code of the record in a slot of a cluster.
+    fn code(&self, cluster_id: ClusterIndex, slot: usize) -> &[u8] {
+        let start = slot * self.code_size;
+        let codes = self.codes.get(cluster_id).map(Vec::as_slice);
+        let code = codes.and_then(|codes| codes.get(start..));
+        code.map_or(&[], |code| &code[..self.code_size])
+    }
+
+    /// Record the locations of all records in a cluster.
+    ///
+    /// This must be called whenever the records of a cluster are replaced
+    /// or the cluster is moved to another index.
+    fn relocate(&mut self, cluster_id: ClusterIndex) {
+        for (slot, id) in self.clusters[cluster_id].iter().enumerate() {
+            self.locations.insert(*id, (cluster_id, slot));
+        }
     }
 
     /// Insert a new centroid and cluster into the index.
@@ -218,26 +629,87 @@ impl Index {
     fn insert_centroid(&mut self, vector: &Vector) -> ClusterIndex {
         self.centroids.push(vector.to_owned());
         self.clusters.push(vec![]);
-        self.centroids.len() - 1
+        if self.code_size > 0 {
+            self.codes.push(vec![]);
+        }
+
+        let cluster_id = self.centroids.len() - 1;
+        self.track_change(cluster_id);
+        cluster_id
     }
 
-    /// Recalculate the centroid of a cluster with the new vector.
+    /// Move the centroid of a cluster after a vector is added or removed.
     ///
-    /// This method must be called before inserting the new vector into the
-    /// cluster because this method calculates the new centroid by taking the
-    /// weighted average of the current centroid and adding the new vector
-    /// before normalizing the result with the new cluster size.
-    fn update_centroid(&mut self, cluster_id: &ClusterIndex, vector: &Vector) {
+    /// This method must be called after the record is added to or removed
+    /// from the cluster. The centroid is kept as the mean of the vectors by
+    /// shifting it towards or away from the vector, weighted by the new
+    /// cluster size.
+    fn shift_centroid(
+        &mut self,
+        cluster_id: &ClusterIndex,
+        vector: &Vector,
+        added: bool,
+    ) {
         let count = self.clusters[*cluster_id].len() as f32;
+        if count == 0.0 {
+            return;
+        }
+
+        let weight = if added { 1.0 / count } else { -1.0 / count };
         self.centroids[*cluster_id] = self.centroids[*cluster_id]
             .as_slice()
             .iter()
             .zip(vector.as_slice())
-            .map(|(a, b)| (a * count) + b / count + 1.0)
+            .map(|(a, b)| a + (b - a) * weight)
             .collect::<Vec<f32>>()
             .into();
     }
 
+    /// Remove a cluster and reassign its records to the nearest clusters.
+    ///
+    /// The removed cluster is replaced by the last cluster. The clusters
+    /// receiving the records are split if they become larger than the
+    /// density. If a record is missing from the storage, the centroid of
+    /// the removed cluster is used in place of its vector.
+    fn merge_cluster(
+        &mut self,
+        cluster_id: &ClusterIndex,
+        storage: &dyn Storage,
+    ) {
+        let record_ids = self.clusters.swap_remove(*cluster_id);
+        let centroid = self.centroids.swap_remove(*cluster_id);
+        let codes = match self.code_size {
+            0 => vec![],
+            _ => self.codes.swap_remove(*cluster_id),
+        };
+
+        self.track_change(*cluster_id);
+
+        // The last cluster is moved into the removed cluster's position.
+        if *cluster_id < self.clusters.len() {
+            self.relocate(*cluster_id);
+        }
+
+        let code_size = self.code_size.max(1);
+        let mut codes = codes.chunks(code_size);
+        for id in record_ids {
+            let record = storage.get(&id).ok();
+            let vector = record.as_ref().map_or(&centroid, |r| &r.vector);
+            let code = codes.next().unwrap_or_default();
+
+            // The index can't be empty here because merging only happens
+            // when there are other clusters.
+            let nearest_centroid = self.find_nearest_centroid(vector).unwrap();
+            self.push_record(nearest_centroid, &id, code);
+            self.shift_centroid(&nearest_centroid, vector, true);
+            self.track_change(nearest_centroid);
+
+            if self.clusters[nearest_centroid].len() > self.density {
+                self.split_cluster(&nearest_centroid, storage);
+            }
+        }
+    }
+
     /// Find the nearest centroid to a given vector.
     ///
     /// If the index is empty, this method will return None. Otherwise, it will
@@ -279,32 +751,367 @@ impl Index {
     /// The current cluster will be halved. The first half will be assigned to
     /// the current cluster, and the second half will be assigned to a new
     /// cluster with a new centroid.
+    ///
+    /// Records missing from the storage have no vector to split by, so they
+    /// are kept in the current cluster. If less than 2 records have a vector,
+    /// the cluster is left as it is.
     fn split_cluster(
         &mut self,
         cluster_id: &ClusterIndex,
-        records: &HashMap<RecordID, Record>,
+        storage: &dyn Storage,
     ) {
         let record_ids = &self.clusters[*cluster_id];
-        let vectors = record_ids
-            .iter()
-            .map(|id| &records.get(id).unwrap().vector)
-            .collect::<Vec<&Vector>>();
+        let records = storage.get_many(record_ids);
+
+        let mut ids = vec![];
+        let mut missing = vec![];
+        let mut vectors = vec![];
+        for (slot, (id, record)) in record_ids.iter().zip(&records).enumerate()
+        {
+            match record {
+                Some(record) => {
+                    ids.push((*id, slot));
+                    vectors.push(&record.vector);
+                }
+                None => missing.push((*id, slot)),
+            }
+        }
 
         let mut kmeans = KMeans::new(2).with_metric(self.metric);
-        kmeans.fit(Rc::from(vectors)).unwrap();
+        if kmeans.fit(Rc::from(vectors)).is_err() {
+            return;
+        }
 
         let centroids = kmeans.centroids();
         self.centroids[*cluster_id] = centroids[0].to_owned();
         self.centroids.push(centroids[1].to_owned());
 
-        let mut clusters = [vec![], vec![]];
+        let mut clusters = [missing, vec![]];
         let assignments = kmeans.assignments();
         for (i, cluster_id) in assignments.iter().enumerate() {
-            clusters[*cluster_id].push(record_ids[i]);
+            clusters[*cluster_id].push(ids[i]);
+        }
+
+        // The codes are moved along with the records to their new slots.
+        let [first, second] = &clusters;
+        if self.code_size > 0 {
+            let first_codes = self.collect_codes(*cluster_id, first);
+            let second_codes = self.collect_codes(*cluster_id, second);
+            self.codes[*cluster_id] = first_codes;
+            self.codes.push(second_codes);
+        }
+
+        self.clusters[*cluster_id] = first.iter().map(|(id, _)| *id).collect();
+        self.clusters.push(second.iter().map(|(id, _)| *id).collect());
+
+        let new_cluster_id = self.clusters.len() - 1;
+        self.relocate(*cluster_id);
+        self.relocate(new_cluster_id);
+
+        self.track_change(*cluster_id);
+        self.track_change(new_cluster_id);
+    }
+
+    /// Collect the codes of the records in the given slots of a cluster.
+    fn collect_codes(
+        &self,
+        cluster_id: ClusterIndex,
+        records: &[(RecordID, usize)],
+    ) -> Vec<u8> {
+        let mut codes = Vec::with_capacity(records.len() * self.code_size);
+        for (_, slot) in records {
+            codes.extend_from_slice(self.code(cluster_id, *slot));
+        }
+
+        codes
+    }
+
+    /// Insert a new record with its code into the index.
+    ///
+    /// The code is kept only if the code size is configured.
+    #[allow(clippy::result_large_err)]
+    pub fn insert_with_code(
+        &mut self,
+        id: &RecordID,
+        record: &Record,
+        code: &[u8],
+        storage: &dyn Storage,
+    ) -> Result<(), Status> {
+        let vector = &record.vector;
+        let nearest_centroid = self.find_nearest_centroid(vector);
+
+        // If the index is empty, the record's vector will be
+        // the first centroid.
+        if nearest_centroid.is_none() {
+            let cluster_id = self.insert_centroid(vector);
+            self.push_record(cluster_id, id, code);
+            return Ok(());
+        }
+
+        let nearest_centroid = nearest_centroid.unwrap();
+        self.track_change(nearest_centroid);
+
+        if self.clusters[nearest_centroid].len() < self.density {
+            self.push_record(nearest_centroid, id, code);
+            self.shift_centroid(&nearest_centroid, vector, true);
+        } else {
+            // If the cluster is full, insert the record into the cluster
+            // and split the cluster with KMeans algorithm.
+            self.push_record(nearest_centroid, id, code);
+            self.split_cluster(&nearest_centroid, storage);
+        }
+
+        Ok(())
+    }
+
+    /// Assign a record with its code to the nearest cluster.
+    ///
+    /// The code is kept only if the code size is configured.
+    pub fn assign_with_code(
+        &mut self,
+        id: &RecordID,
+        vector: &Vector,
+        code: &[u8],
+    ) {
+        let cluster_id = match self.find_nearest_centroid(vector) {
+            Some(cluster_id) => cluster_id,
+            None => self.insert_centroid(vector),
+        };
+
+        self.push_record(cluster_id, id, code);
+        self.track_change(cluster_id);
+    }
+}
+
+impl ClusteredIndex for IvfIndex {
+    fn assign(&mut self, id: &RecordID, vector: &Vector) {
+        self.assign_with_code(id, vector, &[]);
+    }
+
+    fn retain(&mut self, predicate: impl Fn(&RecordID) -> bool) {
+        for cluster_id in 0..self.clusters.len() {
+            if self.code_size > 0 {
+                let records = self.clusters[cluster_id]
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, id)| predicate(id))
+                    .map(|(slot, id)| (*id, slot))
+                    .collect::<Vec<(RecordID, usize)>>();
+
+                self.codes[cluster_id] =
+                    self.collect_codes(cluster_id, &records);
+            }
+
+            self.clusters[cluster_id].retain(&predicate);
+        }
+
+        let mut cluster_id = 0;
+        while cluster_id < self.clusters.len() {
+            if self.clusters[cluster_id].is_empty() {
+                self.clusters.swap_remove(cluster_id);
+                self.centroids.swap_remove(cluster_id);
+                if self.code_size > 0 {
+                    self.codes.swap_remove(cluster_id);
+                }
+            } else {
+                cluster_id += 1;
+            }
+        }
+
+        self.locations.clear();
+        let len = self.clusters.len();
+        for cluster_id in 0..len {
+            self.relocate(cluster_id);
         }
 
-        self.clusters[*cluster_id] = clusters[0].to_vec();
-        self.clusters.push(clusters[1].to_vec());
+        self.changes.get_mut().unwrap().extend(0..len);
+    }
+}
+
+impl Index for IvfIndex {
+    /// Insert a new record into the index.
+    ///
+    /// This method required the reference to the storage because during
+    /// the cluster splitting process, the record assignments will be
+    /// re-calculated
+    fn insert(
+        &mut self,
+        id: &RecordID,
+        record: &Record,
+        storage: &dyn Storage,
+    ) -> Result<(), Status> {
+        self.insert_with_code(id, record, &[], storage)
+    }
+
+    /// Delete a record from the index by its ID.
+    ///
+    /// This method looks up the location of the record and swap-removes it
+    /// from the cluster in constant time. The centroid of the cluster is
+    /// moved away from the deleted vector, so this method must be called
+    /// before the record is deleted from the storage.
+    ///
+    /// When a cluster falls below the merge threshold, its records are
+    /// reassigned to the nearest clusters and the cluster is removed.
+    fn delete(
+        &mut self,
+        id: &RecordID,
+        storage: &dyn Storage,
+    ) -> Result<(), Status> {
+        let (cluster_ix, record_ix) = match self.locations.remove(id) {
+            Some(location) => location,
+            None => return Ok(()),
+        };
+
+        // The last record of the cluster takes the slot of the deleted one.
+        let cluster = &mut self.clusters[cluster_ix];
+        cluster.swap_remove(record_ix);
+        if let Some(moved_id) = cluster.get(record_ix) {
+            self.locations.insert(*moved_id, (cluster_ix, record_ix));
+        }
+
+        // The code of the last record is moved along with it.
+        if self.code_size > 0 {
+            let codes = &mut self.codes[cluster_ix];
+            let last = codes.len() - self.code_size;
+            let start = record_ix * self.code_size;
+            codes.copy_within(last.., start);
+            codes.truncate(last);
+        }
+
+        self.track_change(cluster_ix);
+
+        if let Ok(record) = storage.get(id) {
+            self.shift_centroid(&cluster_ix, &record.vector, false);
+        }
+
+        let merge_size = (self.merge_threshold * self.density as f32) as usize;
+        let size = self.clusters[cluster_ix].len();
+        if size == 0 || (size < merge_size && self.clusters.len() > 1) {
+            self.merge_cluster(&cluster_ix, storage);
+        }
+
+        Ok(())
+    }
+
+    /// Search for the nearest neighbors of a given vector.
+    ///
+    /// This method uses the IVF search algorithm to find the nearest neighbors
+    /// of the query vector. The filtering process of the search is done within
+    /// the boundaries of the nearest clusters to the query vector.
+    fn query(
+        &self,
+        vector: &Vector,
+        k: usize,
+        filters: &Filters,
+        params: &QueryParameters,
+        storage: &dyn Storage,
+    ) -> Result<Vec<QueryResult>, Status> {
+        let QueryParameters { probes, radius, .. } = params.to_owned();
+        let mut results = BinaryHeap::new();
+
+        for record_ids in self.nearest_clusters(vector, probes) {
+            let records = storage.get_many(record_ids);
+            for (record_id, record) in record_ids.iter().zip(records) {
+                let record = match record {
+                    Some(record) => record,
+                    None => continue,
+                };
+
+                let distance = self.metric.distance(&record.vector, vector);
+                let distance = match distance {
+                    Some(distance) => distance as f32,
+                    None => continue,
+                };
+
+                // Check if the record is within the search radius and
+                // the record's metadata passes the filters.
+                if distance > radius || !filters.apply(&record.metadata) {
+                    continue;
+                }
+
+                results.push(QueryResult {
+                    id: *record_id,
+                    metadata: record.metadata.clone(),
+                    distance,
+                });
+
+                if results.len() > k {
+                    results.pop();
+                }
+            }
+        }
+
+        Ok(results.into_sorted_vec())
+    }
+
+    /// Rebuild the clusters from the records in the storage.
+    ///
+    /// The records are re-inserted one by one, so the clusters are split the
+    /// same way as during the regular insertions. All clusters are tracked
+    /// as changed for the next snapshot.
+    fn rebuild(&mut self, storage: &dyn Storage) -> Result<(), Status> {
+        let mut index = self.empty();
+
+        for (id, record) in storage.iter() {
+            index.insert(&id, &record, storage)?;
+        }
+
+        // The codes can't be encoded by the IVF index, so they're cleared
+        // and must be encoded again by the IVF-PQ index.
+        self.centroids = index.centroids;
+        self.clusters = index.clusters;
+        self.locations = index.locations;
+        self.codes = index.codes;
+        self.code_size = index.code_size;
+
+        let len = self.clusters.len();
+        self.changes.get_mut().unwrap().extend(0..len);
+        Ok(())
+    }
+
+    fn stats(&self) -> IndexStats {
+        IndexStats {
+            records: self.locations.len(),
+            cluster_sizes: self.cluster_sizes(),
+        }
+    }
+
+    fn ids(&self) -> Vec<RecordID> {
+        self.clusters.concat()
+    }
+
+    /// The tracked changes aren't included in the copy.
+    fn freeze(&self) -> Box<dyn Encode> {
+        Box::new(self.copy())
+    }
+
+    fn take_changes(&self) -> IndexChanges {
+        let changes = std::mem::take(&mut *self.changes.lock().unwrap());
+        IndexChanges::Clusters(changes)
+    }
+
+    fn restore_changes(&self, changes: IndexChanges) {
+        match changes {
+            IndexChanges::Clusters(changes) => {
+                self.changes.lock().unwrap().extend(changes)
+            }
+            _ => unreachable!("IVF index should track cluster changes"),
+        }
+    }
+
+    /// The delta contains the centroids and the records of the changed
+    /// clusters.
+    fn delta(&self, changes: &IndexChanges) -> Box<dyn Encode> {
+        let changes = match changes {
+            IndexChanges::Clusters(changes) => changes,
+            _ => unreachable!("IVF index should track cluster changes"),
+        };
+
+        Box::new(self.cluster_delta(changes))
+    }
+
+    fn set_merge_threshold(&mut self, merge_threshold: f32) {
+        self.merge_threshold = merge_threshold;
     }
 }
 
@@ -317,21 +1124,20 @@ mod tests {
         let params = Parameters::default();
         let mut index = setup_index(&params);
 
-        let mut records = HashMap::new();
+        let mut storage = setup_storage();
         for _ in 0..1000 {
             let id = RecordID::new();
             let record = Record::random(params.dimension);
-            records.insert(id, record);
-        }
-
-        for (id, record) in records.iter() {
-            index.insert(id, record, &records).unwrap();
+            storage.insert(&id, &record).unwrap();
+            index.insert(&id, &record, &storage).unwrap();
         }
 
         assert!(index.centroids.len() > 20);
+        assert_locations(&index);
     }
 
     #[test]
+    #[allow(clippy::needless_range_loop)]
     fn test_delete() {
         let params = Parameters::default();
         let mut index = setup_index(&params);
@@ -348,21 +1154,67 @@ mod tests {
 
             index.centroids.push(centroid);
             index.clusters.push(cluster);
+            index.relocate(index.clusters.len() - 1);
         }
 
         assert_eq!(ids.len(), 100);
         assert_eq!(index.centroids.len(), 10);
 
-        index.delete(&ids[0]).unwrap();
+        let storage = setup_storage();
+        index.delete(&ids[0], &storage).unwrap();
         for cluster in index.clusters.iter() {
             assert!(!cluster.contains(&ids[0]));
         }
 
         for i in 1..10 {
-            index.delete(&ids[i]).unwrap();
+            index.delete(&ids[i], &storage).unwrap();
         }
 
         assert_eq!(index.centroids.len(), 9);
+        assert_locations(&index);
+    }
+
+    #[test]
+    fn test_delete_merge() {
+        let params = Parameters::default();
+        let mut index = setup_index(&params).with_merge_threshold(0.25);
+
+        // Create 2 clusters of 20 records with the vectors of 0s and 10s.
+        let mut ids = vec![];
+        let mut storage = setup_storage();
+        for value in [0.0, 10.0] {
+            let vector = Vector::from(vec![value; params.dimension]);
+            let mut cluster = vec![];
+            for _ in 0..20 {
+                let id = RecordID::new();
+                let metadata = HashMap::new();
+                let record = Record { vector: vector.clone(), metadata };
+                storage.insert(&id, &record).unwrap();
+                cluster.push(id);
+            }
+
+            ids.extend(cluster.iter().copied());
+            index.centroids.push(vector);
+            index.clusters.push(cluster);
+            index.relocate(index.clusters.len() - 1);
+        }
+
+        // The first cluster is merged once it has less than 16 records.
+        for id in ids.iter().take(4) {
+            index.delete(id, &storage).unwrap();
+            storage.delete(id).unwrap();
+        }
+
+        assert_eq!(index.clusters.len(), 2);
+        index.delete(&ids[4], &storage).unwrap();
+        assert_eq!(index.cluster_sizes(), vec![35]);
+        assert_locations(&index);
+
+        // The centroid is the mean of the merged vectors.
+        let expected = 10.0 * 20.0 / 35.0;
+        for value in index.centroids[0].as_slice() {
+            assert!((value - expected).abs() < 1e-4);
+        }
     }
 
     #[test]
@@ -373,7 +1225,7 @@ mod tests {
         // Populate the index with 1000 sequential records.
         // This allows us to predict the order of the results.
         let mut ids = vec![];
-        let mut records = HashMap::new();
+        let mut storage = setup_storage();
         for i in 0..1000 {
             let id = RecordID::new();
             let vector = Vector::from(vec![i as f32; params.dimension]);
@@ -383,18 +1235,15 @@ mod tests {
             metadata.insert("number".to_string(), value);
 
             let record = Record { vector, metadata };
-            records.insert(id, record);
+            storage.insert(&id, &record).unwrap();
+            index.insert(&id, &record, &storage).unwrap();
             ids.push(id);
         }
 
-        for (id, record) in records.iter() {
-            index.insert(id, record, &records).unwrap();
-        }
-
         let query = Vector::from(vec![1.0; params.dimension]);
         let query_params = QueryParameters::default();
         let result = index
-            .query(&query, 10, &Filters::None, &query_params, &records)
+            .query(&query, 10, &Filters::None, &query_params, &storage)
             .unwrap();
 
         assert_eq!(result.len(), 10);
@@ -402,13 +1251,73 @@ mod tests {
 
         let metadata_filters = Filters::try_from("number > 1050").unwrap();
         let result = index
-            .query(&query, 10, &metadata_filters, &query_params, &records)
+            .query(&query, 10, &metadata_filters, &query_params, &storage)
             .unwrap();
 
         assert_eq!(result.len(), 10);
         assert!(result.iter().any(|r| r.id == ids[51]));
     }
 
+    #[test]
+    fn test_apply_delta() {
+        let params = Parameters::default();
+        let mut index = setup_index(&params);
+
+        let mut storage = setup_storage();
+        let mut insert = |index: &mut IvfIndex| {
+            let id = RecordID::new();
+            let record = Record::random(params.dimension);
+            storage.insert(&id, &record).unwrap();
+            index.insert(&id, &record, &storage).unwrap();
+            id
+        };
+
+        let mut ids: Vec<RecordID> =
+            (0..500).map(|_| insert(&mut index)).collect();
+
+        // Create a snapshot copy of the index.
+        let mut copy = setup_index(&params);
+        copy.apply_delta(collect_delta(&index));
+
+        ids.extend((0..100).map(|_| insert(&mut index)));
+        for id in ids.iter().step_by(3) {
+            index.delete(id, &storage).unwrap();
+        }
+
+        let delta = collect_delta(&index);
+        assert!(delta.clusters.len() <= index.clusters.len());
+
+        copy.apply_delta(delta);
+        assert_eq!(copy.centroids, index.centroids);
+        assert_eq!(copy.clusters, index.clusters);
+        assert_eq!(copy.locations, index.locations);
+        assert_locations(&index);
+    }
+
+    #[test]
+    fn test_train_and_assign() {
+        let params = Parameters::default();
+        let index = setup_index(&params);
+
+        let vectors: Vec<Vector> =
+            (0..100).map(|_| Vector::random(params.dimension)).collect();
+
+        let mut trained = index.train(&vectors, 10).unwrap();
+        assert_eq!(trained.centroids.len(), 10);
+        assert!(trained.clusters.iter().all(Vec::is_empty));
+
+        let ids: Vec<RecordID> = (0..100).map(|_| RecordID::new()).collect();
+        for (id, vector) in ids.iter().zip(vectors.iter()) {
+            trained.assign(id, vector);
+        }
+
+        trained.retain(|id| *id != ids[0]);
+        let sizes = trained.cluster_sizes();
+        assert_eq!(sizes.iter().sum::<usize>(), 99);
+        assert!(sizes.iter().all(|size| *size > 0));
+        assert_eq!(trained.changes.lock().unwrap().len(), sizes.len());
+    }
+
     #[test]
     fn test_insert_centroid() {
         let params = Parameters::default();
@@ -425,19 +1334,35 @@ mod tests {
     }
 
     #[test]
-    fn test_update_centroid() {
+    fn test_centroid_mean() {
         let params = Parameters::default();
-        let mut index = setup_index(&params);
+        let mut index = setup_index(&params).with_density(1000);
 
-        let initial_centroid = Vector::from(vec![0.0; params.dimension]);
-        let cluster_id = index.insert_centroid(&initial_centroid);
-        index.clusters[cluster_id].push(RecordID::new());
+        // Delete every record with a value of 4n + 2 between the inserts.
+        let mut ids = vec![];
+        let mut storage = setup_storage();
+        for i in 0..100 {
+            let id = RecordID::new();
+            let vector = Vector::from(vec![i as f32; params.dimension]);
+            let record = Record { vector, metadata: HashMap::new() };
+            storage.insert(&id, &record).unwrap();
+            index.insert(&id, &record, &storage).unwrap();
+            ids.push(id);
 
-        let vector = Vector::from(vec![1.0; params.dimension]);
-        index.update_centroid(&cluster_id, &vector);
+            if i % 4 == 3 {
+                index.delete(&ids[i - 1], &storage).unwrap();
+                storage.delete(&ids[i - 1]).unwrap();
+            }
+        }
 
-        let centroid = Vector::from(vec![0.5; params.dimension]);
-        assert_ne!(index.centroids[cluster_id], centroid);
+        let values: Vec<f32> =
+            (0..100).filter(|i| i % 4 != 2).map(|i| i as f32).collect();
+        let expected = values.iter().sum::<f32>() / values.len() as f32;
+
+        assert_eq!(index.centroids.len(), 1);
+        for value in index.centroids[0].as_slice() {
+            assert!((value - expected).abs() < 1e-3);
+        }
     }
 
     #[test]
@@ -469,22 +1394,95 @@ mod tests {
         let mut index = setup_index(&params);
 
         let mut ids = vec![];
-        let mut records = HashMap::new();
+        let mut storage = setup_storage();
         for i in 1..5 {
             let id = RecordID::new();
             let vector = Vector::from(vec![i as f32; params.dimension]);
             let record = Record { vector, metadata: HashMap::new() };
 
             ids.push(id);
-            records.insert(id, record);
+            storage.insert(&id, &record).unwrap();
         }
 
         let centroid = Vector::from(vec![2.5; params.dimension]);
         index.centroids.push(centroid);
         index.clusters.push(ids);
 
-        index.split_cluster(&0, &records);
+        index.split_cluster(&0, &storage);
+        assert_eq!(index.centroids.len(), 2);
+    }
+
+    #[test]
+    fn test_split_cluster_missing_record() {
+        let params = Parameters::default();
+        let mut index = setup_index(&params);
+
+        let mut ids = vec![];
+        let mut storage = setup_storage();
+        for i in 1..5 {
+            let id = RecordID::new();
+            let vector = Vector::from(vec![i as f32; params.dimension]);
+            let record = Record { vector, metadata: HashMap::new() };
+
+            ids.push(id);
+            storage.insert(&id, &record).unwrap();
+        }
+
+        // The first record is dangling because it's missing from the storage.
+        storage.delete(&ids[0]).unwrap();
+
+        let centroid = Vector::from(vec![2.5; params.dimension]);
+        index.centroids.push(centroid);
+        index.clusters.push(ids.clone());
+        index.relocate(0);
+
+        index.split_cluster(&0, &storage);
         assert_eq!(index.centroids.len(), 2);
+        assert!(index.clusters[0].contains(&ids[0]));
+        assert_eq!(index.cluster_sizes().iter().sum::<usize>(), 4);
+        assert_locations(&index);
+    }
+
+    #[test]
+    fn test_delete_merge_missing_record() {
+        let params = Parameters::default();
+        let mut index =
+            setup_index(&params).with_density(20).with_merge_threshold(0.8);
+
+        // Create 2 full clusters of 20 records with the vectors of 0s and 10s.
+        let mut ids = vec![];
+        let mut storage = setup_storage();
+        for value in [0.0, 10.0] {
+            let mut cluster = vec![];
+            for i in 0..20 {
+                let id = RecordID::new();
+                let vector =
+                    Vector::from(vec![value + i as f32; params.dimension]);
+                let record = Record { vector, metadata: HashMap::new() };
+                storage.insert(&id, &record).unwrap();
+                cluster.push(id);
+            }
+
+            let centroid = Vector::from(vec![value + 9.5; params.dimension]);
+            ids.extend(cluster.iter().copied());
+            index.centroids.push(centroid);
+            index.clusters.push(cluster);
+            index.relocate(index.clusters.len() - 1);
+        }
+
+        // Leave a dangling ID in both clusters.
+        storage.delete(&ids[5]).unwrap();
+        storage.delete(&ids[25]).unwrap();
+
+        // Merging the first cluster splits the second one.
+        for id in ids.iter().take(5) {
+            index.delete(id, &storage).unwrap();
+            storage.delete(id).unwrap();
+        }
+
+        assert_eq!(index.cluster_sizes().iter().sum::<usize>(), 35);
+        assert!(index.clusters.len() > 1);
+        assert_locations(&index);
     }
 
     #[test]
@@ -502,11 +1500,59 @@ mod tests {
         assert_eq!(nearest, vec![3, 2, 1, 0]);
     }
 
-    fn setup_index(params: &Parameters) -> Index {
-        let index = Index::new()
+    /// Assert that the locations point to every record in the clusters.
+    fn assert_locations(index: &IvfIndex) {
+        let count = index.cluster_sizes().iter().sum::<usize>();
+        assert_eq!(index.locations.len(), count);
+
+        for (cluster_id, cluster) in index.clusters.iter().enumerate() {
+            for (slot, id) in cluster.iter().enumerate() {
+                assert_eq!(index.locations[id], (cluster_id, slot));
+            }
+        }
+    }
+
+    #[test]
+    fn test_upgrade_legacy_index() {
+        let params = Parameters::default();
+        let mut index = setup_index(&params);
+
+        let mut storage = setup_storage();
+        for _ in 0..100 {
+            let id = RecordID::new();
+            let record = Record::random(params.dimension);
+            storage.insert(&id, &record).unwrap();
+            index.insert(&id, &record, &storage).unwrap();
+        }
+
+        // The legacy layout is the current layout without the locations.
+        let mut legacy = bincode::serialize(&index.centroids).unwrap();
+        legacy.extend(bincode::serialize(&index.clusters).unwrap());
+        legacy.extend(bincode::serialize(&index.metric).unwrap());
+        legacy.extend(bincode::serialize(&index.density).unwrap());
+
+        let legacy: LegacyIndex = bincode::deserialize(&legacy).unwrap();
+        let upgraded = IvfIndex::from(legacy);
+        assert_eq!(upgraded.clusters, index.clusters);
+        assert_eq!(upgraded.locations, index.locations);
+    }
+
+    fn collect_delta(index: &IvfIndex) -> IvfDelta {
+        let delta = index.delta(&index.take_changes());
+        let bytes = delta.encode(&FileOptions::default()).unwrap();
+        decode_file(&bytes, None).unwrap()
+    }
+
+    #[allow(clippy::let_and_return)]
+    fn setup_index(params: &Parameters) -> IvfIndex {
+        let index = IvfIndex::new()
             .with_metric(params.metric)
             .with_density(params.density);
 
         index
     }
+
+    fn setup_storage() -> MemoryStorage {
+        MemoryStorage::new()
+    }
 }
@@ -0,0 +1,735 @@
+use super::*;
+use rand::seq::SliceRandom;
+use serde::{Deserializer, Serializer};
+use simsimd::SpatialSimilarity;
+use std::cmp::min;
+use std::collections::BinaryHeap;
+use std::rc::Rc;
+
+/// Number of centroids in the codebook of each subspace.
+///
+/// This is the number of values of a byte, so each subspace of a vector is
+/// encoded into a single byte.
+const CODEBOOK_SIZE: usize = 256;
+
+/// Max number of vectors used to train the codebooks.
+const CODEBOOK_SAMPLE_SIZE: usize = CODEBOOK_SIZE * 16;
+
+/// Max number of KMeans iterations to train a codebook.
+const CODEBOOK_MAX_ITER: usize = 25;
+
+/// Product quantizer compressing vectors into short codes.
+///
+/// The vectors are split into equally sized subspaces and each subspace is
+/// replaced by the ID of its nearest centroid in the codebook of the
+/// subspace. With 4-byte floats, the vectors are compressed by 4 times the
+/// dimension of the subspaces.
+///
+/// The codes are compared with the squared Euclidean distance. For the
+/// cosine metric, the vectors are normalized first, which keeps the order
+/// of the distances.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProductQuantizer {
+    codebooks: Vec<Vec<Vector>>,
+    metric: Metric,
+}
+
+impl ProductQuantizer {
+    /// Train the codebooks of the subspaces with KMeans.
+    ///
+    /// The codebooks are trained on a random sample of the vectors if there
+    /// are too many of them. The dimension of the vectors must be divisible
+    /// by the number of subspaces.
+    #[allow(clippy::result_large_err)]
+    pub fn train(
+        vectors: &[Vector],
+        subspaces: usize,
+        metric: Metric,
+    ) -> Result<Self, Status> {
+        let mut quantizer = ProductQuantizer { codebooks: vec![], metric };
+
+        let mut rng = rand::thread_rng();
+        let sample = vectors.choose_multiple(&mut rng, CODEBOOK_SAMPLE_SIZE);
+        let sample = sample
+            .map(|vector| quantizer.normalize(vector))
+            .collect::<Vec<Vector>>();
+
+        let dimension = sample.first().map_or(0, Vector::len);
+        if subspaces == 0 || !dimension.is_multiple_of(subspaces) {
+            let message = format!(
+                "Invalid number of subspaces: {dimension} isn't divisible by \
+                {subspaces}"
+            );
+
+            return Err(Status::invalid_argument(message));
+        }
+
+        let n_codes = min(CODEBOOK_SIZE, sample.len());
+        let width = dimension / subspaces;
+        for subspace in 0..subspaces {
+            let range = subspace * width..(subspace + 1) * width;
+            let subvectors = sample
+                .iter()
+                .map(|vector| {
+                    Vector::from(vector.as_slice()[range.clone()].to_vec())
+                })
+                .collect::<Vec<Vector>>();
+
+            let subvectors = subvectors.iter().collect::<Vec<&Vector>>();
+            let mut kmeans =
+                KMeans::new(n_codes).with_max_iter(CODEBOOK_MAX_ITER);
+            kmeans.fit(Rc::from(subvectors)).map_err(|e| {
+                let message = format!("Failed to train the codebooks: {e}");
+                Status::internal(message)
+            })?;
+
+            quantizer.codebooks.push(kmeans.centroids().to_vec());
+        }
+
+        Ok(quantizer)
+    }
+
+    /// Encode a vector into the IDs of the nearest codebook centroids.
+    pub fn encode(&self, vector: &Vector) -> Box<[u8]> {
+        let table = self.distance_table(vector);
+        table
+            .0
+            .iter()
+            .map(|distances| {
+                let nearest = distances
+                    .iter()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .map(|(code, _)| code);
+                nearest.unwrap_or_default() as u8
+            })
+            .collect()
+    }
+
+    /// Precompute the distances between the query vector and the centroids
+    /// of each codebook.
+    ///
+    /// The distance to a code is then the sum of the distances looked up for
+    /// its subspaces, so the vectors don't need to be decoded.
+    pub fn distance_table(&self, vector: &Vector) -> DistanceTable {
+        let vector = self.normalize(vector);
+        let distances = self
+            .subvectors(&vector)
+            .zip(self.codebooks.iter())
+            .map(|(subvector, codebook)| {
+                codebook
+                    .iter()
+                    .map(|centroid| {
+                        subspace_distance(subvector, centroid.as_slice())
+                    })
+                    .collect()
+            })
+            .collect();
+
+        DistanceTable(distances)
+    }
+
+    /// Split a vector into the subvectors of the subspaces.
+    fn subvectors<'v>(
+        &self,
+        vector: &'v Vector,
+    ) -> impl Iterator<Item = &'v [f32]> {
+        let width = vector.len() / self.codebooks.len().max(1);
+        vector.as_slice().chunks(width.max(1))
+    }
+
+    /// Normalize the vector to a unit length for the cosine metric.
+    fn normalize(&self, vector: &Vector) -> Vector {
+        if self.metric != Metric::Cosine {
+            return vector.clone();
+        }
+
+        let norm = vector.as_slice().iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm == 0.0 {
+            return vector.clone();
+        }
+
+        let vector = vector.as_slice().iter().map(|x| x / norm);
+        Vector::from(vector.collect::<Vec<f32>>())
+    }
+}
+
+/// Distances between a query vector and the codebook centroids.
+#[derive(Debug)]
+pub struct DistanceTable(Vec<Vec<f32>>);
+
+impl DistanceTable {
+    /// Return the approximate distance between the query and a code.
+    pub fn distance(&self, code: &[u8]) -> f32 {
+        code.iter()
+            .zip(self.0.iter())
+            .map(|(code, distances)| distances[*code as usize])
+            .sum()
+    }
+}
+
+/// IVF-PQ index changes since the previous snapshot.
+///
+/// The quantizer is included as a whole because it's small compared to the
+/// codes and it changes whenever the index is retrained.
+///
+/// Fields:
+/// - clusters: Changes of the IVF clusters.
+/// - quantizer: Product quantizer of the index if it's trained.
+/// - codes: Codes of the changed clusters in the order of the clusters.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IvfPqDelta {
+    pub clusters: IvfDelta,
+    pub quantizer: Option<ProductQuantizer>,
+    pub codes: Vec<Vec<u8>>,
+}
+
+/// IVF index with product quantization.
+///
+/// The records are organized into the IVF clusters while their vectors are
+/// compressed by a product quantizer. The query ranks the records of the
+/// nearest clusters by the compressed vectors using a distance table, then
+/// re-ranks the top candidates with the full-precision vectors from the
+/// storage. With the mapped storage, the full-precision vectors stay on the
+/// disk and only the codes are kept in memory.
+///
+/// The codes of each cluster are stored contiguously in the slot order of
+/// the cluster records, so each record takes only the bytes of its code
+/// besides its ID.
+///
+/// The codebooks are trained along with the centroids when the index is
+/// retrained. Otherwise, they're trained on a sample of the records once
+/// there are enough records for a full sample. Only the codebooks are
+/// trained then, so the records stay in their clusters. Until then, the
+/// queries compare the full-precision vectors like the IVF index.
+#[derive(Debug)]
+pub struct IvfPqIndex {
+    ivf: IvfIndex,
+    subspaces: usize,
+    quantizer: Option<ProductQuantizer>,
+}
+
+// The codes are kept by the IVF index along with the cluster records, so
+// they're persisted after the rest of the index.
+impl Serialize for IvfPqIndex {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let codes = self.ivf.codes();
+        (&self.ivf, self.subspaces, &self.quantizer, codes)
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for IvfPqIndex {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let (mut ivf, subspaces, quantizer, codes): (
+            IvfIndex,
+            usize,
+            Option<ProductQuantizer>,
+            Vec<Vec<u8>>,
+        ) = Deserialize::deserialize(deserializer)?;
+
+        if quantizer.is_some() {
+            ivf.set_codes(subspaces, codes);
+        }
+
+        Ok(IvfPqIndex { ivf, subspaces, quantizer })
+    }
+}
+
+impl IvfPqIndex {
+    /// Create a new IVF-PQ index from the IVF index without a quantizer.
+    /// - subspaces: Number of subspaces of the vector codes.
+    pub fn new(ivf: IvfIndex, subspaces: usize) -> Self {
+        IvfPqIndex { ivf, subspaces, quantizer: None }
+    }
+
+    /// Train the codebooks of the quantizer on the vectors.
+    ///
+    /// The quantizer is only configured if there are vectors to train on.
+    /// The records should be assigned afterwards with the assign method.
+    #[allow(clippy::result_large_err)]
+    pub fn train(mut self, vectors: &[Vector]) -> Result<Self, Status> {
+        if vectors.is_empty() {
+            return Ok(self);
+        }
+
+        let metric = self.ivf.metric();
+        let quantizer =
+            ProductQuantizer::train(vectors, self.subspaces, metric)?;
+        self.quantizer = Some(quantizer);
+        self.ivf.set_codes(self.subspaces, vec![]);
+        Ok(self)
+    }
+
+    /// Train the codebooks once the index has enough records and encode
+    /// the records.
+    ///
+    /// The codebooks are trained on a random sample of the stored vectors.
+    /// The IVF centroids aren't changed.
+    #[allow(clippy::result_large_err)]
+    fn train_codebooks(&mut self, storage: &dyn Storage) -> Result<(), Status> {
+        if self.quantizer.is_some() || self.ivf.count() < CODEBOOK_SAMPLE_SIZE {
+            return Ok(());
+        }
+
+        let ids = self.ivf.ids();
+        let mut rng = rand::thread_rng();
+        let sample = ids
+            .choose_multiple(&mut rng, CODEBOOK_SAMPLE_SIZE)
+            .copied()
+            .collect::<Vec<RecordID>>();
+
+        let vectors = storage
+            .get_many(&sample)
+            .into_iter()
+            .flatten()
+            .map(|record| record.vector.clone())
+            .collect::<Vec<Vector>>();
+
+        let metric = self.ivf.metric();
+        let quantizer =
+            ProductQuantizer::train(&vectors, self.subspaces, metric)?;
+        self.quantizer = Some(quantizer);
+        self.encode_codes(storage)
+    }
+
+    /// Encode the codes of all records with the current quantizer.
+    #[allow(clippy::result_large_err)]
+    fn encode_codes(&mut self, storage: &dyn Storage) -> Result<(), Status> {
+        let quantizer = match &self.quantizer {
+            Some(quantizer) => quantizer,
+            None => return Ok(()),
+        };
+
+        self.ivf.encode_codes(self.subspaces, |id| {
+            let record = storage.get(id)?;
+            Ok(quantizer.encode(&record.vector))
+        })
+    }
+
+    /// Apply the changes from a snapshot delta.
+    ///
+    /// The codes of the changed clusters replace the current ones. Once the
+    /// codebooks are trained, the delta contains the codes of all clusters.
+    pub fn apply_delta(&mut self, delta: IvfPqDelta) {
+        let changed = delta.clusters.clusters.iter().map(|(id, ..)| *id);
+        let changed = changed.collect::<Vec<usize>>();
+        self.ivf.apply_delta(delta.clusters);
+
+        if self.quantizer.is_none() && delta.quantizer.is_some() {
+            self.ivf.set_codes(self.subspaces, vec![]);
+        }
+
+        for (cluster_id, codes) in changed.into_iter().zip(delta.codes) {
+            self.ivf.set_cluster_codes(cluster_id, codes);
+        }
+
+        self.quantizer = delta.quantizer;
+    }
+
+    /// Encode the vector of a record if the quantizer is trained.
+    fn encode_vector(&self, vector: &Vector) -> Box<[u8]> {
+        match &self.quantizer {
+            Some(quantizer) => quantizer.encode(vector),
+            None => Box::default(),
+        }
+    }
+}
+
+impl ClusteredIndex for IvfPqIndex {
+    fn assign(&mut self, id: &RecordID, vector: &Vector) {
+        let code = self.encode_vector(vector);
+        self.ivf.assign_with_code(id, vector, &code);
+    }
+
+    fn retain(&mut self, predicate: impl Fn(&RecordID) -> bool) {
+        self.ivf.retain(predicate);
+    }
+}
+
+impl Index for IvfPqIndex {
+    fn insert(
+        &mut self,
+        id: &RecordID,
+        record: &Record,
+        storage: &dyn Storage,
+    ) -> Result<(), Status> {
+        let code = self.encode_vector(&record.vector);
+        self.ivf.insert_with_code(id, record, &code, storage)?;
+        self.train_codebooks(storage)
+    }
+
+    fn delete(
+        &mut self,
+        id: &RecordID,
+        storage: &dyn Storage,
+    ) -> Result<(), Status> {
+        self.ivf.delete(id, storage)
+    }
+
+    /// The records of the nearest clusters are ranked by the codes and the
+    /// top candidates, at least k of them, are re-ranked with the exact
+    /// distances. The filters and the radius are applied while re-ranking,
+    /// so fewer than k results might be returned with restrictive filters
+    /// unless the number of re-ranked candidates is increased.
+    fn query(
+        &self,
+        vector: &Vector,
+        k: usize,
+        filters: &Filters,
+        params: &QueryParameters,
+        storage: &dyn Storage,
+    ) -> Result<Vec<QueryResult>, Status> {
+        let quantizer = match &self.quantizer {
+            Some(quantizer) => quantizer,
+            None => return self.ivf.query(vector, k, filters, params, storage),
+        };
+
+        let table = quantizer.distance_table(vector);
+        let rerank = params.rerank.max(k);
+
+        let mut candidates = BinaryHeap::new();
+        let clusters = self.ivf.nearest_codes(vector, params.probes);
+        for (record_ids, codes) in clusters {
+            let codes = codes.chunks_exact(self.subspaces);
+            for (id, code) in record_ids.iter().zip(codes) {
+                let distance = table.distance(code);
+                candidates.push(Candidate { id: *id, distance });
+                if candidates.len() > rerank {
+                    candidates.pop();
+                }
+            }
+        }
+
+        let ids: Vec<RecordID> = candidates.into_iter().map(|c| c.id).collect();
+        let metric = self.ivf.metric();
+        Ok(FlatIndex::scan(&ids, vector, k, filters, params, metric, storage))
+    }
+
+    /// The codes are encoded again with the current quantizer.
+    fn rebuild(&mut self, storage: &dyn Storage) -> Result<(), Status> {
+        self.ivf.rebuild(storage)?;
+        self.encode_codes(storage)?;
+        self.train_codebooks(storage)
+    }
+
+    fn stats(&self) -> IndexStats {
+        self.ivf.stats()
+    }
+
+    fn ids(&self) -> Vec<RecordID> {
+        self.ivf.ids()
+    }
+
+    /// The tracked changes aren't included in the copy.
+    fn freeze(&self) -> Box<dyn Encode> {
+        Box::new(IvfPqIndex {
+            ivf: self.ivf.copy(),
+            subspaces: self.subspaces,
+            quantizer: self.quantizer.clone(),
+        })
+    }
+
+    /// The codes only change along with the clusters of the records, so
+    /// the changes are tracked by the clusters.
+    fn take_changes(&self) -> IndexChanges {
+        self.ivf.take_changes()
+    }
+
+    fn restore_changes(&self, changes: IndexChanges) {
+        self.ivf.restore_changes(changes);
+    }
+
+    fn delta(&self, changes: &IndexChanges) -> Box<dyn Encode> {
+        let changes = match changes {
+            IndexChanges::Clusters(changes) => changes,
+            _ => unreachable!("IVF-PQ index should track cluster changes"),
+        };
+
+        let clusters = self.ivf.cluster_delta(changes);
+        let codes = self.ivf.codes();
+        let codes = clusters
+            .clusters
+            .iter()
+            .map(|(cluster_id, ..)| {
+                codes.get(*cluster_id).cloned().unwrap_or_default()
+            })
+            .collect();
+
+        Box::new(IvfPqDelta {
+            clusters,
+            quantizer: self.quantizer.clone(),
+            codes,
+        })
+    }
+
+    fn set_merge_threshold(&mut self, merge_threshold: f32) {
+        self.ivf.set_merge_threshold(merge_threshold);
+    }
+}
+
+/// Calculate the squared Euclidean distance between two subvectors.
+fn subspace_distance(a: &[f32], b: &[f32]) -> f32 {
+    f32::sqeuclidean(a, b).map_or(f32::INFINITY, |distance| distance as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let vectors: Vec<Vector> =
+            (0..300).map(|_| Vector::random(32)).collect();
+        let quantizer =
+            ProductQuantizer::train(&vectors, 8, Metric::Euclidean).unwrap();
+
+        // The codes are closer to their own vector than to the others.
+        let code = quantizer.encode(&vectors[0]);
+        let table = quantizer.distance_table(&vectors[0]);
+        assert_eq!(code.len(), 8);
+        for vector in vectors.iter().skip(1) {
+            let other = quantizer.encode(vector);
+            assert!(table.distance(&code) <= table.distance(&other));
+        }
+
+        let result = ProductQuantizer::train(&vectors, 5, Metric::Euclidean);
+        assert_eq!(result.unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[test]
+    fn test_codebook_quality() {
+        let vectors: Vec<Vector> =
+            (0..1000).map(|_| Vector::random(32)).collect();
+        let quantizer =
+            ProductQuantizer::train(&vectors, 8, Metric::Euclidean).unwrap();
+
+        // The distance between a vector and its own code is the squared
+        // quantization error of the vector. The error is measured on the
+        // vectors that the codebooks weren't trained on.
+        let vectors: Vec<Vector> =
+            (0..200).map(|_| Vector::random(32)).collect();
+        let error: f32 = vectors
+            .iter()
+            .map(|vector| {
+                let table = quantizer.distance_table(vector);
+                table.distance(&quantizer.encode(vector))
+            })
+            .sum();
+
+        // The values of the random vectors are uniform between 0 and 1.
+        let mean = 0.5;
+        let variance: f32 = vectors
+            .iter()
+            .flat_map(|vector| vector.as_slice().iter())
+            .map(|x| (x - mean) * (x - mean))
+            .sum();
+
+        let ratio = error / variance;
+        assert!(ratio < 0.15, "Quantization error is too high: {ratio}");
+    }
+
+    #[test]
+    fn test_train_codebooks() {
+        let mut storage = MemoryStorage::new();
+        let ivf = IvfIndex::new().with_density(CODEBOOK_SAMPLE_SIZE * 2);
+        let mut index = IvfPqIndex::new(ivf, 2);
+
+        let mut insert = |index: &mut IvfPqIndex| {
+            let id = RecordID::new();
+            let record = Record::random(8);
+            storage.insert(&id, &record).unwrap();
+            index.insert(&id, &record, &storage).unwrap();
+        };
+
+        for _ in 1..CODEBOOK_SAMPLE_SIZE {
+            insert(&mut index);
+        }
+
+        assert!(index.quantizer.is_none());
+        assert!(index.ivf.codes().is_empty());
+        let clusters = index.stats().cluster_sizes.len();
+
+        let mut copy = IvfPqIndex::new(IvfIndex::new(), 2);
+        copy.apply_delta(collect_delta(&index));
+
+        // The codebooks are trained once there are enough records for
+        // a full sample while the records stay in their clusters.
+        insert(&mut index);
+        assert!(index.quantizer.is_some());
+        assert_eq!(code_bytes(&index), CODEBOOK_SAMPLE_SIZE * 2);
+        assert_eq!(index.stats().cluster_sizes.len(), clusters);
+
+        // The codes of all clusters are included in the next delta.
+        copy.apply_delta(collect_delta(&index));
+        assert_eq!(copy.ivf.codes(), index.ivf.codes());
+    }
+
+    #[test]
+    fn test_codes_follow_records() {
+        let (mut index, mut storage, ids) = setup_index(200);
+        index.set_merge_threshold(0.5);
+
+        // The inserts split the clusters and the deletes merge them.
+        for _ in 0..300 {
+            let id = RecordID::new();
+            let record = Record::random(32);
+            storage.insert(&id, &record).unwrap();
+            index.insert(&id, &record, &storage).unwrap();
+        }
+
+        for id in ids.iter().step_by(2) {
+            index.delete(id, &storage).unwrap();
+            storage.delete(id).unwrap();
+        }
+
+        let removed: HashSet<RecordID> =
+            ids.iter().skip(1).step_by(4).copied().collect();
+        index.retain(|id| !removed.contains(id));
+
+        // Each code is in the slot of the record it was encoded from.
+        let quantizer = index.quantizer.as_ref().unwrap();
+        let vector = Vector::random(32);
+        let clusters = index.ivf.nearest_codes(&vector, usize::MAX);
+        for (record_ids, codes) in clusters {
+            assert_eq!(codes.len(), record_ids.len() * 8);
+            for (id, code) in record_ids.iter().zip(codes.chunks_exact(8)) {
+                let vector = &storage.get(id).unwrap().vector;
+                assert_eq!(*quantizer.encode(vector), *code);
+            }
+        }
+
+        assert_eq!(code_bytes(&index), index.stats().records * 8);
+    }
+
+    #[test]
+    fn test_code_footprint() {
+        let (mut index, storage, ids) = setup_index(500);
+        for id in ids.iter().step_by(5) {
+            index.delete(id, &storage).unwrap();
+        }
+
+        // Each record takes exactly the bytes of its code. Including the
+        // spare capacity and the buffer of each cluster, it stays within
+        // a few times the code size.
+        let records = index.stats().records;
+        assert_eq!(code_bytes(&index), records * 8);
+
+        let codes = index.ivf.codes();
+        let capacity: usize = codes.iter().map(Vec::capacity).sum();
+        let buffers = std::mem::size_of_val(codes);
+        let footprint = (capacity + buffers) / records;
+        assert!(footprint < 8 * 3, "Footprint is too large: {footprint}");
+    }
+
+    #[test]
+    fn test_query() {
+        let (index, storage, ids) = setup_index(500);
+
+        let vector = storage.get(&ids[0]).unwrap().vector.clone();
+        let params = QueryParameters { rerank: 50, ..Default::default() };
+        let results = index
+            .query(&vector, 10, &Filters::None, &params, &storage)
+            .unwrap();
+
+        let exact = FlatIndex::scan(
+            &ids,
+            &vector,
+            10,
+            &Filters::None,
+            &params,
+            Metric::Euclidean,
+            &storage,
+        );
+
+        let exact: HashSet<RecordID> = exact.iter().map(|r| r.id).collect();
+        let found = results.iter().filter(|r| exact.contains(&r.id)).count();
+        assert_eq!(results[0].id, ids[0]);
+        assert!(found >= 8, "Recall is too low: {found}/10");
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        let (mut index, mut storage, ids) = setup_index(200);
+
+        // Create a snapshot copy of the index.
+        let mut copy = IvfPqIndex::new(IvfIndex::new(), 8);
+        copy.apply_delta(collect_delta(&index));
+        assert_eq!(copy.ivf.codes(), index.ivf.codes());
+
+        for _ in 0..50 {
+            let id = RecordID::new();
+            let record = Record::random(32);
+            storage.insert(&id, &record).unwrap();
+            index.insert(&id, &record, &storage).unwrap();
+        }
+
+        for id in ids.iter().step_by(3) {
+            index.delete(id, &storage).unwrap();
+            storage.delete(id).unwrap();
+        }
+
+        copy.apply_delta(collect_delta(&index));
+        assert_eq!(copy.ids(), index.ids());
+        assert_eq!(copy.ivf.codes(), index.ivf.codes());
+        assert_eq!(code_bytes(&copy), storage.count() * 8);
+    }
+
+    #[test]
+    fn test_serialize() {
+        let (index, storage, ids) = setup_index(200);
+        let bytes = index.freeze().encode(&FileOptions::default()).unwrap();
+        let copy: IvfPqIndex = decode_file(&bytes, None).unwrap();
+        assert_eq!(copy.ivf.codes(), index.ivf.codes());
+
+        let vector = storage.get(&ids[0]).unwrap().vector.clone();
+        let params = QueryParameters::default();
+        let query = |index: &IvfPqIndex| {
+            let results = index
+                .query(&vector, 10, &Filters::None, &params, &storage)
+                .unwrap();
+            results.into_iter().map(|r| r.id).collect::<Vec<RecordID>>()
+        };
+
+        assert_eq!(query(&copy), query(&index));
+    }
+
+    /// Count the bytes of the codes in the clusters.
+    fn code_bytes(index: &IvfPqIndex) -> usize {
+        index.ivf.codes().iter().map(Vec::len).sum()
+    }
+
+    fn collect_delta(index: &IvfPqIndex) -> IvfPqDelta {
+        let delta = index.delta(&index.take_changes());
+        let bytes = delta.encode(&FileOptions::default()).unwrap();
+        decode_file(&bytes, None).unwrap()
+    }
+
+    /// Create a trained index with random records of 32 dimensions.
+    fn setup_index(count: usize) -> (IvfPqIndex, MemoryStorage, Vec<RecordID>) {
+        let mut storage = MemoryStorage::new();
+        let mut ids = vec![];
+        for _ in 0..count {
+            let id = RecordID::new();
+            storage.insert(&id, &Record::random(32)).unwrap();
+            ids.push(id);
+        }
+
+        let vectors: Vec<Vector> =
+            storage.iter().map(|(_, record)| record.vector.clone()).collect();
+
+        let ivf = IvfIndex::new().with_density(64);
+        let ivf = ivf.train(&vectors, count / 32).unwrap();
+        let mut index = IvfPqIndex::new(ivf, 8).train(&vectors).unwrap();
+        for (id, record) in storage.iter() {
+            index.assign(&id, &record.vector);
+        }
+
+        (index, storage, ids)
+    }
+}
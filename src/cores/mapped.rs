@@ -0,0 +1,364 @@
+use super::*;
+use std::borrow::Cow;
+
+/// Record stored in the memory-mapped storage.
+///
+/// Fields:
+/// - slot: Slot of the vector in the segment files.
+/// - metadata: Record metadata.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MappedRecord {
+    pub slot: u64,
+    pub metadata: HashMap<String, Value>,
+}
+
+/// Allocation state of the vector slots.
+///
+/// Fields:
+/// - next: Next slot that has never been allocated.
+/// - free: Slots that can be reused.
+/// - released: Slots released since the previous snapshot.
+/// - pending: Slots released before each retained snapshot, oldest first.
+#[derive(Debug, Default)]
+struct Slots {
+    next: u64,
+    free: Vec<u64>,
+    released: Vec<u64>,
+    pending: Vec<Vec<u64>>,
+}
+
+/// Record storage with memory-mapped vectors.
+///
+/// Only the record metadata and the vector slots are kept in memory and
+/// persisted in the snapshots. The vectors are stored in the memory-mapped
+/// segment files and read on demand, so the snapshots stay small and the
+/// database opens quickly regardless of the dataset size.
+///
+/// The segment files are modified in place. A slot released by a deletion
+/// can still be referenced by the retained snapshots, so it's only reused
+/// after all of them are superseded by snapshots without the deleted record.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MappedStorage {
+    count: usize,
+    records: HashMap<RecordID, Arc<MappedRecord>>,
+
+    #[serde(skip)]
+    vectors: Option<VectorSegments>,
+
+    #[serde(skip)]
+    slots: Mutex<Slots>,
+
+    #[serde(skip)]
+    changes: Mutex<HashSet<RecordID>>,
+}
+
+impl MappedStorage {
+    /// Create a new empty storage instance.
+    pub fn new() -> Self {
+        MappedStorage::default()
+    }
+
+    /// Attach the vector segments and rebuild the slot allocation.
+    ///
+    /// The reserved slots are referenced by the older retained snapshots
+    /// and are excluded from the free slots along with the used ones.
+    ///
+    /// This method must be called after the storage is loaded from the
+    /// snapshots and before any operation is applied.
+    pub fn attach(&mut self, vectors: VectorSegments, reserved: &HashSet<u64>) {
+        let mut used = self.used_slots();
+        used.extend(reserved);
+
+        let next = used.iter().max().map_or(0, |slot| slot + 1);
+        let free = (0..next).rev().filter(|slot| !used.contains(slot));
+
+        let slots = self.slots.get_mut().unwrap();
+        *slots = Slots { next, free: free.collect(), ..Default::default() };
+        self.vectors = Some(vectors);
+    }
+
+    /// Apply the changes from a snapshot delta.
+    pub fn apply_delta(&mut self, delta: RecordDelta<MappedRecord>) {
+        for id in delta.deletes.iter() {
+            self.records.remove(id);
+        }
+
+        self.records.extend(delta.upserts);
+        self.count = delta.count;
+    }
+
+    /// Return the slots referenced by the stored records.
+    pub fn used_slots(&self) -> HashSet<u64> {
+        self.records.values().map(|record| record.slot).collect()
+    }
+
+    /// Read the vector of a stored record from the segment files.
+    #[allow(clippy::result_large_err)]
+    fn read(&self, record: &MappedRecord) -> Result<Record, Status> {
+        let vectors = self.vectors.as_ref().ok_or_else(detached)?;
+        let vector = vectors.read(record.slot).ok_or_else(|| {
+            let message = "The vector of the record is missing";
+            Status::data_loss(message)
+        })?;
+
+        let metadata = record.metadata.clone();
+        Ok(Record { vector, metadata })
+    }
+
+    fn track_change(&mut self, id: &RecordID) {
+        self.changes.get_mut().unwrap().insert(*id);
+    }
+}
+
+impl Storage for MappedStorage {
+    fn insert(&mut self, id: &RecordID, record: &Record) -> Result<(), Status> {
+        let slots = self.slots.get_mut().unwrap();
+        let slot = match slots.free.pop() {
+            Some(slot) => slot,
+            None => {
+                slots.next += 1;
+                slots.next - 1
+            }
+        };
+
+        let vectors = self.vectors.as_mut().ok_or_else(detached)?;
+        if let Err(e) = vectors.write(slot, &record.vector) {
+            self.slots.get_mut().unwrap().free.push(slot);
+            let message = format!("Failed to write the vector: {e}");
+            return Err(Status::internal(message));
+        }
+
+        let metadata = record.metadata.to_owned();
+        self.records.insert(*id, Arc::new(MappedRecord { slot, metadata }));
+        self.count += 1;
+        self.track_change(id);
+        Ok(())
+    }
+
+    /// The vector is copied from the segment files.
+    fn get(&self, id: &RecordID) -> Result<Cow<'_, Record>, Status> {
+        match self.records.get(id) {
+            Some(record) => self.read(record).map(Cow::Owned),
+            None => {
+                let message = "The specified record is not found";
+                Err(Status::not_found(message))
+            }
+        }
+    }
+
+    fn delete(&mut self, id: &RecordID) -> Result<(), Status> {
+        if let Some(record) = self.records.remove(id) {
+            self.slots.get_mut().unwrap().released.push(record.slot);
+            self.count -= 1;
+            self.track_change(id);
+        }
+
+        Ok(())
+    }
+
+    fn update(
+        &mut self,
+        id: &RecordID,
+        metadata: &HashMap<String, Value>,
+    ) -> Result<(), Status> {
+        let record = match self.records.get_mut(id) {
+            Some(record) => record,
+            None => {
+                let message = "The specified record is not found";
+                return Err(Status::not_found(message));
+            }
+        };
+
+        Arc::make_mut(record).metadata = metadata.to_owned();
+        self.track_change(id);
+        Ok(())
+    }
+
+    fn count(&self) -> usize {
+        self.count
+    }
+
+    fn ids(&self) -> Vec<RecordID> {
+        self.records.keys().copied().collect()
+    }
+
+    fn recount(&mut self) {
+        self.count = self.records.len();
+    }
+
+    /// Records with unreadable vectors are skipped.
+    fn iter(&self) -> RecordIter<'_> {
+        let records = self.records.iter().filter_map(|(id, record)| {
+            let record = self.read(record).ok()?;
+            Some((*id, Cow::Owned(record)))
+        });
+
+        Box::new(records)
+    }
+
+    /// Only the records are copied. The vector segments aren't included
+    /// because the snapshots don't contain the vectors.
+    fn freeze(&self) -> Box<dyn Encode> {
+        Box::new(MappedStorage {
+            count: self.count,
+            records: self.records.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// The slots released so far become pending until the snapshots that
+    /// reference them are no longer retained.
+    fn take_changes(&self) -> HashSet<RecordID> {
+        let mut slots = self.slots.lock().unwrap();
+        let released = std::mem::take(&mut slots.released);
+        slots.pending.push(released);
+        std::mem::take(&mut *self.changes.lock().unwrap())
+    }
+
+    fn restore_changes(&self, changes: HashSet<RecordID>) {
+        let mut slots = self.slots.lock().unwrap();
+        let pending = slots.pending.pop().unwrap_or_default();
+        slots.released.extend(pending);
+        self.changes.lock().unwrap().extend(changes);
+    }
+
+    fn delta(&self, changes: &HashSet<RecordID>) -> Box<dyn Encode> {
+        let mut delta: RecordDelta<MappedRecord> = RecordDelta::new(self.count);
+        for id in changes.iter() {
+            match self.records.get(id) {
+                Some(record) => {
+                    delta.upserts.insert(*id, record.clone());
+                }
+                None => delta.deletes.push(*id),
+            }
+        }
+
+        Box::new(delta)
+    }
+
+    /// Make the pending slots reusable once the oldest retained snapshot
+    /// was committed after they were released.
+    fn recycle(&self, retention: usize) {
+        let mut slots = self.slots.lock().unwrap();
+        while !slots.pending.is_empty() && slots.pending.len() >= retention {
+            let pending = slots.pending.remove(0);
+            slots.free.extend(pending);
+        }
+    }
+
+    /// The returned segments share the mappings with the storage.
+    fn segments(&self) -> Option<VectorSegments> {
+        self.vectors.clone()
+    }
+}
+
+fn detached() -> Status {
+    Status::internal("Vector segments are not attached to the storage")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_get() {
+        let mut storage = setup_storage("insert_and_get");
+
+        let record = Record::random(128);
+        let id = RecordID::new();
+        storage.insert(&id, &record).unwrap();
+
+        assert_eq!(storage.count, 1);
+        assert_eq!(storage.get(&id).unwrap().into_owned(), record);
+    }
+
+    #[test]
+    fn test_delete_missing() {
+        let mut storage = setup_storage("delete_missing");
+        storage.insert(&RecordID::new(), &Record::random(128)).unwrap();
+
+        storage.delete(&RecordID::new()).unwrap();
+        assert_eq!(storage.count, 1);
+        assert_eq!(storage.take_changes().len(), 1);
+        assert!(storage.slots.get_mut().unwrap().released.is_empty());
+    }
+
+    #[test]
+    fn test_reuse_slots() {
+        let mut storage = setup_storage("reuse_slots");
+
+        let id = RecordID::new();
+        storage.insert(&id, &Record::random(128)).unwrap();
+        storage.delete(&id).unwrap();
+
+        // The released slot must not be reused before the snapshot.
+        let id = RecordID::new();
+        storage.insert(&id, &Record::random(128)).unwrap();
+        assert_eq!(storage.records.get(&id).unwrap().slot, 1);
+
+        storage.take_changes();
+        storage.recycle(1);
+
+        let id = RecordID::new();
+        storage.insert(&id, &Record::random(128)).unwrap();
+        assert_eq!(storage.records.get(&id).unwrap().slot, 0);
+    }
+
+    #[test]
+    fn test_reuse_slots_with_retention() {
+        let mut storage = setup_storage("reuse_slots_with_retention");
+
+        let id = RecordID::new();
+        storage.insert(&id, &Record::random(128)).unwrap();
+        storage.delete(&id).unwrap();
+
+        // The previous snapshot still references the released slot.
+        storage.take_changes();
+        storage.recycle(2);
+        assert!(storage.slots.get_mut().unwrap().free.is_empty());
+
+        storage.take_changes();
+        storage.recycle(2);
+        assert_eq!(storage.slots.get_mut().unwrap().free, vec![0]);
+    }
+
+    #[test]
+    fn test_attach() {
+        let mut storage = setup_storage("attach");
+
+        let ids: Vec<RecordID> = (0..3).map(|_| RecordID::new()).collect();
+        for id in ids.iter() {
+            storage.insert(id, &Record::random(128)).unwrap();
+        }
+
+        storage.delete(&ids[1]).unwrap();
+
+        // Reload the storage from a snapshot copy.
+        let delta = storage.delta(&storage.take_changes());
+        let mut copy = MappedStorage::new();
+        let bytes = delta.encode(&FileOptions::default()).unwrap();
+        copy.apply_delta(decode_file(&bytes, None).unwrap());
+        copy.attach(storage.segments().unwrap(), &HashSet::new());
+
+        assert_eq!(copy.get(&ids[0]).unwrap(), storage.get(&ids[0]).unwrap());
+        assert_eq!(copy.slots.get_mut().unwrap().free, vec![1]);
+        assert_eq!(copy.slots.get_mut().unwrap().next, 3);
+
+        // Slots referenced by the older snapshots must not be reused.
+        let reserved = HashSet::from([1]);
+        copy.attach(storage.segments().unwrap(), &reserved);
+        assert!(copy.slots.get_mut().unwrap().free.is_empty());
+    }
+
+    fn setup_storage(name: &str) -> MappedStorage {
+        let dir = env::temp_dir().join("oasysdb_mapped_tests").join(name);
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        let mut storage = MappedStorage::new();
+        let vectors = VectorSegments::open(dir, 128).unwrap();
+        storage.attach(vectors, &HashSet::new());
+        storage
+    }
+}
@@ -1,24 +1,49 @@
 // Initialize the modules without making them public.
+mod archive;
 mod database;
+mod encryption;
+mod export;
+mod flat;
+mod format;
+mod hnsw;
+mod import;
 mod index;
+mod ivfpq;
+mod mapped;
+mod segment;
+mod snapshot;
 mod storage;
+mod wal;
 
 // Re-export types from the modules.
+pub use archive::*;
 pub use database::*;
+pub use encryption::*;
+pub use export::*;
+pub use flat::*;
+pub use format::*;
+pub use hnsw::*;
+pub use import::*;
 pub use index::*;
+pub use ivfpq::*;
+pub use mapped::*;
+pub use segment::*;
+pub use snapshot::*;
 pub use storage::*;
+pub use wal::*;
 
 // Import common dependencies below.
 use crate::protos;
 use crate::types::*;
+use crate::utils::files;
 use crate::utils::kmeans::KMeans;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use rayon::prelude::*;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fs::OpenOptions;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::{env, fs};
 use tonic::Status;
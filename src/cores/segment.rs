@@ -0,0 +1,241 @@
+use super::*;
+use memmap2::MmapRaw;
+use std::mem::size_of;
+use std::ptr;
+
+/// Number of vector slots in each segment file.
+const SEGMENT_CAPACITY: usize = 16384;
+
+/// Memory-mapped vector segment files.
+///
+/// Vectors are stored in fixed-stride slots across segment files of the
+/// same capacity. The slot of a vector determines its segment and offset,
+/// so the vectors are read directly from the mapped memory. The operating
+/// system pages the vectors in and out as needed, which allows datasets
+/// larger than the memory to be served.
+///
+/// The segment files contain raw little-endian floats without a header and
+/// are modified in place. Cloning the segments shares the mappings which
+/// allows the snapshot process to flush them without locking the storage.
+#[derive(Debug, Clone)]
+pub struct VectorSegments {
+    dir: PathBuf,
+    dimension: usize,
+    segments: Vec<Arc<MmapRaw>>,
+}
+
+impl VectorSegments {
+    /// Open the segment files in the directory, creating it if needed.
+    pub fn open(
+        dir: impl AsRef<Path>,
+        dimension: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut segments = VectorSegments { dir, dimension, segments: vec![] };
+        while segments.segment_path(segments.segments.len()).try_exists()? {
+            segments.map_segment()?;
+        }
+
+        Ok(segments)
+    }
+
+    /// Write a vector into a slot, creating the segment if needed.
+    pub fn write(
+        &mut self,
+        slot: u64,
+        vector: &Vector,
+    ) -> Result<(), Box<dyn Error>> {
+        if vector.len() != self.dimension {
+            return Err("Vector dimension doesn't match the segments".into());
+        }
+
+        let (segment, offset) = self.locate(slot);
+        while self.segments.len() <= segment {
+            self.map_segment()?;
+        }
+
+        let bytes = vector
+            .as_slice()
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect::<Vec<u8>>();
+
+        // SAFETY: The slot is within the mapped segment. Writers hold the
+        // storage write lock, and the slots referenced by the snapshots are
+        // never rewritten while they're being read.
+        unsafe {
+            let dst = self.segments[segment].as_mut_ptr().add(offset);
+            ptr::copy_nonoverlapping(bytes.as_ptr(), dst, bytes.len());
+        }
+
+        Ok(())
+    }
+
+    /// Read the vector stored in a slot.
+    ///
+    /// Returns None if the segment of the slot doesn't exist.
+    pub fn read(&self, slot: u64) -> Option<Vector> {
+        let (segment, offset) = self.locate(slot);
+        let segment = self.segments.get(segment)?;
+
+        // SAFETY: The slot is within the mapped segment and the mapping
+        // stays alive as long as the reference to the segment.
+        let bytes = unsafe {
+            let src = segment.as_ptr().add(offset);
+            std::slice::from_raw_parts(src, self.stride())
+        };
+
+        let vector = bytes
+            .chunks_exact(size_of::<f32>())
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect::<Vec<f32>>();
+
+        Some(vector.into())
+    }
+
+    /// Flush the modified vectors to the disk.
+    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
+        for segment in self.segments.iter() {
+            segment.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Return the number of mapped segment files.
+    pub fn count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Return the size of each segment file in bytes.
+    pub fn segment_size(&self) -> usize {
+        SEGMENT_CAPACITY * self.stride()
+    }
+
+    /// Copy the raw bytes of a segment starting from the offset.
+    ///
+    /// The caller must prevent concurrent writes to the segments, for
+    /// example, by holding the storage lock while copying.
+    pub fn copy_bytes(
+        &self,
+        segment: usize,
+        offset: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let mapping = self.segments.get(segment).ok_or("Segment not found")?;
+        if offset + buffer.len() > self.segment_size() {
+            return Err("Byte range is out of the segment bounds".into());
+        }
+
+        // SAFETY: The range is within the mapped segment and the caller
+        // guarantees that no writes happen while copying.
+        unsafe {
+            let src = mapping.as_ptr().add(offset);
+            ptr::copy_nonoverlapping(src, buffer.as_mut_ptr(), buffer.len());
+        }
+
+        Ok(())
+    }
+
+    /// Return the file name of a segment.
+    pub fn file_name(segment: usize) -> String {
+        format!("segment_{segment}")
+    }
+
+    /// Map the next segment file, creating the file if it doesn't exist.
+    fn map_segment(&mut self) -> Result<(), Box<dyn Error>> {
+        let path = self.segment_path(self.segments.len());
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        // A segment shorter than expected was interrupted while it was
+        // being created. It can't contain any vectors referenced by
+        // the snapshots, so it's safe to extend it.
+        let length = self.segment_size() as u64;
+        let current_length = file.metadata()?.len();
+        if current_length > length {
+            let message = format!(
+                "Segment {} is larger than expected: {current_length} bytes",
+                path.display()
+            );
+
+            return Err(message.into());
+        }
+
+        if current_length < length {
+            file.set_len(length)?;
+            file.sync_all()?;
+            files::sync_dir(&self.dir)?;
+        }
+
+        let segment = MmapRaw::map_raw(&file)?;
+        self.segments.push(Arc::new(segment));
+        Ok(())
+    }
+
+    /// Return the segment index and byte offset of a slot.
+    fn locate(&self, slot: u64) -> (usize, usize) {
+        let slot = slot as usize;
+        let offset = (slot % SEGMENT_CAPACITY) * self.stride();
+        (slot / SEGMENT_CAPACITY, offset)
+    }
+
+    fn stride(&self) -> usize {
+        self.dimension * size_of::<f32>()
+    }
+
+    fn segment_path(&self, segment: usize) -> PathBuf {
+        self.dir.join(Self::file_name(segment))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_read() {
+        let dir = setup_dir("write_and_read");
+        let mut segments = VectorSegments::open(&dir, 128).unwrap();
+
+        let vectors: Vec<Vector> =
+            (0..3).map(|_| Vector::random(128)).collect();
+        let slots = [0, 1, SEGMENT_CAPACITY as u64 + 1];
+        for (slot, vector) in slots.iter().zip(vectors.iter()) {
+            segments.write(*slot, vector).unwrap();
+        }
+
+        segments.flush().unwrap();
+        assert_eq!(segments.segments.len(), 2);
+
+        // Reopen the segments to read the vectors from the disk.
+        let segments = VectorSegments::open(&dir, 128).unwrap();
+        for (slot, vector) in slots.iter().zip(vectors.iter()) {
+            assert_eq!(segments.read(*slot).unwrap(), *vector);
+        }
+
+        assert!(segments.read(SEGMENT_CAPACITY as u64 * 2).is_none());
+    }
+
+    #[test]
+    fn test_write_invalid_dimension() {
+        let dir = setup_dir("write_invalid_dimension");
+        let mut segments = VectorSegments::open(dir, 128).unwrap();
+        assert!(segments.write(0, &Vector::random(64)).is_err());
+    }
+
+    fn setup_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join("oasysdb_segment_tests").join(name);
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        dir
+    }
+}
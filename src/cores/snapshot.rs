@@ -0,0 +1,184 @@
+use super::*;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Snapshot policy name constants.
+const INTERVAL: &str = "interval";
+const WRITES: &str = "writes";
+const MANUAL: &str = "manual";
+
+/// Interval to check the number of writes for the write-based policy.
+const WRITES_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before retrying a failed snapshot.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Policy to create snapshots in the background.
+///
+/// The policy is parsed from one of these formats:
+/// - interval:<seconds>: Snapshot periodically if there are new writes.
+/// - writes:<count>: Snapshot after the number of writes.
+/// - manual: Only snapshot via the Snapshot RPC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotPolicy {
+    Interval(Duration),
+    Writes(usize),
+    Manual,
+}
+
+impl SnapshotPolicy {
+    /// Return the time to wait before checking the policy again.
+    fn check_interval(&self) -> Duration {
+        match self {
+            SnapshotPolicy::Interval(interval) => *interval,
+            _ => WRITES_CHECK_INTERVAL,
+        }
+    }
+
+    /// Check if a snapshot is due given the writes since the last one.
+    fn is_due(&self, writes: usize) -> bool {
+        match self {
+            SnapshotPolicy::Interval(_) => writes > 0,
+            SnapshotPolicy::Writes(count) => writes >= *count,
+            SnapshotPolicy::Manual => false,
+        }
+    }
+}
+
+impl fmt::Display for SnapshotPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotPolicy::Interval(interval) => {
+                write!(f, "{INTERVAL}:{}", interval.as_secs())
+            }
+            SnapshotPolicy::Writes(count) => write!(f, "{WRITES}:{count}"),
+            SnapshotPolicy::Manual => write!(f, "{MANUAL}"),
+        }
+    }
+}
+
+impl FromStr for SnapshotPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            let message = "Snapshot policy should be interval:<seconds>, \
+                writes:<count>, or manual";
+            message.to_string()
+        };
+
+        let value = s.trim().to_lowercase();
+        if value == MANUAL {
+            return Ok(SnapshotPolicy::Manual);
+        }
+
+        let (name, amount) = value.split_once(':').ok_or_else(invalid)?;
+        let amount = match amount.trim().parse::<u64>() {
+            Ok(amount) if amount > 0 => amount,
+            _ => return Err(invalid()),
+        };
+
+        match name.trim() {
+            INTERVAL => {
+                Ok(SnapshotPolicy::Interval(Duration::from_secs(amount)))
+            }
+            WRITES => Ok(SnapshotPolicy::Writes(amount as usize)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Background thread creating snapshots based on the policy.
+///
+/// A failed snapshot is logged and retried after a delay. The database
+/// doesn't lose any data in the meantime because all operations are
+/// recorded in the write-ahead log.
+#[derive(Debug)]
+pub struct SnapshotScheduler {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SnapshotScheduler {
+    /// Start creating snapshots of the database in the background.
+    ///
+    /// No thread is started for the manual policy.
+    pub fn start(db: Arc<Database>, policy: SnapshotPolicy) -> Self {
+        if policy == SnapshotPolicy::Manual {
+            return SnapshotScheduler { stop: None, thread: None };
+        }
+
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            let timeout = Err(RecvTimeoutError::Timeout);
+            let mut wait = policy.check_interval();
+            while stopped.recv_timeout(wait) == timeout {
+                wait = policy.check_interval();
+                if !policy.is_due(db.pending_writes()) {
+                    continue;
+                }
+
+                if let Err(e) = db.create_snapshot() {
+                    let delay = RETRY_DELAY.as_secs();
+                    tracing::error!(
+                        "Failed to create a snapshot, retrying in {delay}s: {e}"
+                    );
+
+                    wait = RETRY_DELAY;
+                }
+            }
+        });
+
+        SnapshotScheduler { stop: Some(stop), thread: Some(thread) }
+    }
+
+    /// Stop the background thread and wait for it to finish.
+    pub fn stop(mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                tracing::error!("Snapshot thread stopped unexpectedly");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        let policies = [
+            (
+                "interval:600",
+                SnapshotPolicy::Interval(Duration::from_secs(600)),
+            ),
+            ("writes:1000", SnapshotPolicy::Writes(1000)),
+            ("Manual", SnapshotPolicy::Manual),
+        ];
+
+        for (value, policy) in policies {
+            assert_eq!(value.parse::<SnapshotPolicy>().unwrap(), policy);
+            let formatted = policy.to_string();
+            assert_eq!(formatted.parse::<SnapshotPolicy>().unwrap(), policy);
+        }
+
+        for value in ["interval", "writes:0", "writes:-1", "daily:1"] {
+            assert!(value.parse::<SnapshotPolicy>().is_err());
+        }
+    }
+
+    #[test]
+    fn test_is_due() {
+        let policy = SnapshotPolicy::Writes(10);
+        assert!(!policy.is_due(9));
+        assert!(policy.is_due(10));
+
+        let policy = SnapshotPolicy::Interval(Duration::from_secs(60));
+        assert!(!policy.is_due(0));
+        assert!(policy.is_due(1));
+    }
+}
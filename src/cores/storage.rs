@@ -1,58 +1,236 @@
 use super::*;
+use std::borrow::Cow;
+use std::fmt::Debug;
+
+// Storage mode name constants.
+const MEMORY: &str = "memory";
+const MAPPED: &str = "mmap";
+
+/// Location of the record vectors.
+///
+/// ### Memory
+/// Records are kept in memory and persisted as a whole in the snapshots.
+///
+/// ### Mapped
+/// Vectors are stored in memory-mapped segment files and only the metadata
+/// is kept in memory. This allows datasets larger than the memory.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum StorageMode {
+    Memory,
+    Mapped,
+}
+
+impl StorageMode {
+    /// Return the storage mode name as a string slice.
+    pub fn as_str(&self) -> &str {
+        match self {
+            StorageMode::Memory => MEMORY,
+            StorageMode::Mapped => MAPPED,
+        }
+    }
+}
+
+impl From<&str> for StorageMode {
+    fn from(value: &str) -> Self {
+        let value = value.to_lowercase();
+        match value.as_str() {
+            MEMORY => StorageMode::Memory,
+            MAPPED => StorageMode::Mapped,
+            _ => panic!("Storage mode should be memory or mmap"),
+        }
+    }
+}
+
+impl From<String> for StorageMode {
+    fn from(value: String) -> Self {
+        StorageMode::from(value.as_str())
+    }
+}
+
+/// Record changes since the previous snapshot.
+///
+/// Fields:
+/// - count: Number of records after the changes are applied.
+/// - upserts: Records inserted or updated since the previous snapshot.
+/// - deletes: IDs of the records deleted since the previous snapshot.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordDelta<R> {
+    pub count: usize,
+    pub upserts: HashMap<RecordID, Arc<R>>,
+    pub deletes: Vec<RecordID>,
+}
+
+impl<R> RecordDelta<R> {
+    /// Create an empty delta with the record count.
+    pub fn new(count: usize) -> Self {
+        RecordDelta { count, upserts: HashMap::new(), deletes: vec![] }
+    }
+}
 
 /// Record storage interface.
 ///
-/// This interface wraps around Hashbrown's HashMap implementation to store
-/// the records. In the future, if needed, we can modify the storage
-/// implementation without changing the rest of the code.
+/// The database and the index access the records only through this
+/// interface, so the record store can be swapped without changing the rest
+/// of the code. The implementation is selected by the storage mode of the
+/// database parameters.
+///
+/// Besides the record operations, the storage keeps track of the records
+/// changed since the previous snapshot and provides frozen copies of its
+/// state so that snapshots are persisted without blocking the writers.
+#[allow(clippy::result_large_err)]
+pub trait Storage: Debug + Send + Sync {
+    /// Insert a new record into the record storage.
+    fn insert(&mut self, id: &RecordID, record: &Record) -> Result<(), Status>;
+
+    /// Retrieve a record from the storage given its ID.
+    fn get(&self, id: &RecordID) -> Result<Cow<'_, Record>, Status>;
+
+    /// Retrieve multiple records given their IDs.
+    ///
+    /// The result is aligned with the IDs. Missing records are returned as
+    /// None instead of failing the whole batch.
+    fn get_many(&self, ids: &[RecordID]) -> Vec<Option<Cow<'_, Record>>> {
+        ids.iter().map(|id| self.get(id).ok()).collect()
+    }
+
+    /// Delete a record from the storage given its ID.
+    ///
+    /// Deleting a missing record is a no-op.
+    fn delete(&mut self, id: &RecordID) -> Result<(), Status>;
+
+    /// Update a record metadata given its ID.
+    ///
+    /// Vector data should be immutable as it is tightly coupled with the
+    /// semantic meaning of the record. If the vector data changes, users
+    /// should create a new record instead.
+    fn update(
+        &mut self,
+        id: &RecordID,
+        metadata: &HashMap<String, Value>,
+    ) -> Result<(), Status>;
+
+    /// Return the number of records in the storage.
+    fn count(&self) -> usize;
+
+    /// Return the IDs of the stored records in arbitrary order.
+    ///
+    /// Unlike the iterator, this doesn't read the record vectors.
+    fn ids(&self) -> Vec<RecordID>;
+
+    /// Reset the record count to the number of stored records.
+    fn recount(&mut self);
+
+    /// Iterate over the records in the storage in arbitrary order.
+    fn iter(&self) -> RecordIter<'_>;
+
+    /// Create a frozen copy of the storage for a full snapshot.
+    fn freeze(&self) -> Box<dyn Encode>;
+
+    /// Take the IDs of the records changed since the previous snapshot.
+    fn take_changes(&self) -> HashSet<RecordID>;
+
+    /// Restore the taken changes when the snapshot fails.
+    ///
+    /// The changes are merged with the ones tracked after they were taken.
+    /// This is safe because the delta is always collected from the current
+    /// state of the records.
+    fn restore_changes(&self, changes: HashSet<RecordID>);
+
+    /// Collect the delta of the changed records for a delta snapshot.
+    fn delta(&self, changes: &HashSet<RecordID>) -> Box<dyn Encode>;
+
+    /// Release the resources freed by the taken changes after the snapshot
+    /// is committed and they're no longer referenced by the number of
+    /// retained snapshot generations.
+    fn recycle(&self, _retention: usize) {}
+
+    /// Return the vector segments that must be flushed before a snapshot
+    /// is committed.
+    fn segments(&self) -> Option<VectorSegments> {
+        None
+    }
+}
+
+/// Iterator over the records in a storage.
+pub type RecordIter<'a> =
+    Box<dyn Iterator<Item = (RecordID, Cow<'a, Record>)> + 'a>;
+
+/// In-memory record storage.
+///
+/// This storage wraps around Hashbrown's HashMap implementation to store
+/// the records.
+///
+/// The storage keeps track of the records changed since the previous
+/// snapshot so that snapshots only need to persist the changes. The change
+/// set is not persisted and is guarded by a mutex so that it can be taken
+/// while the snapshot holds a shared reference to the storage.
+///
+/// Records are reference-counted so that a frozen copy of the storage can
+/// be created cheaply for snapshots. Records shared with a frozen copy are
+/// cloned before they are modified.
 #[repr(C)]
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Storage {
+pub struct MemoryStorage {
     count: usize,
-    records: HashMap<RecordID, Record>,
+    records: HashMap<RecordID, Arc<Record>>,
+
+    #[serde(skip)]
+    changes: Mutex<HashSet<RecordID>>,
 }
 
-impl Storage {
+impl MemoryStorage {
     /// Create a new empty storage instance.
     pub fn new() -> Self {
-        Storage { count: 0, records: HashMap::new() }
+        MemoryStorage {
+            count: 0,
+            records: HashMap::new(),
+            changes: Mutex::new(HashSet::new()),
+        }
     }
 
-    /// Insert a new record into the record storage.
-    pub fn insert(
-        &mut self,
-        id: &RecordID,
-        record: &Record,
-    ) -> Result<(), Status> {
-        self.records.insert(*id, record.to_owned());
+    /// Apply the changes from a snapshot delta.
+    pub fn apply_delta(&mut self, delta: RecordDelta<Record>) {
+        for id in delta.deletes.iter() {
+            self.records.remove(id);
+        }
+
+        self.records.extend(delta.upserts);
+        self.count = delta.count;
+    }
+
+    fn track_change(&mut self, id: &RecordID) {
+        self.changes.get_mut().unwrap().insert(*id);
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn insert(&mut self, id: &RecordID, record: &Record) -> Result<(), Status> {
+        self.records.insert(*id, Arc::new(record.to_owned()));
         self.count += 1;
+        self.track_change(id);
         Ok(())
     }
 
-    /// Retrieve a record from the storage given its ID.
-    pub fn get(&self, id: &RecordID) -> Result<&Record, Status> {
-        let record = self.records.get(id);
-        if record.is_none() {
-            let message = "The specified record is not found";
-            return Err(Status::not_found(message));
+    fn get(&self, id: &RecordID) -> Result<Cow<'_, Record>, Status> {
+        match self.records.get(id) {
+            Some(record) => Ok(Cow::Borrowed(record.as_ref())),
+            None => {
+                let message = "The specified record is not found";
+                Err(Status::not_found(message))
+            }
         }
-
-        Ok(record.unwrap())
     }
 
-    /// Delete a record from the storage given its ID.
-    pub fn delete(&mut self, id: &RecordID) -> Result<(), Status> {
-        self.records.remove(id);
-        self.count -= 1;
+    fn delete(&mut self, id: &RecordID) -> Result<(), Status> {
+        if self.records.remove(id).is_some() {
+            self.count -= 1;
+            self.track_change(id);
+        }
+
         Ok(())
     }
 
-    /// Update a record metadata given its ID.
-    ///
-    /// Vector data should be immutable as it is tightly coupled with the
-    /// semantic meaning of the record. If the vector data changes, users
-    /// should create a new record instead.
-    pub fn update(
+    fn update(
         &mut self,
         id: &RecordID,
         metadata: &HashMap<String, Value>,
@@ -65,18 +243,58 @@ impl Storage {
             }
         };
 
-        record.metadata = metadata.to_owned();
+        Arc::make_mut(record).metadata = metadata.to_owned();
+        self.track_change(id);
         Ok(())
     }
 
-    /// Return a reference to the records in the storage.
-    pub fn records(&self) -> &HashMap<RecordID, Record> {
-        &self.records
+    fn count(&self) -> usize {
+        self.count
     }
 
-    /// Return the number of records in the storage.
-    pub fn count(&self) -> usize {
-        self.count
+    fn ids(&self) -> Vec<RecordID> {
+        self.records.keys().copied().collect()
+    }
+
+    fn recount(&mut self) {
+        self.count = self.records.len();
+    }
+
+    fn iter(&self) -> RecordIter<'_> {
+        let records = self.records.iter();
+        Box::new(records.map(|(id, record)| (*id, Cow::Borrowed(&**record))))
+    }
+
+    /// The records are shared with the copy, so this only copies the record
+    /// pointers. The tracked changes aren't included in the copy.
+    fn freeze(&self) -> Box<dyn Encode> {
+        Box::new(MemoryStorage {
+            count: self.count,
+            records: self.records.clone(),
+            changes: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn take_changes(&self) -> HashSet<RecordID> {
+        std::mem::take(&mut *self.changes.lock().unwrap())
+    }
+
+    fn restore_changes(&self, changes: HashSet<RecordID>) {
+        self.changes.lock().unwrap().extend(changes);
+    }
+
+    fn delta(&self, changes: &HashSet<RecordID>) -> Box<dyn Encode> {
+        let mut delta: RecordDelta<Record> = RecordDelta::new(self.count);
+        for id in changes.iter() {
+            match self.records.get(id) {
+                Some(record) => {
+                    delta.upserts.insert(*id, record.clone());
+                }
+                None => delta.deletes.push(*id),
+            }
+        }
+
+        Box::new(delta)
     }
 }
 
@@ -86,7 +304,7 @@ mod tests {
 
     #[test]
     fn test_insert() {
-        let mut storage = Storage::new();
+        let mut storage = MemoryStorage::new();
 
         let record = Record::random(128);
         let id = RecordID::new();
@@ -98,7 +316,7 @@ mod tests {
 
     #[test]
     fn test_delete() {
-        let mut storage = Storage::new();
+        let mut storage = MemoryStorage::new();
 
         let record = Record::random(128);
         let id = RecordID::new();
@@ -107,11 +325,15 @@ mod tests {
         storage.delete(&id).unwrap();
         assert_eq!(storage.count, 0);
         assert_eq!(storage.count, storage.records.len());
+
+        // Deleting a missing record must not change the count.
+        storage.delete(&RecordID::new()).unwrap();
+        assert_eq!(storage.count, 0);
     }
 
     #[test]
     fn test_update() {
-        let mut storage = Storage::new();
+        let mut storage = MemoryStorage::new();
 
         let record = Record::random(128);
         let id = RecordID::new();
@@ -124,4 +346,74 @@ mod tests {
         let updated_record = storage.records.get(&id).unwrap();
         assert_eq!(updated_record.metadata, metadata);
     }
+
+    #[test]
+    fn test_apply_delta() {
+        let mut storage = MemoryStorage::new();
+
+        let ids: Vec<RecordID> = (0..3).map(|_| RecordID::new()).collect();
+        for id in ids.iter() {
+            storage.insert(id, &Record::random(128)).unwrap();
+        }
+
+        // Create a snapshot copy of the storage.
+        let mut copy = MemoryStorage::new();
+        copy.apply_delta(collect_delta(&storage));
+        assert_eq!(copy.count, 3);
+
+        storage.delete(&ids[0]).unwrap();
+        storage.update(&ids[1], &HashMap::new()).unwrap();
+        storage.insert(&RecordID::new(), &Record::random(128)).unwrap();
+
+        let delta = collect_delta(&storage);
+        assert_eq!(delta.upserts.len(), 2);
+        assert_eq!(delta.deletes, vec![ids[0]]);
+
+        copy.apply_delta(delta);
+        assert_eq!(copy.count, storage.count);
+        assert_eq!(copy.records, storage.records);
+    }
+
+    #[test]
+    fn test_freeze() {
+        let mut storage = MemoryStorage::new();
+
+        let record = Record::random(128);
+        let id = RecordID::new();
+        storage.insert(&id, &record).unwrap();
+
+        // Modifying the storage must not affect the frozen copy.
+        let frozen = storage.freeze();
+        storage.update(&id, &HashMap::new()).unwrap();
+        storage.insert(&RecordID::new(), &record).unwrap();
+
+        let compression = Compression::Lz4;
+        let options = FileOptions { compression, ..Default::default() };
+        let bytes = frozen.encode(&options).unwrap();
+        let frozen: MemoryStorage = decode_file(&bytes, None).unwrap();
+
+        assert_eq!(frozen.count, 1);
+        assert_eq!(frozen.records.get(&id).unwrap().metadata, record.metadata);
+        assert!(storage.records.get(&id).unwrap().metadata.is_empty());
+    }
+
+    #[test]
+    fn test_get_many() {
+        let mut storage = MemoryStorage::new();
+
+        let record = Record::random(128);
+        let id = RecordID::new();
+        storage.insert(&id, &record).unwrap();
+
+        let records = storage.get_many(&[id, RecordID::new()]);
+        assert_eq!(records[0].as_deref(), Some(&record));
+        assert!(records[1].is_none());
+        assert_eq!(storage.iter().count(), 1);
+    }
+
+    fn collect_delta(storage: &MemoryStorage) -> RecordDelta<Record> {
+        let delta = storage.delta(&storage.take_changes());
+        let bytes = delta.encode(&FileOptions::default()).unwrap();
+        decode_file(&bytes, None).unwrap()
+    }
 }
@@ -0,0 +1,616 @@
+use super::*;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+
+/// Magic bytes at the start of the log files with a header.
+const LOG_MAGIC: [u8; 4] = *b"OWAL";
+
+/// Size of the log header: magic bytes and the segment sequence (u64).
+const LOG_HEADER_SIZE: usize = 12;
+
+/// Size of the entry header: payload length (u32) and CRC32 checksum (u32).
+const ENTRY_HEADER_SIZE: usize = 8;
+
+/// Bit of the payload length marking an encrypted payload.
+const ENCRYPTED_FLAG: u32 = 1 << 31;
+
+/// Database mutation recorded in the write-ahead log.
+///
+/// Operations are recorded with the record ID assigned by the database so
+/// that replaying the log produces exactly the same state as the one that
+/// was acknowledged to the client.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum Operation {
+    Insert(RecordID, Record),
+    Delete(RecordID),
+    Update(RecordID, HashMap<String, Value>),
+}
+
+/// Append-only write-ahead log.
+///
+/// Every mutation is appended to the log and flushed to the disk before it
+/// is acknowledged. When the database is opened, the log is replayed on top
+/// of the last snapshot.
+///
+/// When a snapshot captures the database state, the active log is sealed
+/// into a numbered segment and a new active log is started. Once the
+/// snapshot is committed, the sealed segments are removed since the
+/// snapshot already contains all of their operations.
+///
+/// Each entry is framed with the payload length and a CRC32 checksum of the
+/// payload. A partially written entry at the end of the log, which happens
+/// when the process dies in the middle of an append, is discarded.
+///
+/// If an encryption key is provided, the payloads are encrypted and marked
+/// with a flag in the payload length. Entries that can't be decrypted fail
+/// the read instead of being discarded as torn entries. So do unencrypted
+/// entries because they could be appended without the key. The entries of
+/// an existing log are encrypted explicitly with `encrypt_existing`.
+///
+/// The log file starts with a header containing the sequence number the
+/// log is sealed with. The encrypted entries are bound to the sequence
+/// number and their offset in the file, so they can't be reordered or
+/// moved to another segment. Logs written prior to the header are still
+/// read from the start of the file.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: File,
+    key: Option<EncryptionKey>,
+    sequence: u64,
+    start: u64,
+}
+
+impl WriteAheadLog {
+    /// Open the write-ahead log file, creating it if it doesn't exist.
+    pub fn open(
+        path: impl AsRef<Path>,
+        key: Option<EncryptionKey>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref().to_path_buf();
+        let file = Self::open_file(&path)?;
+        let mut wal = WriteAheadLog { path, file, key, sequence: 0, start: 0 };
+
+        // A log shorter than the header can't contain a complete entry, so
+        // it's started again with the header.
+        match read_header(&mut wal.file)? {
+            Some(sequence) => {
+                wal.sequence = sequence;
+                wal.start = LOG_HEADER_SIZE as u64;
+            }
+            None if wal.file.metadata()?.len() < LOG_HEADER_SIZE as u64 => {
+                let sequence = wal.next_sequence()?;
+                wal.file.set_len(0)?;
+                wal.write_header(sequence)?;
+            }
+            None => wal.sequence = wal.next_sequence()?,
+        }
+
+        Ok(wal)
+    }
+
+    /// Append an operation to the log and flush it to the disk.
+    pub fn append(
+        &mut self,
+        operation: &Operation,
+    ) -> Result<(), Box<dyn Error>> {
+        // Remove the partially written entry if the write fails so that
+        // the following entries remain readable.
+        let length = self.file.metadata()?.len();
+        let entry = self.encode_entry(operation, length)?;
+        let result =
+            self.file.write_all(&entry).and_then(|_| self.file.sync_data());
+
+        if let Err(e) = result {
+            self.file.set_len(length)?;
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    /// Read all valid operations in the order they were appended.
+    ///
+    /// The operations from the sealed segments are returned before the
+    /// ones from the active log. If the active log ends with a torn or
+    /// corrupted entry, the log is truncated to the last valid entry so that
+    /// new entries won't be appended after the invalid bytes.
+    pub fn read(&mut self) -> Result<Vec<Operation>, Box<dyn Error>> {
+        self.read_all(false)
+    }
+
+    /// Encrypt the unencrypted entries of an existing log with the key.
+    ///
+    /// The operations of the sealed segments and the active log are written
+    /// into a new active log which replaces the old one before the segments
+    /// are removed. If the process stops before the segments are removed,
+    /// their operations are replayed twice which has no effect.
+    pub fn encrypt_existing(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.key.is_none() {
+            return Err("The key to encrypt the log is not provided".into());
+        }
+
+        let mut entries = encode_header(self.sequence).to_vec();
+        for operation in self.read_all(true)? {
+            let offset = entries.len() as u64;
+            entries.extend(self.encode_entry(&operation, offset)?);
+        }
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&entries)?;
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = Self::open_file(&self.path)?;
+        self.start = LOG_HEADER_SIZE as u64;
+        self.remove_segments(u64::MAX)?;
+
+        if let Some(dir) = self.path.parent() {
+            files::sync_dir(dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read the operations from the sealed segments and the active log.
+    ///
+    /// The unencrypted entries are only accepted with a key when they're
+    /// being encrypted.
+    fn read_all(
+        &mut self,
+        unencrypted: bool,
+    ) -> Result<Vec<Operation>, Box<dyn Error>> {
+        let key = self.key.as_ref();
+        let mut operations = vec![];
+        for (sequence, path) in self.segments()? {
+            let mut file = File::open(path)?;
+            let start = match read_header(&mut file)? {
+                Some(header) if header != sequence => {
+                    let message = format!(
+                        "Write-ahead log segment {sequence} has the header \
+                        of segment {header}"
+                    );
+                    return Err(message.into());
+                }
+                Some(_) => LOG_HEADER_SIZE as u64,
+                None => 0,
+            };
+
+            let log = (sequence, start);
+            let (entries, _) = read_entries(&mut file, log, key, unencrypted)?;
+            operations.extend(entries);
+        }
+
+        let log = (self.sequence, self.start);
+        let (entries, valid_length) =
+            read_entries(&mut self.file, log, key, unencrypted)?;
+        if valid_length < self.file.metadata()?.len() {
+            tracing::warn!("Discarding an incomplete write-ahead log entry");
+            self.file.set_len(valid_length)?;
+            self.file.sync_all()?;
+        }
+
+        operations.extend(entries);
+        Ok(operations)
+    }
+
+    /// Seal the active log into a segment and start a new active log.
+    ///
+    /// This method returns the sequence number of the sealed segment which
+    /// can be used to remove the segment after the snapshot is committed.
+    pub fn rotate(&mut self) -> Result<u64, Box<dyn Error>> {
+        let sequence = self.sequence;
+        fs::rename(&self.path, self.segment_path(sequence))?;
+        self.file = Self::open_file(&self.path)?;
+        self.write_header(sequence + 1)?;
+
+        if let Some(dir) = self.path.parent() {
+            files::sync_dir(dir)?;
+        }
+
+        Ok(sequence)
+    }
+
+    /// Remove the sealed segments up to the given sequence number.
+    pub fn remove_segments(&self, sequence: u64) -> Result<(), Box<dyn Error>> {
+        for (segment, path) in self.segments()? {
+            if segment <= sequence {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Discard all operations in the log including the sealed segments.
+    pub fn clear(&mut self) -> Result<(), Box<dyn Error>> {
+        self.remove_segments(u64::MAX)?;
+        self.file.set_len(0)?;
+        self.write_header(self.sequence)?;
+        Ok(())
+    }
+
+    /// Return the sequence number following the sealed segments.
+    fn next_sequence(&self) -> Result<u64, Box<dyn Error>> {
+        match self.segments()?.last() {
+            Some((sequence, _)) => Ok(sequence + 1),
+            None => Ok(1),
+        }
+    }
+
+    /// Write the header to the empty active log.
+    fn write_header(&mut self, sequence: u64) -> Result<(), Box<dyn Error>> {
+        self.file.write_all(&encode_header(sequence))?;
+        self.file.sync_all()?;
+        self.sequence = sequence;
+        self.start = LOG_HEADER_SIZE as u64;
+        Ok(())
+    }
+
+    /// Return the sealed segments sorted by their sequence numbers.
+    fn segments(&self) -> Result<Vec<(u64, PathBuf)>, Box<dyn Error>> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        // Unwrap is safe because the log path is always a file path.
+        let prefix =
+            format!("{}.", self.path.file_name().unwrap().to_string_lossy());
+
+        let mut segments = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy();
+            let sequence = name
+                .strip_prefix(&prefix)
+                .and_then(|sequence| sequence.parse::<u64>().ok());
+
+            if let Some(sequence) = sequence {
+                segments.push((sequence, path));
+            }
+        }
+
+        segments.sort_unstable();
+        Ok(segments)
+    }
+
+    /// Frame the operation as a log entry, encrypting it with the key.
+    /// - offset: Position of the entry in the active log.
+    fn encode_entry(
+        &self,
+        operation: &Operation,
+        offset: u64,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut payload = bincode::serialize(operation)?;
+        let mut length = payload.len() as u32;
+        if let Some(key) = self.key.as_ref() {
+            let associated = associated_data(self.sequence, offset);
+            payload = key.encrypt(&payload, &associated)?;
+            length = payload.len() as u32 | ENCRYPTED_FLAG;
+        }
+
+        let checksum = crc32fast::hash(&payload);
+        let mut entry = Vec::with_capacity(ENTRY_HEADER_SIZE + payload.len());
+        entry.extend_from_slice(&length.to_le_bytes());
+        entry.extend_from_slice(&checksum.to_le_bytes());
+        entry.extend_from_slice(&payload);
+        Ok(entry)
+    }
+
+    fn segment_path(&self, sequence: u64) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{sequence}"));
+        PathBuf::from(path)
+    }
+
+    fn open_file(path: &Path) -> Result<File, Box<dyn Error>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        Ok(file)
+    }
+}
+
+/// Encode the header of a log file.
+fn encode_header(sequence: u64) -> [u8; LOG_HEADER_SIZE] {
+    let mut header = [0; LOG_HEADER_SIZE];
+    header[0..4].copy_from_slice(&LOG_MAGIC);
+    header[4..12].copy_from_slice(&sequence.to_le_bytes());
+    header
+}
+
+/// Read the sequence number from the header of a log file.
+///
+/// Returns None if the file doesn't start with the header.
+fn read_header(file: &mut File) -> Result<Option<u64>, Box<dyn Error>> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut header = [0; LOG_HEADER_SIZE];
+    if read_exact_or_eof(file, &mut header)?.is_none() {
+        return Ok(None);
+    }
+
+    if header[0..4] != LOG_MAGIC {
+        return Ok(None);
+    }
+
+    Ok(Some(u64::from_le_bytes(header[4..12].try_into()?)))
+}
+
+/// Associated data binding an encrypted entry to its position in the log.
+fn associated_data(sequence: u64, offset: u64) -> [u8; 16] {
+    let mut associated = [0; 16];
+    associated[0..8].copy_from_slice(&sequence.to_le_bytes());
+    associated[8..16].copy_from_slice(&offset.to_le_bytes());
+    associated
+}
+
+/// Read the valid entries of a log file after its header.
+///
+/// Returns the operations and the length of the valid part of the file.
+/// Reading stops at the first torn or corrupted entry.
+/// - log: Sequence number of the log and the offset of its first entry.
+/// - unencrypted: Accept unencrypted entries even if a key is provided.
+fn read_entries(
+    file: &mut File,
+    log: (u64, u64),
+    key: Option<&EncryptionKey>,
+    unencrypted: bool,
+) -> Result<(Vec<Operation>, u64), Box<dyn Error>> {
+    let (sequence, start) = log;
+    file.seek(SeekFrom::Start(start))?;
+    let mut reader = BufReader::new(file);
+
+    let mut operations = vec![];
+    let mut valid_length = start;
+
+    loop {
+        let mut header = [0; ENTRY_HEADER_SIZE];
+        if read_exact_or_eof(&mut reader, &mut header)?.is_none() {
+            break;
+        }
+
+        let length = u32::from_le_bytes(header[0..4].try_into()?);
+        let checksum = u32::from_le_bytes(header[4..8].try_into()?);
+        let encrypted = length & ENCRYPTED_FLAG != 0;
+        let length = length & !ENCRYPTED_FLAG;
+
+        let mut payload = vec![0; length as usize];
+        if read_exact_or_eof(&mut reader, &mut payload)?.is_none() {
+            break;
+        }
+
+        if crc32fast::hash(&payload) != checksum {
+            break;
+        }
+
+        // The entry is intact at this point, so a decryption failure means
+        // that the key is missing or wrong rather than a torn write.
+        let entry_length = (ENTRY_HEADER_SIZE + payload.len()) as u64;
+        let data = match (encrypted, key) {
+            (false, None) => payload,
+            (false, Some(_)) if unencrypted => payload,
+            (false, Some(_)) => {
+                let message =
+                    "Write-ahead log entry is not encrypted but a key is provided";
+                return Err(message.into());
+            }
+            (true, Some(key)) => {
+                let associated = associated_data(sequence, valid_length);
+                key.decrypt(&payload, &associated)?
+            }
+            (true, None) => {
+                let message =
+                    "Write-ahead log is encrypted but no key is provided";
+                return Err(message.into());
+            }
+        };
+
+        let operation = match bincode::deserialize(&data) {
+            Ok(operation) => operation,
+            Err(_) => break,
+        };
+
+        operations.push(operation);
+        valid_length += entry_length;
+    }
+
+    Ok((operations, valid_length))
+}
+
+/// Fill the buffer from the reader.
+///
+/// Returns None if the reader reaches the end of the file before the buffer
+/// is completely filled.
+fn read_exact_or_eof(
+    reader: &mut impl Read,
+    buffer: &mut [u8],
+) -> Result<Option<()>, Box<dyn Error>> {
+    match reader.read_exact(buffer) {
+        Ok(()) => Ok(Some(())),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_and_read() {
+        let path = setup_path("append_and_read");
+        let mut wal = WriteAheadLog::open(&path, None).unwrap();
+
+        let id = RecordID::new();
+        let operations = vec![
+            Operation::Insert(id, Record::random(128)),
+            Operation::Update(id, HashMap::new()),
+            Operation::Delete(id),
+        ];
+
+        for operation in operations.iter() {
+            wal.append(operation).unwrap();
+        }
+
+        let mut wal = WriteAheadLog::open(&path, None).unwrap();
+        assert_eq!(wal.read().unwrap(), operations);
+    }
+
+    #[test]
+    fn test_read_torn_entry() {
+        let path = setup_path("read_torn_entry");
+        let mut wal = WriteAheadLog::open(&path, None).unwrap();
+
+        let operation = Operation::Delete(RecordID::new());
+        wal.append(&operation).unwrap();
+
+        // Simulate a crash in the middle of writing the second entry.
+        let length = fs::metadata(&path).unwrap().len();
+        wal.append(&operation).unwrap();
+        wal.file.set_len(length + 4).unwrap();
+
+        let mut wal = WriteAheadLog::open(&path, None).unwrap();
+        assert_eq!(wal.read().unwrap(), vec![operation.clone()]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), length);
+
+        // New entries must be readable after the discarded entry.
+        wal.append(&operation).unwrap();
+        assert_eq!(wal.read().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let path = setup_path("rotate");
+        let mut wal = WriteAheadLog::open(&path, None).unwrap();
+
+        let operations: Vec<Operation> =
+            (0..3).map(|_| Operation::Delete(RecordID::new())).collect();
+
+        wal.append(&operations[0]).unwrap();
+        let first = wal.rotate().unwrap();
+        wal.append(&operations[1]).unwrap();
+        let second = wal.rotate().unwrap();
+        wal.append(&operations[2]).unwrap();
+
+        assert_eq!((first, second), (1, 2));
+        assert_eq!(wal.read().unwrap(), operations);
+
+        wal.remove_segments(first).unwrap();
+        assert_eq!(wal.read().unwrap(), operations[1..].to_vec());
+
+        wal.remove_segments(second).unwrap();
+        assert_eq!(wal.read().unwrap(), operations[2..].to_vec());
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let path = setup_path("encrypted");
+        let key = EncryptionKey::from_hex(&"ab".repeat(32)).unwrap();
+        let mut wal = WriteAheadLog::open(&path, Some(key.clone())).unwrap();
+
+        let operation = Operation::Delete(RecordID::new());
+        wal.append(&operation).unwrap();
+        assert_eq!(wal.read().unwrap(), vec![operation]);
+
+        // Reading without the key must fail without truncating the log.
+        let length = fs::metadata(&path).unwrap().len();
+        let mut wal = WriteAheadLog::open(&path, None).unwrap();
+        assert!(wal.read().is_err());
+
+        let other = EncryptionKey::from_hex(&"cd".repeat(32)).unwrap();
+        let mut wal = WriteAheadLog::open(&path, Some(other)).unwrap();
+        assert!(wal.read().is_err());
+        assert_eq!(fs::metadata(&path).unwrap().len(), length);
+    }
+
+    #[test]
+    fn test_encrypted_position() {
+        let path = setup_path("encrypted_position");
+        let key = EncryptionKey::from_hex(&"ab".repeat(32)).unwrap();
+        let mut wal = WriteAheadLog::open(&path, Some(key.clone())).unwrap();
+
+        let operations: Vec<Operation> =
+            (0..2).map(|_| Operation::Delete(RecordID::new())).collect();
+
+        for operation in operations.iter() {
+            wal.append(operation).unwrap();
+        }
+
+        // Swapping the entries of the same size must fail the read.
+        let bytes = fs::read(&path).unwrap();
+        let (header, entries) = bytes.split_at(LOG_HEADER_SIZE);
+        let (first, second) = entries.split_at(entries.len() / 2);
+        fs::write(&path, [header, second, first].concat()).unwrap();
+
+        let mut wal = WriteAheadLog::open(&path, Some(key.clone())).unwrap();
+        assert!(wal.read().is_err());
+
+        // So must moving a sealed segment to another sequence number.
+        fs::write(&path, &bytes).unwrap();
+        let mut wal = WriteAheadLog::open(&path, Some(key.clone())).unwrap();
+        let sequence = wal.rotate().unwrap();
+        fs::rename(wal.segment_path(sequence), wal.segment_path(5)).unwrap();
+        assert!(wal.read().is_err());
+    }
+
+    #[test]
+    fn test_sequence_after_removal() {
+        let path = setup_path("sequence_after_removal");
+        let key = EncryptionKey::from_hex(&"ab".repeat(32)).unwrap();
+        let mut wal = WriteAheadLog::open(&path, Some(key.clone())).unwrap();
+
+        let operation = Operation::Delete(RecordID::new());
+        wal.append(&operation).unwrap();
+        let sequence = wal.rotate().unwrap();
+        wal.append(&operation).unwrap();
+        wal.remove_segments(sequence).unwrap();
+
+        // The active log keeps its sequence number without the segments.
+        let mut wal = WriteAheadLog::open(&path, Some(key)).unwrap();
+        assert_eq!(wal.read().unwrap(), vec![operation]);
+        assert_eq!(wal.rotate().unwrap(), sequence + 1);
+    }
+
+    #[test]
+    fn test_encrypt_existing() {
+        let path = setup_path("encrypt_existing");
+        let mut wal = WriteAheadLog::open(&path, None).unwrap();
+
+        let operations: Vec<Operation> =
+            (0..3).map(|_| Operation::Delete(RecordID::new())).collect();
+
+        wal.append(&operations[0]).unwrap();
+        wal.rotate().unwrap();
+        wal.append(&operations[1]).unwrap();
+
+        // Unencrypted entries must not be accepted with the key.
+        let key = EncryptionKey::from_hex(&"ab".repeat(32)).unwrap();
+        let mut wal = WriteAheadLog::open(&path, Some(key.clone())).unwrap();
+        assert!(wal.read().is_err());
+
+        wal.encrypt_existing().unwrap();
+        wal.append(&operations[2]).unwrap();
+        assert_eq!(wal.read().unwrap(), operations);
+        assert!(wal.segments().unwrap().is_empty());
+
+        let mut wal = WriteAheadLog::open(&path, None).unwrap();
+        assert!(wal.read().is_err());
+    }
+
+    fn setup_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join("oasysdb_wal_tests").join(name);
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("odb_wal")
+    }
+}
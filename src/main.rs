@@ -3,17 +3,25 @@ mod protos;
 mod types;
 mod utils;
 
+use clap::parser::ValueSource;
 use clap::{arg, ArgMatches, Command};
-use cores::{Database, Parameters};
+use cores::IndexType;
+use cores::StorageMode;
+use cores::{Compression, ConsistencyReport, Database, EncryptionKey};
+use cores::{ExportFormat, ExportWriter, ImportFormat, ImportReader};
+use cores::{OpenParameters, Parameters};
+use cores::{SnapshotPolicy, SnapshotScheduler};
 use dotenv::dotenv;
+use protos::database_client::DatabaseClient;
 use protos::database_server::DatabaseServer;
+use protos::RetrainRequest;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::time::{Instant, SystemTime};
 use tonic::transport::Server;
-use types::Metric;
-
-const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(600);
+use types::{Metric, Record};
 
 #[tokio::main]
 async fn main() {
@@ -24,49 +32,201 @@ async fn main() {
         .version(env!("CARGO_PKG_VERSION"))
         .about("Interface to setup and manage OasysDB server")
         .arg_required_else_help(true)
+        .arg(arg_dir())
         .subcommand(start())
         .subcommand(configure())
+        .subcommand(snapshots())
+        .subcommand(inspect())
+        .subcommand(verify())
+        .subcommand(repair())
+        .subcommand(retrain())
+        .subcommand(import())
+        .subcommand(export())
+        .subcommand(backup())
+        .subcommand(restore())
         .get_matches();
 
     match command.subcommand() {
         Some(("start", args)) => start_handler(args).await,
         Some(("configure", args)) => configure_handler(args).await,
+        Some(("snapshots", args)) => snapshots_handler(args).await,
+        Some(("inspect", args)) => inspect_handler(args).await,
+        Some(("verify", args)) => verify_handler(args).await,
+        Some(("repair", args)) => repair_handler(args).await,
+        Some(("retrain", args)) => retrain_handler(args).await,
+        Some(("import", args)) => import_handler(args).await,
+        Some(("export", args)) => export_handler(args).await,
+        Some(("backup", args)) => backup_handler(args).await,
+        Some(("restore", args)) => restore_handler(args).await,
         _ => unreachable!(),
     }
 }
 
+fn arg_dir() -> clap::Arg {
+    arg!(--dir <path> "Path to the data directory")
+        .env("ODB_DIR")
+        .default_value("oasysdb")
+        .value_parser(clap::value_parser!(PathBuf))
+        .global(true)
+}
+
+/// Return the data directory passed with the global argument.
+fn data_dir(args: &ArgMatches) -> &Path {
+    args.get_one::<PathBuf>("dir").unwrap()
+}
+
 fn start() -> Command {
     let arg_port = arg!(--port <port> "Port to listen on")
         .default_value("2505")
         .value_parser(clap::value_parser!(u16))
         .allow_negative_numbers(false);
 
+    let arg_snapshot_policy = arg!(
+        --"snapshot-policy" <policy>
+        "Snapshot policy: interval:<seconds>, writes:<count>, or manual"
+    )
+    .env("ODB_SNAPSHOT_POLICY")
+    .default_value("interval:600")
+    .value_parser(clap::value_parser!(SnapshotPolicy));
+
+    let arg_restore = arg!(
+        --restore <generation>
+        "Restore an older snapshot generation, discarding the newer data"
+    )
+    .value_parser(clap::value_parser!(u64));
+
+    let arg_merge_threshold = arg!(
+        --"merge-threshold" <fraction>
+        "Fraction of the density below which clusters are merged"
+    )
+    .env("ODB_MERGE_THRESHOLD")
+    .default_value("0.1")
+    .value_parser(clap::value_parser!(f32));
+
+    let arg_encrypt_existing = arg!(
+        --"encrypt-existing"
+        "Encrypt the unencrypted data files with the key before starting"
+    );
+
     Command::new("start")
         .alias("run")
         .about("Start the database server")
         .arg(arg_port)
+        .arg(arg_snapshot_policy)
+        .arg(arg_retention())
+        .arg(arg_restore)
+        .arg(arg_merge_threshold)
+        .arg(arg_compression())
+        .arg(arg_key_file())
+        .arg(arg_encrypt_existing)
 }
 
 async fn start_handler(args: &ArgMatches) {
     // Unwrap is safe because Clap validates the arguments.
     let port = args.get_one::<u16>("port").unwrap();
     let addr = format!("[::]:{port}").parse().unwrap();
+    let policy = *args.get_one::<SnapshotPolicy>("snapshot-policy").unwrap();
+    let retention = *args.get_one::<u64>("retention").unwrap() as usize;
+    let restore = args.get_one::<u64>("restore").copied();
+    let merge_threshold = *args.get_one::<f32>("merge-threshold").unwrap();
+
+    let compression = *args.get_one::<Compression>("compression").unwrap();
+    let key = load_key(args);
+    let encrypt_existing = args.get_flag("encrypt-existing");
+
+    let options = OpenParameters {
+        retention,
+        restore,
+        compression,
+        key,
+        merge_threshold,
+        maintenance: false,
+        encrypt_existing,
+    };
+    let db = Database::open(data_dir(args), &options)
+        .expect("Failed to open the database");
 
-    let db = Arc::new(Database::open().expect("Failed to open the database"));
+    let db = Arc::new(db);
 
-    let db_clone = db.clone();
-    thread::spawn(move || loop {
-        thread::sleep(SNAPSHOT_INTERVAL);
-        db_clone.create_snapshot().expect("Failed to create a snapshot");
-    });
+    let scheduler = SnapshotScheduler::start(db.clone(), policy);
 
     tracing::info!("Database server is ready on port {port}");
+    tracing::info!("Snapshot policy: {policy}");
 
+    // The server stops accepting new connections on shutdown and waits
+    // for the in-flight requests to complete before returning.
     Server::builder()
-        .add_service(DatabaseServer::new(db))
-        .serve(addr)
+        .add_service(DatabaseServer::new(db.clone()))
+        .serve_with_shutdown(addr, shutdown_signal())
         .await
         .expect("Failed to start the database");
+
+    scheduler.stop();
+
+    // The final snapshot is an optimization for the next startup. If it
+    // fails, the operations are still recovered from the write-ahead log.
+    match db.create_snapshot() {
+        Ok(_) => tracing::info!("Database server is stopped"),
+        Err(e) => tracing::error!("Failed to create the final snapshot: {e}"),
+    }
+}
+
+fn arg_retention() -> clap::Arg {
+    arg!(--retention <count> "Number of snapshot generations to keep")
+        .env("ODB_SNAPSHOT_RETENTION")
+        .default_value("3")
+        .value_parser(clap::value_parser!(u64).range(1..))
+}
+
+fn arg_compression() -> clap::Arg {
+    arg!(--compression <codec> "Compression of the snapshot files")
+        .env("ODB_COMPRESSION")
+        .default_value(Compression::None.as_str())
+        .value_parser(clap::value_parser!(Compression))
+}
+
+fn arg_key_file() -> clap::Arg {
+    arg!(
+        --"key-file" <path>
+        "File containing the hex-encoded key to encrypt the data files"
+    )
+    .env("ODB_ENCRYPTION_KEY_FILE")
+    .value_parser(clap::value_parser!(PathBuf))
+}
+
+/// Load the encryption key from the key file or the environment.
+fn load_key(args: &ArgMatches) -> Option<EncryptionKey> {
+    let file = args.get_one::<PathBuf>("key-file");
+    EncryptionKey::load(file.map(PathBuf::as_path))
+        .expect("Failed to load the encryption key")
+}
+
+/// Wait for a SIGINT or SIGTERM signal to shut down the server.
+async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for the interrupt signal");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        signal(SignalKind::terminate())
+            .expect("Failed to listen for the terminate signal")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("Shutting down the database server");
 }
 
 fn configure() -> Command {
@@ -85,18 +245,620 @@ fn configure() -> Command {
         .value_parser(clap::value_parser!(usize))
         .allow_negative_numbers(false);
 
+    let arg_storage = arg!(
+        --storage <storage>
+        "Location of the vectors, mapped by default for unencrypted ivfpq. \
+        Encryption requires memory as the mapped vectors aren't encrypted"
+    )
+    .default_value(StorageMode::Memory.as_str())
+    .value_parser(clap::value_parser!(StorageMode));
+
+    let arg_index =
+        arg!(--index <type> "Type of the index: ivf, hnsw, flat, or ivfpq")
+            .default_value(IndexType::Ivf.as_str())
+            .value_parser(clap::value_parser!(IndexType));
+
+    let arg_m = arg!(--m <count> "Max number of neighbors per HNSW node")
+        .default_value("16")
+        .value_parser(clap::value_parser!(usize))
+        .allow_negative_numbers(false);
+
+    let arg_ef_construction = arg!(
+        --"ef-construction" <count>
+        "Number of candidates explored when inserting into HNSW"
+    )
+    .default_value("200")
+    .value_parser(clap::value_parser!(usize))
+    .allow_negative_numbers(false);
+
+    let arg_subspaces = arg!(
+        --subspaces <count>
+        "Number of subspaces of the IVF-PQ vector codes"
+    )
+    .default_value("16")
+    .value_parser(clap::value_parser!(usize))
+    .allow_negative_numbers(false);
+
+    let arg_force = arg!(--force "Overwrite the existing database")
+        .conflicts_with("if-not-exists");
+
+    let arg_if_not_exists = arg!(
+        --"if-not-exists" "Keep the existing database without prompting"
+    );
+
     Command::new("configure")
         .about("Configure the initial database parameters")
         .arg(arg_dimension)
         .arg(arg_metric)
         .arg(arg_density)
+        .arg(arg_storage)
+        .arg(arg_index)
+        .arg(arg_m)
+        .arg(arg_ef_construction)
+        .arg(arg_subspaces)
+        .arg(arg_key_file())
+        .arg(arg_force)
+        .arg(arg_if_not_exists)
 }
 
 async fn configure_handler(args: &ArgMatches) {
     let dim = *args.get_one::<usize>("dim").unwrap();
     let metric = *args.get_one::<Metric>("metric").unwrap();
     let density = *args.get_one::<usize>("density").unwrap();
+    let mut storage = *args.get_one::<StorageMode>("storage").unwrap();
+    let index = match *args.get_one::<IndexType>("index").unwrap() {
+        IndexType::Hnsw { .. } => IndexType::Hnsw {
+            m: *args.get_one::<usize>("m").unwrap(),
+            ef_construction: *args.get_one::<usize>("ef-construction").unwrap(),
+        },
+        IndexType::IvfPq { .. } => IndexType::IvfPq {
+            subspaces: *args.get_one::<usize>("subspaces").unwrap(),
+        },
+        index => index,
+    };
+
+    // The IVF-PQ index keeps the full-precision vectors on the disk unless
+    // they're encrypted.
+    let key = load_key(args);
+    let source = args.value_source("storage");
+    if matches!(index, IndexType::IvfPq { .. })
+        && source == Some(ValueSource::DefaultValue)
+        && key.is_none()
+    {
+        storage = StorageMode::Mapped;
+    }
+
+    let params = Parameters { dimension: dim, metric, density, storage, index };
+    if key.is_some() {
+        params.check_encryption().expect("Failed to configure the database");
+    }
+    let dir = data_dir(args);
+
+    let configured = Database::is_configured(dir)
+        .expect("Failed to check the data directory");
+
+    if configured {
+        if args.get_flag("if-not-exists") {
+            println!("The database is already configured");
+            return;
+        }
+
+        let prompt = "Database is already configured. Overwrite?";
+        if !args.get_flag("force") && !confirm(prompt) {
+            eprintln!(
+                "The database is already configured. Use --force to reset"
+            );
+            std::process::exit(1);
+        }
+    }
+
+    Database::configure(dir, &params, configured)
+        .expect("Failed to configure the database");
+
+    println!("The database has been configured successfully");
+}
+
+/// Ask the user for a confirmation on the terminal.
+///
+/// Returns false without prompting if the input is not a terminal so that
+/// the scripts never hang waiting for an answer.
+fn confirm(prompt: &str) -> bool {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return false;
+    }
+
+    eprint!("{prompt} (y/n): ");
+    let mut input = String::new();
+    if stdin.read_line(&mut input).is_err() {
+        return false;
+    }
+
+    matches!(input.to_lowercase().trim(), "y")
+}
+
+fn snapshots() -> Command {
+    Command::new("snapshots")
+        .about("List the snapshot generations available to restore")
+}
+
+async fn snapshots_handler(args: &ArgMatches) {
+    let snapshots =
+        Database::snapshots(data_dir(args)).expect("Failed to list snapshots");
+
+    println!("{:<12}{:<8}{:>14}  CREATED", "GENERATION", "KIND", "SIZE");
+    for snapshot in snapshots {
+        let kind = if snapshot.full { "full" } else { "delta" };
+        let current = if snapshot.current { " (current)" } else { "" };
+        let age = SystemTime::now()
+            .duration_since(snapshot.created)
+            .unwrap_or_default()
+            .as_secs();
+
+        println!(
+            "{:<12}{:<8}{:>14}  {}{current}",
+            snapshot.generation,
+            kind,
+            snapshot.size,
+            format_age(age),
+        );
+    }
+}
+
+/// Format the age in seconds as a short human-readable string.
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..60 => format!("{seconds}s ago"),
+        60..3600 => format!("{}m ago", seconds / 60),
+        3600..86400 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
+
+fn inspect() -> Command {
+    Command::new("inspect")
+        .about("Print a summary of the data directory without the server")
+        .arg(arg_key_file())
+}
+
+async fn inspect_handler(args: &ArgMatches) {
+    let key = load_key(args);
+    let info = Database::inspect(data_dir(args), key.as_ref())
+        .expect("Failed to inspect the database");
+
+    let params = info.params;
+    println!("Parameters");
+    println!("  Dimension: {}", params.dimension);
+    println!("  Metric: {}", params.metric.as_str());
+    println!("  Density: {}", params.density);
+    println!("  Storage: {}", params.storage.as_str());
+    match params.index {
+        IndexType::Ivf => println!("  Index: ivf"),
+        IndexType::Hnsw { m, ef_construction } => println!(
+            "  Index: hnsw (m: {m}, ef_construction: {ef_construction})"
+        ),
+        IndexType::Flat => println!("  Index: flat"),
+        IndexType::IvfPq { subspaces } => {
+            println!("  Index: ivfpq (subspaces: {subspaces})")
+        }
+    }
+
+    println!();
+    println!("Snapshot generation: {}", info.generation);
+    println!("Records: {}", info.count);
+
+    // Only the IVF indexes organize the records into clusters.
+    if matches!(params.index, IndexType::Ivf | IndexType::IvfPq { .. }) {
+        println!("Clusters: {}", info.cluster_sizes.len());
+        println!();
+        println!("{:<16}{:>10}", "CLUSTER SIZE", "CLUSTERS");
+        for (range, count) in histogram(&info.cluster_sizes, params.density) {
+            println!("{range:<16}{count:>10}");
+        }
+    }
+
+    println!();
+    println!("{:<32}{:>10}", "METADATA KEY", "RECORDS");
+    for (key, count) in info.metadata_keys {
+        println!("{key:<32}{count:>10}");
+    }
+
+    println!();
+    println!("{:<32}{:>14}", "FILE", "SIZE");
+    for (path, size) in info.files {
+        println!("{:<32}{size:>14}", path.display());
+    }
+}
+
+/// Count the cluster sizes in 4 equal-width buckets up to the density.
+///
+/// Empty clusters and clusters larger than the density, if any, are
+/// counted in their own buckets.
+fn histogram(sizes: &[usize], density: usize) -> Vec<(String, usize)> {
+    let width = density.div_ceil(4).max(1);
+    let mut buckets = vec![("0".to_string(), 0)];
+    for start in (1..=density).step_by(width) {
+        let end = (start + width - 1).min(density);
+        buckets.push((format!("{start}-{end}"), 0));
+    }
+
+    buckets.push((format!(">{density}"), 0));
+    let overflow = buckets.len() - 1;
+    for size in sizes.iter() {
+        let bucket = match *size {
+            0 => 0,
+            size if size > density => overflow,
+            size => (size - 1) / width + 1,
+        };
+
+        buckets[bucket].1 += 1;
+    }
+
+    // Only show the overflow bucket when it's not empty.
+    if buckets[overflow].1 == 0 {
+        buckets.pop();
+    }
+
+    buckets
+}
+
+fn verify() -> Command {
+    Command::new("verify")
+        .about("Check the consistency between the index and the storage")
+        .long_about(
+            "Check the consistency between the index and the storage. \
+            The server must be stopped. Exits with a non-zero status if \
+            any inconsistency is found.",
+        )
+        .arg(arg_compression())
+        .arg(arg_key_file())
+}
+
+async fn verify_handler(args: &ArgMatches) {
+    let db = open_offline(args);
+    let report = db.verify();
+    print_report(&report);
+
+    if !report.is_consistent() {
+        eprintln!("Run the repair command to rebuild the index");
+        std::process::exit(1);
+    }
+}
+
+fn repair() -> Command {
+    Command::new("repair")
+        .about("Rebuild the index from the storage to fix inconsistencies")
+        .long_about(
+            "Rebuild the index from the storage to fix inconsistencies. \
+            The server must be stopped. The repaired state is persisted \
+            as a new snapshot.",
+        )
+        .arg(arg_compression())
+        .arg(arg_key_file())
+}
+
+async fn repair_handler(args: &ArgMatches) {
+    let db = open_offline(args);
+    let report = db.repair().expect("Failed to repair the database");
+    print_report(&report);
+
+    if report.is_consistent() {
+        println!("Nothing to repair");
+        return;
+    }
+
+    db.create_snapshot().expect("Failed to persist the repaired database");
+    println!("The database has been repaired successfully");
+}
+
+fn retrain() -> Command {
+    let arg_sample = arg!(--sample <size> "Number of vectors to train on")
+        .value_parser(clap::value_parser!(u32).range(1..));
+
+    let arg_server = arg!(--server <url> "Address of the running server");
+
+    Command::new("retrain")
+        .about("Retrain the index centroids and reassign all records")
+        .long_about(
+            "Retrain the index centroids with KMeans and reassign all \
+            records. The centroids are trained on all vectors unless a \
+            sample size is specified. The IVF-PQ codebooks are trained on \
+            the same vectors. They're also trained automatically once \
+            there are enough records, and retraining later with more \
+            vectors improves them. The running server is retrained if \
+            specified while it keeps serving queries. Otherwise, the server \
+            must be stopped.",
+        )
+        .arg(arg_sample)
+        .arg(arg_server)
+        .arg(arg_compression())
+        .arg(arg_key_file())
+}
+
+async fn retrain_handler(args: &ArgMatches) {
+    let sample = args.get_one::<u32>("sample").copied();
+    let start = Instant::now();
+
+    let (records, clusters) = match args.get_one::<String>("server") {
+        Some(server) => {
+            let mut client = DatabaseClient::connect(server.to_owned())
+                .await
+                .expect("Failed to connect to the database server");
+
+            let request = RetrainRequest { sample: sample.unwrap_or(0) as i32 };
+            let response = client
+                .retrain(request)
+                .await
+                .expect("Failed to retrain the index")
+                .into_inner();
+
+            (response.records, response.clusters)
+        }
+        None => {
+            let db = open_offline(args);
+            let sample = sample.map(|size| size as usize);
+            let stats =
+                db.retrain(sample).expect("Failed to retrain the index");
+            db.create_snapshot()
+                .expect("Failed to persist the retrained index");
+            (stats.records as i32, stats.clusters as i32)
+        }
+    };
+
+    let elapsed = start.elapsed().as_secs_f32();
+    println!(
+        "Retrained {clusters} cluster(s) from {records} record(s) \
+        in {elapsed:.1}s"
+    );
+}
+
+fn import() -> Command {
+    let arg_input = arg!(--input <path> "Path to the dataset file")
+        .required(true)
+        .value_parser(clap::value_parser!(PathBuf));
+
+    let arg_format = arg!(
+        --format <format>
+        "Format of the dataset: fvecs, bvecs, npy, or jsonl"
+    )
+    .value_parser(clap::value_parser!(ImportFormat));
+
+    Command::new("import")
+        .about("Import records in bulk from a dataset file")
+        .long_about(
+            "Import records in bulk from a dataset file. The server must \
+            be stopped. The format is detected from the file extension \
+            unless specified.",
+        )
+        .arg(arg_input)
+        .arg(arg_format)
+        .arg(arg_compression())
+        .arg(arg_key_file())
+}
+
+async fn import_handler(args: &ArgMatches) {
+    let input = args.get_one::<PathBuf>("input").unwrap();
+    let format = match args.get_one::<ImportFormat>("format") {
+        Some(format) => *format,
+        None => ImportFormat::from_path(input).unwrap_or_else(|| {
+            eprintln!("Unknown dataset format. Specify it with --format");
+            std::process::exit(1);
+        }),
+    };
+
+    let reader = ImportReader::open(input, format)
+        .expect("Failed to open the dataset file");
+
+    let db = open_offline(args);
+    let start = Instant::now();
+    let count = db.import(reader).expect("Failed to import the records");
+
+    db.create_snapshot().expect("Failed to persist the imported records");
+    let elapsed = start.elapsed().as_secs_f32();
+    let format = format.as_str();
+    println!("Imported {count} record(s) from {format} in {elapsed:.1}s");
+}
+
+fn export() -> Command {
+    let arg_output = arg!(--output <path> "Path to write the exported file")
+        .required(true)
+        .value_parser(clap::value_parser!(PathBuf));
+
+    let arg_format = arg!(--format <format> "Format of the file: jsonl or npy")
+        .value_parser(clap::value_parser!(ExportFormat));
+
+    let arg_server = arg!(--server <url> "Address of the running server");
+
+    Command::new("export")
+        .about("Export all records to a JSONL or NumPy file")
+        .long_about(
+            "Export all records with their IDs, vectors, and metadata. The \
+            npy format writes the vectors to the output file and the IDs \
+            and metadata to a sidecar JSONL file. The records are streamed \
+            from the server if specified. Otherwise, the server must be \
+            stopped.",
+        )
+        .arg(arg_output)
+        .arg(arg_format)
+        .arg(arg_server)
+        .arg(arg_compression())
+        .arg(arg_key_file())
+}
+
+async fn export_handler(args: &ArgMatches) {
+    let output = args.get_one::<PathBuf>("output").unwrap();
+    let format = match args.get_one::<ExportFormat>("format") {
+        Some(format) => *format,
+        None => match output.extension().and_then(|ext| ext.to_str()) {
+            Some("npy") => ExportFormat::Npy,
+            _ => ExportFormat::Jsonl,
+        },
+    };
+
+    let start = Instant::now();
+    let count = match args.get_one::<String>("server") {
+        Some(server) => export_from_server(server, output, format).await,
+        None => {
+            let db = open_offline(args);
+            let mut writer =
+                ExportWriter::create(output, format, db.dimension())
+                    .expect("Failed to create the export file");
+
+            db.export(|id, record| writer.write(&id, &record))
+                .expect("Failed to export the records");
+            writer.finish().expect("Failed to write the export file")
+        }
+    };
+
+    let elapsed = start.elapsed().as_secs_f32();
+    let format = format.as_str();
+    println!("Exported {count} record(s) to {format} in {elapsed:.1}s");
+}
+
+/// Export the records streamed from a running server.
+///
+/// The writer is created with the dimension of the first record since the
+/// server doesn't expose its parameters.
+async fn export_from_server(
+    server: &str,
+    output: &Path,
+    format: ExportFormat,
+) -> usize {
+    let mut client = DatabaseClient::connect(server.to_owned())
+        .await
+        .expect("Failed to connect to the database server");
+
+    // The dimension is needed for the npy header even with no records.
+    let params = client
+        .parameters(())
+        .await
+        .expect("Failed to get the database parameters")
+        .into_inner();
+
+    let dimension = params.dimension as usize;
+    let mut writer = ExportWriter::create(output, format, dimension)
+        .expect("Failed to create the export file");
+
+    let mut stream = client
+        .export(())
+        .await
+        .expect("Failed to start the export")
+        .into_inner();
+
+    while let Some(message) = stream.message().await.expect("Export failed") {
+        let id = message.id.parse().expect("Invalid record ID");
+        let record = message.record.expect("Missing record data");
+        let record = Record::try_from(record).expect("Invalid record data");
+        writer.write(&id, &record).expect("Failed to write the record");
+    }
+
+    writer.finish().expect("Failed to write the export file")
+}
+
+/// Open the database for the maintenance commands.
+///
+/// The snapshot generations are kept as they are because the retention
+/// is configured by the server. This fails if the server is running.
+fn open_offline(args: &ArgMatches) -> Database {
+    let compression = *args.get_one::<Compression>("compression").unwrap();
+    let key = load_key(args);
+
+    let options = OpenParameters {
+        compression,
+        key,
+        maintenance: true,
+        ..Default::default()
+    };
+    Database::open(data_dir(args), &options)
+        .expect("Failed to open the database")
+}
+
+fn print_report(report: &ConsistencyReport) {
+    println!("Records: {} (tracked count: {})", report.records, report.count);
+
+    let sections = [
+        ("Dangling IDs in the index", &report.dangling),
+        ("Orphaned records in the storage", &report.orphaned),
+        ("Duplicate IDs in the index", &report.duplicates),
+    ];
+
+    for (title, ids) in sections {
+        println!("{title}: {}", ids.len());
+        for id in ids.iter() {
+            println!("  {id}");
+        }
+    }
+
+    let clusters = &report.empty_clusters;
+    println!("Empty clusters: {}", clusters.len());
+    for cluster in clusters.iter() {
+        println!("  {cluster}");
+    }
+}
+
+fn backup() -> Command {
+    let arg_output = arg!(--output <path> "Path to write the backup archive")
+        .required(true)
+        .value_parser(clap::value_parser!(PathBuf));
+
+    let arg_server = arg!(--server <url> "Address of the running server")
+        .default_value("http://[::1]:2505");
+
+    Command::new("backup")
+        .about("Download a backup archive from a running server")
+        .arg(arg_output)
+        .arg(arg_server)
+}
+
+async fn backup_handler(args: &ArgMatches) {
+    let output = args.get_one::<PathBuf>("output").unwrap();
+    let server = args.get_one::<String>("server").unwrap().to_owned();
+
+    let mut client = DatabaseClient::connect(server)
+        .await
+        .expect("Failed to connect to the database server");
+
+    let mut stream = client
+        .backup(())
+        .await
+        .expect("Failed to start the backup")
+        .into_inner();
+
+    // The archive is written to a temporary file first so that a failed
+    // backup doesn't leave an incomplete archive at the output path.
+    let mut tmp_output = output.clone().into_os_string();
+    tmp_output.push(".tmp");
+
+    let file = File::create(&tmp_output).expect("Failed to create the file");
+    let mut writer = BufWriter::new(file);
+    while let Some(chunk) = stream.message().await.expect("Backup failed") {
+        writer.write_all(&chunk.data).expect("Failed to write the backup");
+    }
+
+    let file = writer.into_inner().expect("Failed to write the backup");
+    file.sync_all().expect("Failed to write the backup");
+    std::fs::rename(&tmp_output, output).expect("Failed to write the backup");
+    println!("The backup has been saved to {}", output.display());
+}
+
+fn restore() -> Command {
+    let arg_input = arg!(--input <path> "Path to the backup archive")
+        .required(true)
+        .value_parser(clap::value_parser!(PathBuf));
+
+    Command::new("restore")
+        .about("Restore the data directory from a backup archive")
+        .arg(arg_input)
+        .arg(arg_key_file())
+}
+
+async fn restore_handler(args: &ArgMatches) {
+    let input = args.get_one::<PathBuf>("input").unwrap();
+    let key = load_key(args);
+    let file = File::open(input).expect("Failed to open the backup archive");
+    let reader = BufReader::new(file);
+    Database::restore_backup(data_dir(args), reader, key.as_ref())
+        .expect("Failed to restore the backup");
 
-    let params = Parameters { dimension: dim, metric, density };
-    Database::configure(&params);
+    println!("The database has been restored successfully");
 }
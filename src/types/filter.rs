@@ -230,6 +230,7 @@ mod tests {
         Ok(())
     }
 
+    #[allow(clippy::useless_conversion)]
     fn setup_metadata() -> HashMap<String, Value> {
         let keys = vec!["name", "age", "gpa", "active"];
         let values: Vec<Value> = vec![
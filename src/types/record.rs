@@ -107,7 +107,7 @@ impl TryFrom<protos::Value> for Value {
 /// This is the main data structure for OasysDB. It contains the vector data
 /// and metadata of the record. Metadata is a key-value store that can be used
 /// to store additional information about the vector.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Record {
     pub vector: Vector,
     pub metadata: HashMap<String, Value>,
@@ -128,6 +128,7 @@ impl From<Record> for protos::Record {
 
 impl TryFrom<protos::Record> for Record {
     type Error = Status;
+    #[allow(clippy::result_large_err)]
     fn try_from(value: protos::Record) -> Result<Self, Self::Error> {
         let vector = match value.vector {
             Some(vector) => Vector::try_from(vector)?,
@@ -0,0 +1,64 @@
+use super::*;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// Flush the directory entries to the disk.
+///
+/// This makes sure that the renames inside the directory are durable.
+/// Directories can't be opened as files on Windows, so this is a no-op.
+pub fn sync_dir(dir: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+    if cfg!(unix) {
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// List the files under the directory recursively with their sizes.
+///
+/// The paths are relative to the directory and sorted.
+pub fn list_files(
+    dir: impl AsRef<Path>,
+) -> Result<Vec<(PathBuf, u64)>, Box<dyn Error>> {
+    let dir = dir.as_ref();
+    let mut files = vec![];
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+                continue;
+            }
+
+            let path = entry.path().strip_prefix(dir)?.to_path_buf();
+            files.push((path, metadata.len()));
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Lock the file exclusively for the lifetime of the returned handle.
+///
+/// The lock is advisory and released when the handle is dropped, including
+/// when the process exits. If another handle holds the lock, this returns
+/// None immediately instead of waiting for it.
+pub fn lock_file(
+    path: impl AsRef<Path>,
+) -> Result<Option<File>, Box<dyn Error>> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(fs::TryLockError::WouldBlock) => Ok(None),
+        Err(fs::TryLockError::Error(e)) => Err(e.into()),
+    }
+}
@@ -51,7 +51,6 @@ impl KMeans {
     }
 
     /// Configure the maximum number of iterations to run the algorithm.
-    #[allow(dead_code)]
     pub fn with_max_iter(mut self, max_iter: usize) -> Self {
         self.max_iter = max_iter;
         self
@@ -98,20 +97,17 @@ impl KMeans {
         let first_centroid = vectors.choose(&mut rng).cloned().unwrap();
         centroids.push(first_centroid.to_owned());
 
+        // Distance of each vector to its nearest centroid. Only the newest
+        // centroid needs to be compared with on each round.
+        let mut distances = vec![f64::INFINITY; vectors.len()];
         for _ in 1..self.n_clusters {
-            let nearest_centroid_distance = |vector: &&Vector| {
-                centroids
-                    .iter()
-                    .map(|centroid| self.metric.distance(vector, centroid))
-                    .min_by(|a, b| a.partial_cmp(b).unwrap())
-                    .unwrap()
-                    .unwrap()
-            };
-
-            let distances = vectors
-                .par_iter()
-                .map(nearest_centroid_distance)
-                .collect::<Vec<f64>>();
+            let newest_centroid = centroids.last().unwrap();
+            distances.par_iter_mut().zip(vectors.par_iter()).for_each(
+                |(distance, vector)| {
+                    let d = self.metric.distance(vector, newest_centroid);
+                    *distance = distance.min(d.unwrap());
+                },
+            );
 
             // Choose the next centroid with probability proportional
             // to the squared distance.
@@ -226,6 +222,32 @@ mod tests {
         evaluate_kmeans(10, generate_vectors(100));
     }
 
+    #[test]
+    fn test_initialize_centroids() {
+        // Groups of identical vectors far apart from each other.
+        let vectors: Vec<Vector> = (0..10)
+            .flat_map(|i| vec![Vector::from(vec![i as f32 * 100.0; 3]); 20])
+            .collect();
+
+        let vectors: Vectors = {
+            let vectors_ref: Vec<&Vector> = vectors.iter().collect();
+            Rc::from(vectors_ref.as_slice())
+        };
+
+        // A vector of a group that already has a centroid is at the distance
+        // of 0, so each centroid should be seeded from a different group.
+        let kmeans = KMeans::new(10);
+        let mut centroids: Vec<f32> = kmeans
+            .initialize_centroids(vectors)
+            .iter()
+            .map(|centroid| centroid.as_slice()[0])
+            .collect();
+
+        centroids.sort_by(f32::total_cmp);
+        let expected: Vec<f32> = (0..10).map(|i| i as f32 * 100.0).collect();
+        assert_eq!(centroids, expected);
+    }
+
     fn evaluate_kmeans(n_cluster: usize, vectors: Vec<Vector>) {
         let vectors: Vectors = {
             let vectors_ref: Vec<&Vector> = vectors.iter().collect();
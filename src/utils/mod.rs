@@ -1,3 +1,4 @@
+pub mod files;
 pub mod kmeans;
 
 // Import common dependencies below.